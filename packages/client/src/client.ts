@@ -30,6 +30,10 @@ export enum ErrorCode {
     NotFound = "not_found",
     Forbidden = "forbidden",
     UnprocessableContent = "unprocessable_content",
+    TooManyRequests = "too_many_requests",
+    PayloadTooLarge = "payload_too_large",
+    QuotaExceeded = "quota_exceeded",
+    ResyncRequired = "resync_required",
     InternalServerError = "internal_server_error"
 }
 
@@ -67,6 +71,7 @@ type RawDocument = {
     colrev: string
     data: null | string
     permissions: string
+    createdBy: null | string
 }
 
 export type Document = {
@@ -77,6 +82,7 @@ export type Document = {
     colrev: string
     data: null | Uint8Array
     permissions: Permissions
+    createdBy: null | string
 }
 
 export type CreateCollectionProps = {
@@ -84,6 +90,29 @@ export type CreateCollectionProps = {
     permissions: Permissions
 }
 
+// Page of the list, `cursor` is not set when the page is the last one
+export type Page<T> = {
+    items: T[]
+    cursor: string | null
+}
+
+export type PageProps = {
+    cursor?: string
+    limit?: number
+}
+
+export type ListCollectionsProps = PageProps & {
+    prefix?: string
+}
+
+export type ListUserCollectionsProps = PageProps & {
+    user: string
+}
+
+export type ListGroupMembersProps = PageProps & {
+    group: string
+}
+
 export type GetDocumentProps = {
     id: string
     col: string
@@ -113,10 +142,15 @@ export type UpdateDocumentWithCallbackProps = {
 export type Group = {
     id: string
     members: string[]
+    // Groups that are directly included in this group
+    subgroups: string[]
+    // Arbitrary data attached to the group, like display name
+    metadata: unknown | null
 }
 
 export type User = {
     id: string
+    // Includes groups inherited from parent groups
     groups: string[]
 }
 
@@ -125,6 +159,16 @@ export type AddRemoveUserToGroupProps = {
     group: string
 }
 
+export type AddRemoveUsersToGroupProps = {
+    users: string[]
+    group: string
+}
+
+export type AddRemoveGroupToGroupProps = {
+    group: string
+    parent: string
+}
+
 export type UpdateCollectionPermissionsProps = {
     id: string
     permissions: Permissions
@@ -284,7 +328,7 @@ class SinkronClient {
         const res = await this.send<RawCollection>("create_collection", {
             id,
             is_ref: false,
-            permissions: permissions.stringify()
+            permissions: permissions.table
         })
         if (!res.isOk) return res
         return Result.ok(parseCollection(res.value))
@@ -298,6 +342,31 @@ class SinkronClient {
         return Result.ok(parseCollection(res.value))
     }
 
+    async listCollections(
+        props: ListCollectionsProps = {}
+    ): Promise<ResultType<Page<Collection>, SinkronError>> {
+        const res = await this.send<Page<RawCollection>>(
+            "list_collections",
+            props
+        )
+        if (!res.isOk) return res
+        const { items, cursor } = res.value
+        return Result.ok({ items: items.map(parseCollection), cursor })
+    }
+
+    // Lists collections that the user is allowed to read
+    async listUserCollections(
+        props: ListUserCollectionsProps
+    ): Promise<ResultType<Page<Collection>, SinkronError>> {
+        const res = await this.send<Page<RawCollection>>(
+            "list_user_collections",
+            props
+        )
+        if (!res.isOk) return res
+        const { items, cursor } = res.value
+        return Result.ok({ items: items.map(parseCollection), cursor })
+    }
+
     async deleteCollection(
         id: string
     ): Promise<ResultType<void, SinkronError>> {
@@ -382,8 +451,23 @@ class SinkronClient {
 
     // Groups and users
 
-    async createGroup(id: string): Promise<ResultType<void, SinkronError>> {
-        const res = await this.send<void>("create_group", { id })
+    async createGroup(
+        id: string,
+        metadata?: unknown
+    ): Promise<ResultType<void, SinkronError>> {
+        const res = await this.send<void>("create_group", { id, metadata })
+        if (!res.isOk) return res
+        return Result.ok(undefined)
+    }
+
+    async updateGroupMetadata(
+        id: string,
+        metadata: unknown | null
+    ): Promise<ResultType<void, SinkronError>> {
+        const res = await this.send<void>("update_group_metadata", {
+            id,
+            metadata
+        })
         if (!res.isOk) return res
         return Result.ok(undefined)
     }
@@ -392,6 +476,18 @@ class SinkronClient {
         return await this.send<Group>("get_group", { id })
     }
 
+    async listGroups(
+        props: PageProps = {}
+    ): Promise<ResultType<Page<string>, SinkronError>> {
+        return await this.send<Page<string>>("list_groups", props)
+    }
+
+    async listGroupMembers(
+        props: ListGroupMembersProps
+    ): Promise<ResultType<Page<string>, SinkronError>> {
+        return await this.send<Page<string>>("list_group_members", props)
+    }
+
     async getUser(id: string): Promise<ResultType<User, SinkronError>> {
         return await this.send<User>("get_user", { id })
     }
@@ -422,6 +518,23 @@ class SinkronClient {
         return Result.ok(undefined)
     }
 
+    // Adds multiple users to the group, existing members are skipped
+    async addUsersToGroup(
+        props: AddRemoveUsersToGroupProps
+    ): Promise<ResultType<void, SinkronError>> {
+        const res = await this.send<void>("add_users_to_group", props)
+        if (!res.isOk) return res
+        return Result.ok(undefined)
+    }
+
+    async removeUsersFromGroup(
+        props: AddRemoveUsersToGroupProps
+    ): Promise<ResultType<void, SinkronError>> {
+        const res = await this.send<void>("remove_users_from_group", props)
+        if (!res.isOk) return res
+        return Result.ok(undefined)
+    }
+
     async removeUserFromAllGroups(
         id: string
     ): Promise<ResultType<undefined, SinkronError>> {
@@ -433,6 +546,22 @@ class SinkronClient {
         return Result.ok(undefined)
     }
 
+    async addGroupToGroup(
+        props: AddRemoveGroupToGroupProps
+    ): Promise<ResultType<void, SinkronError>> {
+        const res = await this.send<void>("add_group_to_group", props)
+        if (!res.isOk) return res
+        return Result.ok(undefined)
+    }
+
+    async removeGroupFromGroup(
+        props: AddRemoveGroupToGroupProps
+    ): Promise<ResultType<void, SinkronError>> {
+        const res = await this.send<void>("remove_group_from_group", props)
+        if (!res.isOk) return res
+        return Result.ok(undefined)
+    }
+
     // Permissions
 
     async updateCollectionPermissions(
@@ -441,7 +570,7 @@ class SinkronClient {
         const { id, permissions } = props
         const res = await this.send<void>("update_collection_permissions", {
             id,
-            permissions: permissions.stringify()
+            permissions: permissions.table
         })
         if (!res.isOk) return res
         return Result.ok(undefined)
@@ -468,7 +597,7 @@ class SinkronClient {
         const res = await this.send<void>("update_document_permissions", {
             id,
             col,
-            permissions: permissions.stringify()
+            permissions: permissions.table
         })
         if (!res.isOk) return res
         return Result.ok(undefined)
@@ -19,6 +19,7 @@ import { Op } from "./protocol"
 import type {
     SyncErrorMessage,
     SyncCompleteMessage,
+    SyncProgressMessage,
     DocMessage,
     ServerChangeMessage,
     ServerCreateMessage,
@@ -26,11 +27,15 @@ import type {
     ChangeErrorMessage,
     HeartbeatMessage,
     GetErrorMessage,
+    ServerPermissionsMessage,
+    PermissionsErrorMessage,
     ServerMessage
 } from "./protocol"
+import type { Permissions } from "./permissions"
 import { Transport, WebSocketTransport } from "./utils/transport"
 import { Heartbeat } from "./utils/heartbeat"
 import { AutoReconnect } from "./utils/autoReconnect"
+import { inflateBatch } from "./utils/inflate"
 
 const L = LoroDoc.prototype
 
@@ -353,9 +358,13 @@ interface SinkronCollectionProps<T> {
     store?: CollectionStore
     noAutoReconnect?: boolean
     errorHandler?: (msg: ServerMessage) => void
+    // Called when permissions of the collection or document were changed
+    permissionsHandler?: (msg: ServerPermissionsMessage) => void
     logger?: Logger<string>
     webSocketImpl?: typeof WebSocket
     extractData?: ExtractData<T>
+    // Request sync responses in deflate-compressed batches
+    compression?: boolean
 }
 
 const defaultLogger = (level = "debug"): Logger<string> => {
@@ -368,10 +377,18 @@ const defaultLogger = (level = "debug"): Logger<string> => {
 
 class SinkronCollection<T = undefined> {
     constructor(props: SinkronCollectionProps<T>) {
-        const { col, store, errorHandler, logger, extractData } = props
+        const {
+            col,
+            store,
+            errorHandler,
+            permissionsHandler,
+            logger,
+            extractData
+        } = props
         this.col = col
         this.store = store
         this.errorHandler = errorHandler
+        this.permissionsHandler = permissionsHandler
         this.logger = logger === undefined ? defaultLogger() : logger
         this.extractData = extractData
         makeObservable(this, {
@@ -402,6 +419,7 @@ class SinkronCollection<T = undefined> {
     store?: CollectionStore = undefined
     logger: Logger<string>
     errorHandler?: (msg: ServerMessage) => void
+    permissionsHandler?: (msg: ServerPermissionsMessage) => void
     extractData?: ExtractData<T>
 
     heartbeat?: Heartbeat
@@ -424,7 +442,7 @@ class SinkronCollection<T = undefined> {
     }
 
     async init(props: SinkronCollectionProps<T>) {
-        const { url, token, col, webSocketImpl } = props
+        const { url, token, col, webSocketImpl, compression } = props
 
         if (this.store) await this.loadFromStore()
         this.isLoaded = true
@@ -434,7 +452,8 @@ class SinkronCollection<T = undefined> {
                 const query = queryString.stringify({
                     token,
                     col,
-                    colrev: this.colrev
+                    colrev: this.colrev,
+                    compression: compression ? "deflate" : undefined
                 })
                 return `${url}?${query}`
             },
@@ -474,16 +493,9 @@ class SinkronCollection<T = undefined> {
                 this.heartbeat = undefined
             })
         )
-        this.transport.emitter.on("message", (msg: string) => {
-            try {
-                this.handleMessage(msg)
-            } catch (e) {
-                this.logger.error(
-                    "Unhandled exception in message handler, %o",
-                    e
-                )
-            }
-        })
+        this.transport.emitter.on("message", (msg: string | ArrayBuffer) =>
+            this.receive(msg)
+        )
 
         if (props.noAutoReconnect) {
             this.transport.open()
@@ -533,6 +545,45 @@ class SinkronCollection<T = undefined> {
         )
     }
 
+    // Batches are inflated asynchronously, messages that are received
+    // meanwhile wait for them to keep the order
+    pendingBatches?: Promise<void>
+
+    receive(msg: string | ArrayBuffer) {
+        if (typeof msg === "string" && this.pendingBatches === undefined) {
+            this.safeHandle(() => this.handleMessage(msg))
+            return
+        }
+        const prev = this.pendingBatches ?? Promise.resolve()
+        const next = prev
+            .then(async () => {
+                if (typeof msg === "string") {
+                    this.safeHandle(() => this.handleMessage(msg))
+                    return
+                }
+                const messages = await inflateBatch(msg)
+                this.logger.trace("Received batch: %d", messages.length)
+                for (const parsed of messages) {
+                    this.safeHandle(() => this.handleParsedMessage(parsed))
+                }
+            })
+            .catch((e) => {
+                this.logger.error("Couldn't read compressed batch, %o", e)
+            })
+        this.pendingBatches = next
+        next.then(() => {
+            if (this.pendingBatches === next) this.pendingBatches = undefined
+        })
+    }
+
+    safeHandle(handle: () => void) {
+        try {
+            handle()
+        } catch (e) {
+            this.logger.error("Unhandled exception in message handler, %o", e)
+        }
+    }
+
     handleMessage(msg: string) {
         this.logger.trace("Received message: %o", msg)
         let parsed
@@ -542,12 +593,18 @@ class SinkronCollection<T = undefined> {
             this.logger.error("Couldn't parse message JSON: %m", msg)
             return
         }
+        this.handleParsedMessage(parsed)
+    }
+
+    handleParsedMessage(parsed: any) {
         if (parsed.kind === "h") {
             this.handleHeartbeatMessage(parsed)
         } else if (parsed.kind === "doc") {
             this.handleDocMessage(parsed)
         } else if (parsed.kind === "get_error") {
             this.handleGetErrorMessage(parsed)
+        } else if (parsed.kind === "sync_progress") {
+            this.handleSyncProgressMessage(parsed)
         } else if (parsed.kind === "sync_complete") {
             this.handleSyncCompleteMessage(parsed)
         } else if (parsed.kind === "sync_error") {
@@ -556,7 +613,35 @@ class SinkronCollection<T = undefined> {
             this.handleChangeMessage(parsed)
         } else if (parsed.kind === "change_error") {
             this.handleChangeErrorMessage(parsed)
+        } else if (parsed.kind === "permissions") {
+            this.handlePermissionsMessage(parsed)
+        } else if (parsed.kind === "permissions_error") {
+            this.handlePermissionsErrorMessage(parsed)
+        }
+    }
+
+    // Changes permissions of the document, or of the whole collection
+    // when `id` is not provided. User should have "share" permission.
+    setPermissions(permissions: Permissions, id?: string) {
+        const msg = {
+            kind: "permissions",
+            col: this.col,
+            id,
+            permissions: permissions.table,
+            changeid: uuidv4()
         }
+        this.transport.send(JSON.stringify(msg))
+        return msg.changeid
+    }
+
+    handlePermissionsMessage(msg: ServerPermissionsMessage) {
+        this.logger.debug("Permissions changed: %o", msg)
+        this.permissionsHandler?.(msg)
+    }
+
+    handlePermissionsErrorMessage(msg: PermissionsErrorMessage) {
+        this.logger.warn("Couldn't change permissions: %o", msg)
+        this.errorHandler?.(msg)
     }
 
     handleHeartbeatMessage(msg: HeartbeatMessage) {
@@ -564,6 +649,13 @@ class SinkronCollection<T = undefined> {
         this.heartbeat?.handleHeartbeatResponse(msg.i)
     }
 
+    // Documents up to this colrev are received, so when the connection is
+    // interrupted, sync can be resumed from this point
+    handleSyncProgressMessage(msg: SyncProgressMessage) {
+        this.colrev = msg.colrev
+        this.backupDebounced()
+    }
+
     handleSyncCompleteMessage(msg: SyncCompleteMessage) {
         this.colrev = msg.colrev
         this.flush()
@@ -573,12 +665,32 @@ class SinkronCollection<T = undefined> {
     }
 
     handleSyncError(msg: SyncErrorMessage) {
+        if (msg.code === "resync_required") {
+            this.resync()
+            return
+        }
         this.logger.error("Sync error: %o", msg)
         this.status = ConnectionStatus.Error
         this.disconnect?.()
         this.errorHandler?.(msg)
     }
 
+    // Deleted documents were purged on the server since the last sync, so
+    // synchronized documents are dropped and the sync starts from the
+    // beginning, local changes are kept and sent again
+    resync() {
+        this.logger.warn("Resync required, syncing from the start")
+        this.colrev = "0"
+        for (const [id, item] of this.items) {
+            if (item.state === ItemState.Synchronized) {
+                this.items.delete(id)
+                this.enqueueBackup(id)
+            }
+        }
+        // Connection is restored by the auto reconnect
+        this.transport.close()
+    }
+
     handleGetErrorMessage(msg: GetErrorMessage) {
         const { id, code } = msg
         if (code === "auth_failed") {
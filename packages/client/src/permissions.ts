@@ -4,22 +4,35 @@ export type Role =
     | { kind: "any" }
     | { kind: "user"; id: string }
     | { kind: "group"; id: string }
+    // Creator of the document
+    | { kind: "owner" }
 
 export enum Action {
     read = "read",
     create = "create",
     update = "update",
-    delete = "delete"
+    delete = "delete",
+    // Changing permissions of the collection or document
+    share = "share"
 }
 
 const role = {
     any: (): Role => ({ kind: "any" }),
     user: (id: string): Role => ({ kind: "user", id }),
-    group: (id: string): Role => ({ kind: "group", id })
+    group: (id: string): Role => ({ kind: "group", id }),
+    owner: (): Role => ({ kind: "owner" })
+}
+
+export type DenyTable = {
+    -readonly [key in keyof typeof Action]?: Role[]
 }
 
 export type PermissionsTable = {
     -readonly [key in keyof typeof Action]: Role[]
+} & {
+    // Roles that are not allowed to perform actions, even when they are
+    // allowed by other roles
+    deny?: DenyTable
 }
 
 export type User = {
@@ -27,18 +40,33 @@ export type User = {
     groups: string[]
 }
 
+const matchRole = (role: Role, user: User, owner?: string) => {
+    if (role.kind === "any") {
+        return true
+    } else if (role.kind === "user") {
+        return user.id === role.id
+    } else if (role.kind === "group") {
+        return user.groups.includes(role.id)
+    } else if (role.kind === "owner") {
+        return owner !== undefined && user.id === owner
+    }
+    return false
+}
+
 const emptyPermissionsTable = () => ({
     read: [],
     create: [],
     update: [],
-    delete: []
+    delete: [],
+    share: []
 })
 
 const anyPermissionsTable = (): PermissionsTable => ({
     read: [role.any()],
     create: [role.any()],
     update: [role.any()],
-    delete: [role.any()]
+    delete: [role.any()],
+    share: []
 })
 
 class Permissions {
@@ -50,34 +78,48 @@ class Permissions {
 
     // Adds permission to the table
     add(action: Action, role: Role) {
-        const list = this.table[action]
+        // Tables created before "share" action was added don't have it
+        const list = this.table[action] ?? []
         for (const item of list) {
             if (isEqual(item, role)) return
         }
         list.push(role)
+        this.table[action] = list
     }
 
     // Removes permission from the table
     remove(action: Action, role: Role) {
-        this.table[action] = this.table[action].filter(
+        this.table[action] = (this.table[action] ?? []).filter(
             (item) => !isEqual(item, role)
         )
     }
 
-    // Checks if user has permission (issued directly on him or on his group)
-    check(user: User, action: Action) {
-        const roles = this.table[action]
-        for (const i in roles) {
-            const role = roles[i]
-            if (role.kind === "any") {
-                return true
-            } else if (role.kind === "user") {
-                if (user.id === role.id) return true
-            } else if (role.kind === "group") {
-                if (user.groups.includes(role.id)) return true
-            }
+    // Adds deny rule to the table
+    deny(action: Action, role: Role) {
+        if (this.table.deny === undefined) this.table.deny = {}
+        const list = this.table.deny[action] ?? []
+        for (const item of list) {
+            if (isEqual(item, role)) return
         }
-        return false
+        list.push(role)
+        this.table.deny[action] = list
+    }
+
+    // Removes deny rule from the table
+    removeDeny(action: Action, role: Role) {
+        const list = this.table.deny?.[action]
+        if (list === undefined) return
+        this.table.deny![action] = list.filter((item) => !isEqual(item, role))
+    }
+
+    // Checks if user has permission (issued directly on the user, on their
+    // group or on the owner of the document).
+    // Deny rules take precedence over allowed roles.
+    check(user: User, action: Action, owner?: string) {
+        const denied = this.table.deny?.[action] ?? []
+        if (denied.some((role) => matchRole(role, user, owner))) return false
+        const allowed = this.table[action] ?? []
+        return allowed.some((role) => matchRole(role, user, owner))
     }
 
     stringify() {
@@ -1,9 +1,15 @@
+import type { PermissionsTable } from "./permissions"
+
 export type ErrorCode =
     | "bad_request"
     | "auth_failed"
     | "not_found"
     | "forbidden"
     | "unprocessable_content"
+    | "too_many_requests"
+    | "payload_too_large"
+    | "quota_exceeded"
+    | "resync_required"
     | "internal_server_error"
 
 export type HeartbeatMessage = {
@@ -23,6 +29,12 @@ export type SyncCompleteMessage = {
     colrev: string
 }
 
+export type SyncProgressMessage = {
+    kind: "sync_progress"
+    col: string
+    colrev: string
+}
+
 export type GetMessage = {
     kind: "get"
     id: string // uuid
@@ -61,6 +73,7 @@ interface BaseClientChangeMessage {
 export interface ClientCreateMessage extends BaseClientChangeMessage {
     op: Op.Create
     data: string
+    permissions?: PermissionsTable
 }
 
 export interface ClientUpdateMessage extends BaseClientChangeMessage {
@@ -79,6 +92,8 @@ export type ClientChangeMessage =
     | ClientDeleteMessage
 
 interface BaseServerChangeMessage extends BaseClientChangeMessage {
+    // Changes that were replaced by this one
+    changeids?: string[] // uuid
     colrev: string
     createdAt: string // iso8601
     updatedAt: string // iso860,
@@ -111,13 +126,46 @@ export type ChangeErrorMessage = {
     changeid: string
 }
 
-export type ClientMessage = HeartbeatMessage | GetMessage | ClientChangeMessage
+// Changes permissions of the document, or of the whole collection
+// when `id` is not set
+export type ClientPermissionsMessage = {
+    kind: "permissions"
+    col: string
+    id?: string // uuid
+    permissions: PermissionsTable
+    changeid: string // uuid
+}
+
+export type ServerPermissionsMessage = {
+    kind: "permissions"
+    col: string
+    id?: string // uuid
+    permissions: PermissionsTable
+    changeid: string // uuid
+}
+
+export type PermissionsErrorMessage = {
+    kind: "permissions_error"
+    code: ErrorCode
+    col: string
+    id?: string // uuid
+    changeid: string // uuid
+}
+
+export type ClientMessage =
+    | HeartbeatMessage
+    | GetMessage
+    | ClientChangeMessage
+    | ClientPermissionsMessage
 
 export type ServerMessage =
     | HeartbeatMessage
+    | SyncProgressMessage
     | SyncCompleteMessage
     | SyncErrorMessage
     | GetErrorMessage
     | DocMessage
     | ServerChangeMessage
     | ChangeErrorMessage
+    | ServerPermissionsMessage
+    | PermissionsErrorMessage
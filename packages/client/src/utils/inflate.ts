@@ -0,0 +1,11 @@
+// Compressed batch is a JSON array of messages, deflated without the zlib
+// header ("deflate-raw")
+const inflateBatch = async (data: ArrayBuffer): Promise<object[]> => {
+    const stream = new Blob([data])
+        .stream()
+        .pipeThrough(new DecompressionStream("deflate-raw"))
+    const text = await new Response(stream).text()
+    return JSON.parse(text)
+}
+
+export { inflateBatch }
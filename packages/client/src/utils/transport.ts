@@ -32,6 +32,8 @@ class WebSocketTransport implements Transport {
         const url = typeof this.url === "function" ? this.url() : this.url
         this.logger?.debug("Connecting to websocket: %s", url)
         this.ws = new this.webSocketImpl(url)
+        // Compressed batches are received as binary frames
+        this.ws.binaryType = "arraybuffer"
         this.ws.addEventListener("open", () => {
             this.logger?.debug("Websocket connection open")
             this.emitter.emit("open")
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sinkron_client::DocumentInfo;
+use tauri::State;
+use uuid::Uuid;
+
+use crate::markdown;
+use crate::sync::Collections;
+
+const ATTACHMENTS_DIR: &str = "attachments";
+const INDEX_FILE: &str = "index.md";
+// Keeps state of the previous export for the incremental mode
+const MANIFEST_FILE: &str = ".sinkron-export.json";
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct ExportedNote {
+    colrev: i64,
+    file: String,
+    title: String,
+    categories: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    // Category id -> full name, e.g. "Work / Projects"
+    categories: HashMap<String, String>,
+    notes: HashMap<Uuid, ExportedNote>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportSummary {
+    written: usize,
+    skipped: usize,
+    deleted: usize,
+    attachments: usize,
+    // Ids of pictures that couldn't be downloaded
+    failed_attachments: Vec<String>,
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> String {
+    format!("{}: {}", path.display(), err)
+}
+
+// Builds full names of categories from the space metadata document
+fn category_names(meta: &Value) -> HashMap<String, String> {
+    let Some(categories) = meta.get("categories").and_then(Value::as_object) else {
+        return HashMap::new();
+    };
+    let name = |id: &str| {
+        categories
+            .get(id)
+            .and_then(|c| c.get("name"))
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_string()
+    };
+    let mut names = HashMap::new();
+    for (id, category) in categories {
+        let mut parts = vec![name(id)];
+        let mut parent = category.get("parent").and_then(Value::as_str);
+        // Depth is limited in case of cycles
+        while let Some(id) = parent.filter(|_| parts.len() < 32) {
+            parts.push(name(id));
+            parent = categories
+                .get(id)
+                .and_then(|c| c.get("parent"))
+                .and_then(Value::as_str);
+        }
+        parts.reverse();
+        names.insert(id.clone(), parts.join(" / "));
+    }
+    names
+}
+
+fn file_name(title: &str, id: Uuid) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.chars().count() >= 50 {
+            break;
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    let slug = if slug.is_empty() { "untitled" } else { slug };
+    let id = id.simple().to_string();
+    format!("{}-{}.md", slug, &id[..8])
+}
+
+fn front_matter(id: Uuid, root: &Value, categories: &[String]) -> String {
+    let quote = |s: &str| serde_json::to_string(s).unwrap_or_default();
+    let mut lines = vec![format!("id: {}", id)];
+    if !categories.is_empty() {
+        let list: Vec<String> = categories.iter().map(|c| quote(c)).collect();
+        lines.push(format!("categories: [{}]", list.join(", ")));
+    }
+    for (key, name) in [("isPinned", "pinned"), ("isPublished", "published")] {
+        if root.get(key).and_then(Value::as_bool) == Some(true) {
+            lines.push(format!("{}: true", name));
+        }
+    }
+    format!("---\n{}\n---\n\n", lines.join("\n"))
+}
+
+fn render_index(manifest: &Manifest) -> String {
+    let mut notes: Vec<&ExportedNote> = manifest.notes.values().collect();
+    notes.sort_by_key(|note| note.title.to_lowercase());
+    let mut index = String::from("# Notes\n\n");
+    for note in notes {
+        let title = match note.title.as_str() {
+            "" => "Untitled".to_string(),
+            title => markdown::escape(title),
+        };
+        let link = note.file.replace(' ', "%20");
+        index.push_str(&format!("- [{}]({})", title, link));
+        let names: Vec<&str> = note
+            .categories
+            .iter()
+            .filter_map(|id| manifest.categories.get(id))
+            .map(String::as_str)
+            .collect();
+        if !names.is_empty() {
+            index.push_str(&format!(" ({})", names.join(", ")));
+        }
+        index.push('\n');
+    }
+    index
+}
+
+async fn read_manifest(path: &Path) -> Manifest {
+    match tokio::fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_default(),
+        Err(_) => Manifest::default(),
+    }
+}
+
+async fn download(client: &reqwest::Client, url: &str, path: &Path) -> Result<(), String> {
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    let res = res.error_for_status().map_err(|err| err.to_string())?;
+    let data = res.bytes().await.map_err(|err| err.to_string())?;
+    tokio::fs::write(path, data)
+        .await
+        .map_err(|err| io_error(path, err))
+}
+
+// Exports notes of the collection to Markdown files in `dir`, pictures are
+// downloaded from `files_url` into the "attachments" subdirectory. In the
+// incremental mode, only notes that changed since the previous export are
+// rewritten.
+#[tauri::command]
+pub async fn export_markdown(
+    collections: State<'_, Collections>,
+    col: String,
+    dir: PathBuf,
+    files_url: String,
+    incremental: bool,
+) -> Result<ExportSummary, String> {
+    let collection = collections.get(&col)?;
+    let attachments_dir = dir.join(ATTACHMENTS_DIR);
+    tokio::fs::create_dir_all(&attachments_dir)
+        .await
+        .map_err(|err| io_error(&attachments_dir, err))?;
+
+    // Manifest is read in both modes to remove files of the deleted notes
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let mut manifest = read_manifest(&manifest_path).await;
+
+    let infos = collection
+        .documents()
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut notes: Vec<(DocumentInfo, Value)> = Vec::new();
+    let mut categories = HashMap::new();
+    for info in infos {
+        let doc = collection
+            .get(info.id)
+            .await
+            .map_err(|err| err.to_string())?;
+        let Some(doc) = doc else {
+            continue;
+        };
+        let value = serde_json::to_value(doc.get_deep_value()).map_err(|err| err.to_string())?;
+        let Some(root) = value.get("root").cloned() else {
+            continue;
+        };
+        if root.get("isMeta").and_then(Value::as_bool) == Some(true) {
+            categories = category_names(&root);
+        } else {
+            notes.push((info, root));
+        }
+    }
+    // Category names are included in the notes, so they all are rewritten
+    // when the categories change
+    let is_full = !incremental || manifest.categories != categories;
+    manifest.categories = categories;
+
+    let client = reqwest::Client::new();
+    let mut summary = ExportSummary::default();
+    let mut exported = HashMap::new();
+    for (info, root) in notes {
+        let prev = manifest.notes.remove(&info.id);
+        if let Some(prev) = prev {
+            let is_unchanged = prev.colrev == info.colrev && !info.is_changed;
+            if !is_full && is_unchanged && dir.join(&prev.file).exists() {
+                summary.skipped += 1;
+                exported.insert(info.id, prev);
+                continue;
+            }
+            // File name changes together with the title
+            _ = tokio::fs::remove_file(dir.join(&prev.file)).await;
+        }
+
+        let content = root.get("content").cloned().unwrap_or(Value::Null);
+        let title = markdown::title(&content);
+        let rendered = markdown::render(&content, ATTACHMENTS_DIR);
+        let note_categories: Vec<String> = root
+            .get("categories")
+            .and_then(Value::as_array)
+            .map(|ids| {
+                ids.iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let names: Vec<String> = note_categories
+            .iter()
+            .filter_map(|id| manifest.categories.get(id).cloned())
+            .collect();
+
+        let file = file_name(&title, info.id);
+        let path = dir.join(&file);
+        let text = format!(
+            "{}{}",
+            front_matter(info.id, &root, &names),
+            rendered.markdown
+        );
+        tokio::fs::write(&path, text)
+            .await
+            .map_err(|err| io_error(&path, err))?;
+        summary.written += 1;
+
+        for image in rendered.images {
+            if !markdown::is_valid_image_id(&image) {
+                continue;
+            }
+            let path = attachments_dir.join(format!("{}.jpg", image));
+            if path.exists() {
+                continue;
+            }
+            let url = format!("{}/{}", files_url.trim_end_matches('/'), image);
+            match download(&client, &url, &path).await {
+                Ok(()) => summary.attachments += 1,
+                Err(_) => summary.failed_attachments.push(image),
+            }
+        }
+
+        let note = ExportedNote {
+            colrev: info.colrev,
+            file,
+            title,
+            categories: note_categories,
+        };
+        exported.insert(info.id, note);
+    }
+
+    // Notes that are left were deleted since the previous export
+    for (_, note) in manifest.notes.drain() {
+        _ = tokio::fs::remove_file(dir.join(&note.file)).await;
+        summary.deleted += 1;
+    }
+    manifest.notes = exported;
+
+    let index_path = dir.join(INDEX_FILE);
+    tokio::fs::write(&index_path, render_index(&manifest))
+        .await
+        .map_err(|err| io_error(&index_path, err))?;
+    let data = serde_json::to_vec(&manifest).map_err(|err| err.to_string())?;
+    tokio::fs::write(&manifest_path, data)
+        .await
+        .map_err(|err| io_error(&manifest_path, err))?;
+    Ok(summary)
+}
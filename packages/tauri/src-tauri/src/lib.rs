@@ -1,3 +1,7 @@
+mod export;
+mod markdown;
+mod sync;
+
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 #[tauri::command]
 fn greet(name: &str) -> String {
@@ -8,7 +12,19 @@ fn greet(name: &str) -> String {
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
-        .invoke_handler(tauri::generate_handler![greet])
+        .manage(sync::Collections::default())
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            sync::open_collection,
+            sync::close_collection,
+            sync::collection_status,
+            sync::list_documents,
+            sync::get_document,
+            sync::create_document,
+            sync::update_document,
+            sync::delete_document,
+            export::export_markdown
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
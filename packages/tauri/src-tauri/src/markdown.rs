@@ -0,0 +1,298 @@
+use serde_json::Value;
+
+// Renders note content (Slate nodes stored in the "content" of the document
+// root) to Markdown. Pictures are linked to `{attachments_dir}/{id}.jpg`.
+
+pub struct Rendered {
+    pub markdown: String,
+    // Ids of the pictures used in the note
+    pub images: Vec<String>,
+}
+
+fn children(node: &Value) -> &[Value] {
+    match node.get("children").and_then(Value::as_array) {
+        Some(children) => children,
+        None => &[],
+    }
+}
+
+fn node_type(node: &Value) -> &str {
+    node.get("type").and_then(Value::as_str).unwrap_or("")
+}
+
+// Ids are used in file paths and urls, so anything except letters, digits
+// and dashes (like "../") is rejected
+pub fn is_valid_image_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn flag(node: &Value, key: &str) -> bool {
+    node.get(key).and_then(Value::as_bool).unwrap_or(false)
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Link destination is wrapped in angle brackets, so spaces and parentheses
+// in the url don't end it
+fn link_destination(url: &str) -> String {
+    let mut escaped = String::with_capacity(url.len() + 2);
+    escaped.push('<');
+    for c in url.chars() {
+        match c {
+            '\\' | '<' | '>' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("%0A"),
+            '\r' => escaped.push_str("%0D"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped.push('>');
+    escaped
+}
+
+// Fence is longer than any run of backticks in the code, so the code can't
+// close it
+fn code_fence(code: &str) -> String {
+    let mut longest = 0;
+    let mut current = 0;
+    for c in code.chars() {
+        if c == '`' {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    "`".repeat((longest + 1).max(3))
+}
+
+// Concatenated text of all descendants without formatting
+pub fn plain_text(node: &Value) -> String {
+    if let Some(text) = node.get("text").and_then(Value::as_str) {
+        return text.to_string();
+    }
+    if let Some(text) = node.get("children").and_then(Value::as_str) {
+        return text.to_string();
+    }
+    children(node).iter().map(plain_text).collect()
+}
+
+fn render_text(node: &Value) -> String {
+    let text = node.get("text").and_then(Value::as_str).unwrap_or("");
+    // Markers can't wrap whitespace, so it is kept outside
+    let trimmed = text.trim();
+    if trimmed.is_empty() {
+        return text.to_string();
+    }
+    let mut inner = escape(trimmed);
+    if flag(node, "underline") {
+        inner = format!("<u>{}</u>", inner);
+    }
+    if flag(node, "strikethrough") {
+        inner = format!("~~{}~~", inner);
+    }
+    if flag(node, "italic") {
+        inner = format!("_{}_", inner);
+    }
+    if flag(node, "bold") {
+        inner = format!("**{}**", inner);
+    }
+    let start = text.len() - text.trim_start().len();
+    let end = text.trim_end().len();
+    format!("{}{}{}", &text[..start], inner, &text[end..])
+}
+
+fn render_inline(node: &Value) -> String {
+    children(node)
+        .iter()
+        .map(|child| match node_type(child) {
+            "link" => {
+                let url = child.get("url").and_then(Value::as_str).unwrap_or("");
+                format!("[{}]({})", render_inline(child), link_destination(url))
+            }
+            _ => render_text(child),
+        })
+        .collect()
+}
+
+fn render_list(node: &Value, marker: impl Fn(usize, &Value) -> String) -> String {
+    children(node)
+        .iter()
+        .enumerate()
+        .map(|(i, item)| format!("{}{}", marker(i, item), render_inline(item)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn render_block(node: &Value, attachments_dir: &str, images: &mut Vec<String>) -> Option<String> {
+    let block = match node_type(node) {
+        "title" => format!("# {}", render_inline(node)),
+        "heading" => format!("## {}", render_inline(node)),
+        "list" | "list-item" => render_list(node, |_, _| "- ".to_string()),
+        "ordered-list" => render_list(node, |i, _| format!("{}. ", i + 1)),
+        "check-list" => render_list(node, |_, item| {
+            let mark = if flag(item, "isChecked") { "x" } else { " " };
+            format!("- [{}] ", mark)
+        }),
+        "code-block" => {
+            let lines: Vec<String> = children(node).iter().map(plain_text).collect();
+            let code = lines.join("\n");
+            let fence = code_fence(&code);
+            format!("{}\n{}\n{}", fence, code, fence)
+        }
+        "image" => {
+            let id = node
+                .get("id")
+                .and_then(Value::as_str)
+                .filter(|id| is_valid_image_id(id))?;
+            // Pictures that were not uploaded can't be exported
+            if node.get("status").and_then(Value::as_str) != Some("ready") {
+                return None;
+            }
+            images.push(id.to_string());
+            format!("![]({}/{}.jpg)", attachments_dir, id)
+        }
+        _ => {
+            let text = render_inline(node);
+            if text.trim().is_empty() {
+                return None;
+            }
+            // Paragraph shouldn't turn into heading, quote or list
+            if text.starts_with(['#', '>', '-', '+']) {
+                format!("\\{}", text)
+            } else {
+                text
+            }
+        }
+    };
+    Some(block)
+}
+
+pub fn render(content: &Value, attachments_dir: &str) -> Rendered {
+    let mut images = Vec::new();
+    let blocks: Vec<String> = children(content)
+        .iter()
+        .filter_map(|node| render_block(node, attachments_dir, &mut images))
+        .collect();
+    let mut markdown = blocks.join("\n\n");
+    markdown.push('\n');
+    Rendered { markdown, images }
+}
+
+// Title of the note is the text of its first block
+pub fn title(content: &Value) -> String {
+    match children(content).first() {
+        Some(first) => plain_text(first).trim().to_string(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn text(text: &str) -> Value {
+        json!({ "text": text })
+    }
+
+    fn block(kind: &str, children: Vec<Value>) -> Value {
+        json!({ "type": kind, "children": children })
+    }
+
+    fn render_nodes(nodes: Vec<Value>) -> Rendered {
+        render(&json!({ "children": nodes }), "attachments")
+    }
+
+    #[test]
+    fn lists() {
+        let item = |t: &str| block("list-item", vec![text(t)]);
+        let rendered = render_nodes(vec![
+            block("list", vec![item("One"), item("Two")]),
+            block("ordered-list", vec![item("First"), item("Second")]),
+        ]);
+        assert_eq!(rendered.markdown, "- One\n- Two\n\n1. First\n2. Second\n");
+    }
+
+    #[test]
+    fn check_lists() {
+        let checked = json!({ "type": "check-list-item", "isChecked": true, "children": [text("Done")] });
+        let unchecked = block("check-list-item", vec![text("Todo")]);
+        let rendered = render_nodes(vec![block("check-list", vec![checked, unchecked])]);
+        assert_eq!(rendered.markdown, "- [x] Done\n- [ ] Todo\n");
+    }
+
+    #[test]
+    fn code_blocks() {
+        let line = |t: &str| block("code-line", vec![text(t)]);
+        let rendered = render_nodes(vec![block("code-block", vec![line("let a = 1;"), line("*b*")])]);
+        assert_eq!(rendered.markdown, "```\nlet a = 1;\n*b*\n```\n");
+
+        // Fence is longer than backticks inside of the code
+        let rendered = render_nodes(vec![block("code-block", vec![line("```"), line("````js")])]);
+        assert_eq!(rendered.markdown, "`````\n```\n````js\n`````\n");
+    }
+
+    #[test]
+    fn images_are_filtered_by_status() {
+        let image = |id: &str, status: &str| json!({ "type": "image", "id": id, "status": status, "children": [] });
+        let rendered = render_nodes(vec![
+            image("ready-1", "ready"),
+            image("uploading-1", "uploading"),
+            image("../secret", "ready"),
+        ]);
+        assert_eq!(rendered.markdown, "![](attachments/ready-1.jpg)\n");
+        assert_eq!(rendered.images, vec!["ready-1"]);
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        let rendered = render_nodes(vec![
+            block("paragraph", vec![text("*not bold* [x] <b> `code` a_b \\")]),
+            block("paragraph", vec![text("# not a heading")]),
+            block("paragraph", vec![json!({ "text": " bold ", "bold": true })]),
+        ]);
+        assert_eq!(
+            rendered.markdown,
+            "\\*not bold\\* \\[x\\] \\<b> \\`code\\` a\\_b \\\\\n\n\\# not a heading\n\n **bold** \n"
+        );
+    }
+
+    #[test]
+    fn links() {
+        let link = |url: &str| {
+            block("paragraph", vec![json!({ "type": "link", "url": url, "children": [text("link")] })])
+        };
+        let rendered = render_nodes(vec![
+            link("https://example.com/a b)"),
+            link("x>\n[y](z)"),
+        ]);
+        assert_eq!(
+            rendered.markdown,
+            "[link](<https://example.com/a b)>)\n\n[link](<x\\>%0A[y](z)>)\n"
+        );
+    }
+
+    #[test]
+    fn title_is_text_of_first_block() {
+        let content = json!({
+            "children": [
+                block("title", vec![text("  My "), json!({ "text": "note", "bold": true })]),
+                block("paragraph", vec![text("Body")]),
+            ]
+        });
+        assert_eq!(title(&content), "My note");
+        assert_eq!(title(&json!({ "children": [] })), "");
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base64::prelude::*;
+use serde::Serialize;
+use sinkron_client::loro::{ExportMode, LoroDoc};
+use sinkron_client::{
+    CollectionConfig, CollectionEvent, ConnectionStatus, SinkronCollection, SqliteStore,
+};
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+// Documents are exchanged with the webview as base64-encoded Loro snapshots
+// and updates, same as in the sync protocol.
+
+// Collections opened by the webview, by collection id
+#[derive(Default)]
+pub struct Collections(Mutex<HashMap<String, SinkronCollection>>);
+
+impl Collections {
+    pub(crate) fn get(&self, col: &str) -> Result<SinkronCollection, String> {
+        let collections = self.0.lock().unwrap();
+        match collections.get(col) {
+            Some(collection) => Ok(collection.clone()),
+            None => Err(format!("Collection is not opened: {}", col)),
+        }
+    }
+}
+
+// Emitted when the document is changed by the server
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ChangeEvent {
+    col: String,
+    id: Uuid,
+    is_deleted: bool,
+}
+
+const CHANGE_EVENT: &str = "sinkron:change";
+
+fn store_path(app: &AppHandle) -> Result<String, String> {
+    let dir = app.path().app_data_dir().map_err(|err| err.to_string())?;
+    std::fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+    Ok(dir.join("sinkron.db").to_string_lossy().into_owned())
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, String> {
+    BASE64_STANDARD
+        .decode(data)
+        .map_err(|_| "Invalid base64 data".to_string())
+}
+
+fn forward_events(app: AppHandle, col: String, collection: &SinkronCollection) {
+    let mut events = collection.events();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let (id, is_deleted) = match events.recv().await {
+                Ok(CollectionEvent::Changed(id)) => (id, false),
+                Ok(CollectionEvent::Deleted(id)) => (id, true),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            let event = ChangeEvent {
+                col: col.clone(),
+                id,
+                is_deleted,
+            };
+            _ = app.emit(CHANGE_EVENT, event);
+        }
+    });
+}
+
+// Opens collection from the local store and starts syncing it with the
+// server. Documents are available right away, even when offline.
+#[tauri::command]
+pub async fn open_collection(
+    app: AppHandle,
+    collections: State<'_, Collections>,
+    url: String,
+    token: String,
+    col: String,
+) -> Result<(), String> {
+    let mut opened = collections.0.lock().unwrap();
+    if opened.contains_key(&col) {
+        return Ok(());
+    }
+    let store = SqliteStore::open(&store_path(&app)?)
+        .map_err(|err| format!("Couldn't open store: {}", err))?;
+    let mut config = CollectionConfig::new(&url, &token, &col);
+    config.store = Some(Box::new(store));
+    let collection = SinkronCollection::connect(config);
+    forward_events(app.clone(), col.clone(), &collection);
+    opened.insert(col, collection);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn close_collection(
+    collections: State<'_, Collections>,
+    col: String,
+) -> Result<(), String> {
+    if let Some(collection) = collections.0.lock().unwrap().remove(&col) {
+        collection.close();
+    }
+    Ok(())
+}
+
+// Returns "disconnected", "connected", "ready" or "error"
+#[tauri::command]
+pub async fn collection_status(
+    collections: State<'_, Collections>,
+    col: String,
+) -> Result<String, String> {
+    let status = match collections.get(&col)?.status() {
+        ConnectionStatus::Disconnected => "disconnected",
+        ConnectionStatus::Connected => "connected",
+        ConnectionStatus::Ready => "ready",
+        ConnectionStatus::Error(_) => "error",
+    };
+    Ok(status.to_string())
+}
+
+#[tauri::command]
+pub async fn list_documents(
+    collections: State<'_, Collections>,
+    col: String,
+) -> Result<Vec<Uuid>, String> {
+    let collection = collections.get(&col)?;
+    collection.list().await.map_err(|err| err.to_string())
+}
+
+// Returns snapshot of the document
+#[tauri::command]
+pub async fn get_document(
+    collections: State<'_, Collections>,
+    col: String,
+    id: Uuid,
+) -> Result<Option<String>, String> {
+    let collection = collections.get(&col)?;
+    let doc = collection.get(id).await.map_err(|err| err.to_string())?;
+    let Some(doc) = doc else {
+        return Ok(None);
+    };
+    let snapshot = doc
+        .export(ExportMode::Snapshot)
+        .map_err(|err| err.to_string())?;
+    Ok(Some(BASE64_STANDARD.encode(snapshot)))
+}
+
+// Changes are applied and stored locally right away and sent to the server
+// in the background, so these commands don't wait for the server.
+
+#[tauri::command]
+pub async fn create_document(
+    collections: State<'_, Collections>,
+    col: String,
+    data: String,
+) -> Result<Uuid, String> {
+    let collection = collections.get(&col)?;
+    let doc = LoroDoc::new();
+    doc.import(&decode(&data)?).map_err(|err| err.to_string())?;
+    let id = Uuid::new_v4();
+    drop(collection.create_with_id(id, doc));
+    Ok(id)
+}
+
+#[tauri::command]
+pub async fn update_document(
+    collections: State<'_, Collections>,
+    col: String,
+    id: Uuid,
+    data: String,
+) -> Result<(), String> {
+    let collection = collections.get(&col)?;
+    let update = decode(&data)?;
+    drop(collection.change(id, move |doc| {
+        _ = doc.import(&update);
+    }));
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn delete_document(
+    collections: State<'_, Collections>,
+    col: String,
+    id: Uuid,
+) -> Result<(), String> {
+    let collection = collections.get(&col)?;
+    drop(collection.delete(id));
+    Ok(())
+}
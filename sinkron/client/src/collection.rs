@@ -0,0 +1,1158 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::Read;
+
+use base64::prelude::*;
+use flate2::read::DeflateDecoder;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, trace, warn};
+use loro::{ExportMode, LoroDoc};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{sleep, sleep_until, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+use sinkron::permissions::Permissions;
+use sinkron::protocol::*;
+
+use crate::error::ClientError;
+use crate::heartbeat::{Heartbeat, HeartbeatEvent};
+use crate::store::{CollectionStore, StoredDocument};
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Receives colrev of the collection after the change is confirmed
+type Waiter = oneshot::Sender<Result<i64, ClientError>>;
+
+pub struct CollectionConfig {
+    // Url of the sync endpoint, e.g. "wss://example.com/sync"
+    pub url: String,
+    pub token: String,
+    pub col: String,
+    pub heartbeat_interval: Duration,
+    // Max time to wait for the heartbeat response
+    pub heartbeat_timeout: Duration,
+    // Delay before reconnecting, it doubles after each failed attempt
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    // Keeps documents between restarts, when not set documents are only
+    // kept in memory
+    pub store: Option<Box<dyn CollectionStore>>,
+    // Requests sync responses in deflate-compressed batches
+    pub compression: bool,
+}
+
+impl CollectionConfig {
+    pub fn new(url: &str, token: &str, col: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            token: token.to_string(),
+            col: col.to_string(),
+            heartbeat_interval: Duration::from_secs(30),
+            heartbeat_timeout: Duration::from_secs(5),
+            reconnect_delay: Duration::from_millis(333),
+            max_reconnect_delay: Duration::from_secs(10),
+            store: None,
+            compression: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ConnectionStatus {
+    Disconnected,
+    // Connected, initial sync is in progress
+    Connected,
+    // Initial sync is completed
+    Ready,
+    // Server rejected the sync, client doesn't reconnect after that
+    Error(ErrorCode),
+}
+
+// Changes received from the server, changes made by this client are not
+// included
+#[derive(Clone)]
+pub enum CollectionEvent {
+    Changed(Uuid),
+    Deleted(Uuid),
+    // Permissions of the document, or of the whole collection when `id` is
+    // not set
+    Permissions {
+        id: Option<Uuid>,
+        permissions: Box<Permissions>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct DocumentInfo {
+    pub id: Uuid,
+    // Colrev of the last change received from the server, 0 when the
+    // document was not created on the server yet
+    pub colrev: i64,
+    // Document has local changes that were not confirmed by the server
+    pub is_changed: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ItemState {
+    Changed,
+    ChangesSent,
+    Synchronized,
+}
+
+struct Item {
+    // Remote version of the document, not set until server acknowledges
+    // creation
+    remote: Option<LoroDoc>,
+    // Local version of the document, not set when the document is deleted,
+    // but server didn't acknowledge deletion yet
+    local: Option<LoroDoc>,
+    // Colrev of the last change of the remote version
+    colrev: i64,
+    state: ItemState,
+    // Callers waiting for the confirmation of the changes that are not
+    // sent yet
+    waiters: Vec<Waiter>,
+}
+
+impl Item {
+    fn update_state(&mut self) {
+        let is_changed = match (&self.local, &self.remote) {
+            (Some(local), Some(remote)) => has_changes(local, remote),
+            _ => true,
+        };
+        self.state = if is_changed {
+            ItemState::Changed
+        } else {
+            ItemState::Synchronized
+        };
+    }
+
+    fn fail(self, err: ClientError) {
+        for waiter in self.waiters {
+            _ = waiter.send(Err(err.clone()));
+        }
+    }
+}
+
+// Checks if `a` has any changes that are not present in `b`
+fn has_changes(a: &LoroDoc, b: &LoroDoc) -> bool {
+    matches!(
+        a.oplog_vv().partial_cmp(&b.oplog_vv()),
+        Some(Ordering::Greater) | None
+    )
+}
+
+// Applies all changes from `from` that are missing in `to`
+fn merge_changes(to: &LoroDoc, from: &LoroDoc) {
+    if let Ok(update) = from.export(ExportMode::updates(&to.oplog_vv())) {
+        _ = to.import(&update);
+    }
+}
+
+fn doc_from_bytes(data: &[u8]) -> Option<LoroDoc> {
+    let doc = LoroDoc::new();
+    doc.import(data).ok()?;
+    Some(doc)
+}
+
+fn doc_from_base64(data: &str) -> Option<LoroDoc> {
+    doc_from_bytes(&BASE64_STANDARD.decode(data).ok()?)
+}
+
+enum Command {
+    Create {
+        id: Uuid,
+        doc: LoroDoc,
+        reply: Waiter,
+    },
+    Change {
+        id: Uuid,
+        change: Box<dyn FnOnce(&LoroDoc) + Send>,
+        reply: Waiter,
+    },
+    Delete {
+        id: Uuid,
+        reply: Waiter,
+    },
+    Get {
+        id: Uuid,
+        reply: oneshot::Sender<Option<LoroDoc>>,
+    },
+    List {
+        reply: oneshot::Sender<Vec<Uuid>>,
+    },
+    Documents {
+        reply: oneshot::Sender<Vec<DocumentInfo>>,
+    },
+    Colrev {
+        reply: oneshot::Sender<i64>,
+    },
+    SetPermissions {
+        id: Option<Uuid>,
+        permissions: Permissions,
+        reply: oneshot::Sender<Result<(), ClientError>>,
+    },
+    Close,
+}
+
+enum Exit {
+    // Connection was lost, client should reconnect
+    Disconnected,
+    // Server rejected the sync
+    Error(ErrorCode),
+    Closed,
+}
+
+async fn connect(url: String) -> Result<WebSocket, String> {
+    let (websocket, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(websocket)
+}
+
+struct CollectionActor {
+    config: CollectionConfig,
+    colrev: i64,
+    items: HashMap<Uuid, Item>,
+    // Sent changes waiting for confirmation, changeid -> (id, waiters)
+    sent: HashMap<Uuid, (Uuid, Vec<Waiter>)>,
+    sent_permissions: HashMap<Uuid, oneshot::Sender<Result<(), ClientError>>>,
+    // Messages that are sent after handling the current event
+    outgoing: Vec<ClientMessage>,
+    receiver: mpsc::UnboundedReceiver<Command>,
+    status: watch::Sender<ConnectionStatus>,
+    events: broadcast::Sender<CollectionEvent>,
+    // Documents that were changed since the last save to the store
+    unsaved: HashSet<Uuid>,
+    saved_colrev: i64,
+}
+
+impl CollectionActor {
+    async fn run(&mut self) {
+        debug!("col-{}: client start", self.config.col);
+        self.load();
+        let mut delay = self.config.reconnect_delay;
+        loop {
+            let res = match self.sync_url() {
+                Ok(url) => connect(url).await,
+                Err(err) => Err(err),
+            };
+            let exit = match res {
+                Ok(websocket) => {
+                    delay = self.config.reconnect_delay;
+                    self.run_connection(websocket).await
+                }
+                Err(err) => {
+                    warn!("col-{}: couldn't connect: {}", self.config.col, err);
+                    Exit::Disconnected
+                }
+            };
+            self.handle_disconnect();
+            match exit {
+                Exit::Disconnected => {}
+                Exit::Error(code) => {
+                    warn!("col-{}: sync error: {:?}", self.config.col, code);
+                    for (_, item) in self.items.drain() {
+                        item.fail(ClientError::Rejected(code.clone()));
+                    }
+                    self.status.send_replace(ConnectionStatus::Error(code));
+                    return;
+                }
+                Exit::Closed => break,
+            }
+            if !self.wait(delay).await {
+                break;
+            }
+            delay = (delay * 2).min(self.config.max_reconnect_delay);
+        }
+        debug!("col-{}: client exit", self.config.col);
+    }
+
+    fn sync_url(&self) -> Result<String, String> {
+        let colrev = self.colrev.to_string();
+        let mut params = vec![
+            ("token", self.config.token.as_str()),
+            ("col", self.config.col.as_str()),
+            ("colrev", colrev.as_str()),
+        ];
+        if self.config.compression {
+            params.push(("compression", "deflate"));
+        }
+        let url = url::Url::parse_with_params(&self.config.url, &params)
+            .map_err(|err| err.to_string())?;
+        Ok(url.into())
+    }
+
+    // Handles commands while waiting for the reconnect, returns false when
+    // the collection was closed
+    async fn wait(&mut self, delay: Duration) -> bool {
+        let timer = sleep(delay);
+        tokio::pin!(timer);
+        loop {
+            tokio::select! {
+                _ = &mut timer => return true,
+                cmd = self.receiver.recv() => match cmd {
+                    Some(Command::Close) | None => return false,
+                    Some(cmd) => {
+                        self.handle_command(cmd);
+                        self.save();
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_connection(&mut self, websocket: WebSocket) -> Exit {
+        debug!("col-{}: connected", self.config.col);
+        self.status.send_replace(ConnectionStatus::Connected);
+        let (mut sink, mut stream) = websocket.split();
+        let mut heartbeat = Heartbeat::new(
+            self.config.heartbeat_interval,
+            self.config.heartbeat_timeout,
+        );
+        let exit = 'conn: loop {
+            let exit = tokio::select! {
+                cmd = self.receiver.recv() => match cmd {
+                    Some(Command::Close) | None => Some(Exit::Closed),
+                    Some(cmd) => {
+                        self.handle_command(cmd);
+                        None
+                    }
+                },
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        self.handle_message(&text, &mut heartbeat)
+                    }
+                    Some(Ok(Message::Close(_))) | None => {
+                        Some(Exit::Disconnected)
+                    }
+                    Some(Err(err)) => {
+                        debug!("col-{}: websocket error: {}", self.config.col, err);
+                        Some(Exit::Disconnected)
+                    }
+                    Some(Ok(Message::Binary(data))) => {
+                        self.handle_batch(&data, &mut heartbeat)
+                    }
+                    Some(Ok(_)) => None,
+                },
+                _ = sleep_until(heartbeat.next_event()) => {
+                    match heartbeat.fire() {
+                        HeartbeatEvent::Send(i) => {
+                            trace!("col-{}: heartbeat {}", self.config.col, i);
+                            let msg = HeartbeatMessage { i };
+                            self.outgoing.push(ClientMessage::Heartbeat(msg));
+                            None
+                        }
+                        HeartbeatEvent::Timeout => {
+                            warn!(
+                                "col-{}: no response from server for too long",
+                                self.config.col
+                            );
+                            Some(Exit::Disconnected)
+                        }
+                    }
+                }
+            };
+            self.save();
+            if let Some(exit) = exit {
+                break exit;
+            }
+            for msg in std::mem::take(&mut self.outgoing) {
+                let Ok(encoded) = serde_json::to_string(&msg) else {
+                    continue;
+                };
+                if sink.send(Message::Text(encoded)).await.is_err() {
+                    break 'conn Exit::Disconnected;
+                }
+            }
+        };
+        _ = sink.close().await;
+        debug!("col-{}: disconnected", self.config.col);
+        exit
+    }
+
+    fn load(&mut self) {
+        let Some(store) = &mut self.config.store else {
+            return;
+        };
+        let (colrev, docs) = match store.load(&self.config.col) {
+            Ok(res) => res,
+            Err(err) => {
+                warn!("col-{}: couldn't load store: {}", self.config.col, err);
+                return;
+            }
+        };
+        self.colrev = colrev;
+        self.saved_colrev = colrev;
+        for doc in docs {
+            let state = if doc.is_changed {
+                ItemState::Changed
+            } else {
+                ItemState::Synchronized
+            };
+            let item = Item {
+                local: doc.local.as_deref().and_then(doc_from_bytes),
+                remote: doc.remote.as_deref().and_then(doc_from_bytes),
+                colrev: doc.colrev,
+                state,
+                waiters: Vec::new(),
+            };
+            self.items.insert(doc.id, item);
+        }
+        debug!(
+            "col-{}: loaded {} documents from store, colrev {}",
+            self.config.col,
+            self.items.len(),
+            self.colrev
+        );
+    }
+
+    // Writes changed documents to the store
+    fn save(&mut self) {
+        let Some(store) = &mut self.config.store else {
+            return;
+        };
+        let col = &self.config.col;
+        for id in self.unsaved.drain() {
+            let res = match self.items.get(&id) {
+                Some(item) => {
+                    let export = |doc: &Option<LoroDoc>| {
+                        doc.as_ref().and_then(|doc| {
+                            doc.export(ExportMode::Snapshot).ok()
+                        })
+                    };
+                    let doc = StoredDocument {
+                        id,
+                        local: export(&item.local),
+                        remote: export(&item.remote),
+                        colrev: item.colrev,
+                        is_changed: item.state != ItemState::Synchronized,
+                    };
+                    store.save_document(col, &doc)
+                }
+                None => store.delete_document(col, id),
+            };
+            if let Err(err) = res {
+                warn!("col-{}: couldn't save document: {}", col, err);
+            }
+        }
+        if self.colrev != self.saved_colrev {
+            match store.save_colrev(col, self.colrev) {
+                Ok(()) => self.saved_colrev = self.colrev,
+                Err(err) => warn!("col-{}: couldn't save colrev: {}", col, err),
+            }
+        }
+    }
+
+    fn handle_disconnect(&mut self) {
+        self.status.send_replace(ConnectionStatus::Disconnected);
+        self.outgoing.clear();
+        // Changes that were not confirmed are sent again after the next sync
+        for (_, (id, waiters)) in self.sent.drain() {
+            match self.items.get_mut(&id) {
+                Some(item) => item.waiters.extend(waiters),
+                None => {
+                    for waiter in waiters {
+                        _ = waiter.send(Err(ClientError::NotFound));
+                    }
+                }
+            }
+        }
+        for item in self.items.values_mut() {
+            if item.state == ItemState::ChangesSent {
+                item.state = ItemState::Changed;
+            }
+        }
+        for (_, reply) in self.sent_permissions.drain() {
+            _ = reply.send(Err(ClientError::Disconnected));
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        matches!(*self.status.borrow(), ConnectionStatus::Ready)
+    }
+
+    fn emit(&self, event: CollectionEvent) {
+        // Error only means that nobody is listening
+        _ = self.events.send(event);
+    }
+
+    // Commands
+
+    fn handle_command(&mut self, cmd: Command) {
+        match cmd {
+            Command::Create { id, doc, reply } => {
+                self.unsaved.insert(id);
+                let item = Item {
+                    remote: None,
+                    local: Some(doc),
+                    colrev: 0,
+                    state: ItemState::Changed,
+                    waiters: vec![reply],
+                };
+                self.items.insert(id, item);
+                self.flush(id);
+            }
+            Command::Change { id, change, reply } => {
+                self.handle_change_command(id, change, reply)
+            }
+            Command::Delete { id, reply } => {
+                self.handle_delete_command(id, reply)
+            }
+            Command::Get { id, reply } => {
+                let doc = self
+                    .items
+                    .get(&id)
+                    .and_then(|item| item.local.as_ref())
+                    .map(|doc| doc.fork());
+                _ = reply.send(doc);
+            }
+            Command::List { reply } => {
+                let ids = self
+                    .items
+                    .iter()
+                    .filter(|(_, item)| item.local.is_some())
+                    .map(|(id, _)| *id)
+                    .collect();
+                _ = reply.send(ids);
+            }
+            Command::Documents { reply } => {
+                let docs = self
+                    .items
+                    .iter()
+                    .filter(|(_, item)| item.local.is_some())
+                    .map(|(id, item)| DocumentInfo {
+                        id: *id,
+                        colrev: item.colrev,
+                        is_changed: item.state != ItemState::Synchronized,
+                    })
+                    .collect();
+                _ = reply.send(docs);
+            }
+            Command::Colrev { reply } => {
+                _ = reply.send(self.colrev);
+            }
+            Command::SetPermissions {
+                id,
+                permissions,
+                reply,
+            } => {
+                if !self.is_ready() {
+                    _ = reply.send(Err(ClientError::Disconnected));
+                    return;
+                }
+                let changeid = Uuid::new_v4();
+                self.sent_permissions.insert(changeid, reply);
+                let msg = ClientPermissionsMessage {
+                    col: self.config.col.clone(),
+                    id,
+                    permissions: Box::new(permissions),
+                    changeid,
+                };
+                self.outgoing.push(ClientMessage::Permissions(msg));
+            }
+            Command::Close => {}
+        }
+    }
+
+    fn handle_change_command(
+        &mut self,
+        id: Uuid,
+        change: Box<dyn FnOnce(&LoroDoc) + Send>,
+        reply: Waiter,
+    ) {
+        let Some(item) = self.items.get_mut(&id) else {
+            _ = reply.send(Err(ClientError::NotFound));
+            return;
+        };
+        let Some(local) = &item.local else {
+            _ = reply.send(Err(ClientError::Deleted));
+            return;
+        };
+        let version = local.oplog_vv();
+        change(local);
+        local.commit();
+        if local.oplog_vv() == version {
+            // Nothing changed
+            _ = reply.send(Ok(self.colrev));
+            return;
+        }
+        self.unsaved.insert(id);
+        item.state = ItemState::Changed;
+        item.waiters.push(reply);
+        self.flush(id);
+    }
+
+    fn handle_delete_command(&mut self, id: Uuid, reply: Waiter) {
+        let Some(item) = self.items.get_mut(&id) else {
+            _ = reply.send(Err(ClientError::NotFound));
+            return;
+        };
+        if item.local.is_none() {
+            _ = reply.send(Err(ClientError::Deleted));
+            return;
+        }
+        self.unsaved.insert(id);
+        if item.remote.is_none() {
+            // Document was not created on the server yet
+            if let Some(item) = self.items.remove(&id) {
+                item.fail(ClientError::Deleted);
+            }
+            _ = reply.send(Ok(self.colrev));
+            return;
+        }
+        item.local = None;
+        item.state = ItemState::Changed;
+        item.waiters.push(reply);
+        self.flush(id);
+    }
+
+    // Sends local changes of the item to the server
+    fn flush(&mut self, id: Uuid) {
+        if !self.is_ready() {
+            return;
+        }
+        let Some(item) = self.items.get_mut(&id) else {
+            return;
+        };
+        if item.state != ItemState::Changed {
+            return;
+        }
+        let (op, data) = match (&item.local, &item.remote) {
+            (Some(local), None) => {
+                (Op::Create, local.export(ExportMode::Snapshot))
+            }
+            (None, Some(_)) => (Op::Delete, Ok(Vec::new())),
+            (Some(local), Some(remote)) => (
+                Op::Update,
+                local.export(ExportMode::updates(&remote.oplog_vv())),
+            ),
+            (None, None) => return,
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("col-{}: couldn't export doc: {}", self.config.col, err);
+                return;
+            }
+        };
+        let data = match op {
+            Op::Delete => None,
+            _ => Some(BASE64_STANDARD.encode(data)),
+        };
+        let changeid = Uuid::new_v4();
+        self.sent
+            .insert(changeid, (id, std::mem::take(&mut item.waiters)));
+        item.state = ItemState::ChangesSent;
+        let msg = ClientChangeMessage {
+            id,
+            col: self.config.col.clone(),
+            op,
+            data,
+            changeid,
+            permissions: None,
+        };
+        self.outgoing.push(ClientMessage::Change(msg));
+    }
+
+    fn flush_all(&mut self) {
+        let changed: Vec<Uuid> = self
+            .items
+            .iter()
+            .filter(|(_, item)| item.state == ItemState::Changed)
+            .map(|(id, _)| *id)
+            .collect();
+        debug!(
+            "col-{}: flushing changes, {} items",
+            self.config.col,
+            changed.len()
+        );
+        for id in changed {
+            self.flush(id);
+        }
+    }
+
+    fn request_doc(&mut self, id: Uuid) {
+        let msg = GetMessage {
+            id,
+            col: self.config.col.clone(),
+        };
+        self.outgoing.push(ClientMessage::Get(msg));
+    }
+
+    // Server messages
+
+    fn handle_message(
+        &mut self,
+        text: &str,
+        heartbeat: &mut Heartbeat,
+    ) -> Option<Exit> {
+        match serde_json::from_str::<ServerMessage>(text) {
+            Ok(msg) => self.handle_server_message(msg, heartbeat),
+            Err(err) => {
+                warn!(
+                    "col-{}: couldn't parse message: {}",
+                    self.config.col, err
+                );
+                None
+            }
+        }
+    }
+
+    // Compressed batch is a deflated JSON array of messages
+    fn handle_batch(
+        &mut self,
+        data: &[u8],
+        heartbeat: &mut Heartbeat,
+    ) -> Option<Exit> {
+        let mut text = String::new();
+        if let Err(err) = DeflateDecoder::new(data).read_to_string(&mut text) {
+            warn!("col-{}: couldn't inflate batch: {}", self.config.col, err);
+            return None;
+        }
+        let messages = match serde_json::from_str::<Vec<ServerMessage>>(&text) {
+            Ok(messages) => messages,
+            Err(err) => {
+                warn!("col-{}: couldn't parse batch: {}", self.config.col, err);
+                return None;
+            }
+        };
+        trace!(
+            "col-{}: received batch of {} messages",
+            self.config.col,
+            messages.len()
+        );
+        for msg in messages {
+            if let Some(exit) = self.handle_server_message(msg, heartbeat) {
+                return Some(exit);
+            }
+        }
+        None
+    }
+
+    fn handle_server_message(
+        &mut self,
+        msg: ServerMessage,
+        heartbeat: &mut Heartbeat,
+    ) -> Option<Exit> {
+        match msg {
+            ServerMessage::Heartbeat(msg) => heartbeat.handle_response(msg.i),
+            ServerMessage::SyncProgress(msg) => self.colrev = msg.colrev,
+            ServerMessage::SyncComplete(msg) => {
+                debug!("col-{}: sync complete", self.config.col);
+                self.colrev = msg.colrev;
+                self.status.send_replace(ConnectionStatus::Ready);
+                self.flush_all();
+            }
+            ServerMessage::SyncError(msg) => {
+                if let ErrorCode::ResyncRequired = msg.code {
+                    self.reset();
+                    return Some(Exit::Disconnected);
+                }
+                return Some(Exit::Error(msg.code));
+            }
+            ServerMessage::GetError(msg) => return self.handle_get_error(msg),
+            ServerMessage::Doc(msg) => {
+                self.handle_doc(msg.id, msg.data.as_deref(), false);
+                self.set_item_colrev(msg.id, msg.colrev);
+            }
+            ServerMessage::Change(msg) => self.handle_change(msg),
+            ServerMessage::ChangeError(msg) => {
+                return self.handle_change_error(msg)
+            }
+            ServerMessage::Permissions(msg) => {
+                if let Some(reply) = self.sent_permissions.remove(&msg.changeid)
+                {
+                    _ = reply.send(Ok(()));
+                }
+                self.emit(CollectionEvent::Permissions {
+                    id: msg.id,
+                    permissions: msg.permissions,
+                });
+            }
+            ServerMessage::PermissionsError(msg) => {
+                if let Some(reply) = self.sent_permissions.remove(&msg.changeid)
+                {
+                    _ = reply.send(Err(ClientError::Rejected(msg.code)));
+                }
+            }
+        }
+        None
+    }
+
+    // Drops synchronized documents, so the next sync starts from the
+    // beginning, local changes are kept and sent again
+    fn reset(&mut self) {
+        debug!("col-{}: resync from the start", self.config.col);
+        self.colrev = 0;
+        let ids: Vec<Uuid> = self
+            .items
+            .iter()
+            .filter(|(_, item)| item.state == ItemState::Synchronized)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            self.items.remove(&id);
+            self.unsaved.insert(id);
+            self.emit(CollectionEvent::Deleted(id));
+        }
+    }
+
+    fn handle_get_error(&mut self, msg: GetErrorMessage) -> Option<Exit> {
+        let GetErrorMessage { id, code } = msg;
+        match code {
+            ErrorCode::AuthFailed => return Some(Exit::Error(code)),
+            ErrorCode::NotFound => {
+                debug!(
+                    "col-{}: document doesn't exist: {}",
+                    self.config.col, id
+                );
+                self.unsaved.insert(id);
+                if let Some(item) = self.items.remove(&id) {
+                    item.fail(ClientError::NotFound);
+                    self.emit(CollectionEvent::Deleted(id));
+                }
+            }
+            code => {
+                warn!("col-{}: get error: {} {:?}", self.config.col, id, code)
+            }
+        }
+        None
+    }
+
+    fn handle_doc(&mut self, id: Uuid, data: Option<&str>, is_own: bool) {
+        self.unsaved.insert(id);
+        let Some(data) = data else {
+            if let Some(item) = self.items.remove(&id) {
+                item.fail(ClientError::Deleted);
+                if !is_own {
+                    self.emit(CollectionEvent::Deleted(id));
+                }
+            }
+            return;
+        };
+        let Some(doc) = doc_from_base64(data) else {
+            warn!("col-{}: couldn't import doc: {}", self.config.col, id);
+            return;
+        };
+        match self.items.get_mut(&id) {
+            Some(item) => {
+                if let Some(local) = &item.local {
+                    merge_changes(local, &doc);
+                }
+                item.remote = Some(doc);
+                item.update_state();
+            }
+            None => {
+                let item = Item {
+                    remote: Some(doc.fork()),
+                    local: Some(doc),
+                    colrev: 0,
+                    state: ItemState::Synchronized,
+                    waiters: Vec::new(),
+                };
+                self.items.insert(id, item);
+            }
+        }
+        if !is_own {
+            self.emit(CollectionEvent::Changed(id));
+        }
+    }
+
+    fn set_item_colrev(&mut self, id: Uuid, colrev: i64) {
+        if let Some(item) = self.items.get_mut(&id) {
+            item.colrev = colrev;
+        }
+    }
+
+    fn handle_update(&mut self, id: Uuid, data: Option<&str>, is_own: bool) {
+        self.unsaved.insert(id);
+        let update = data.and_then(|data| BASE64_STANDARD.decode(data).ok());
+        let item = self.items.get_mut(&id);
+        let imported = match (item, &update) {
+            (Some(item), Some(update)) => match &item.remote {
+                Some(remote) => remote.import(update).is_ok().then_some(item),
+                None => None,
+            },
+            _ => None,
+        };
+        let (Some(item), Some(update)) = (imported, update) else {
+            warn!("col-{}: couldn't apply changes: {}", self.config.col, id);
+            self.request_doc(id);
+            return;
+        };
+        if let Some(local) = &item.local {
+            _ = local.import(&update);
+        }
+        item.update_state();
+        if !is_own {
+            self.emit(CollectionEvent::Changed(id));
+        }
+    }
+
+    fn handle_change(&mut self, msg: ServerChangeMessage) {
+        let ServerChangeMessage {
+            id,
+            op,
+            data,
+            changeid,
+            changeids,
+            colrev,
+            ..
+        } = msg;
+        // Server can replace several updates of the document with the last
+        // one, changeids of the replaced updates are confirmed by it as well
+        let sent: Vec<_> = std::iter::once(changeid)
+            .chain(changeids)
+            .filter_map(|changeid| self.sent.remove(&changeid))
+            .collect();
+        let is_own = !sent.is_empty();
+        match op {
+            Op::Create => self.handle_doc(id, data.as_deref(), is_own),
+            Op::Update => self.handle_update(id, data.as_deref(), is_own),
+            Op::Delete => self.handle_doc(id, None, is_own),
+        }
+        self.set_item_colrev(id, colrev);
+        self.colrev = colrev;
+        for (_, waiters) in sent {
+            for waiter in waiters {
+                _ = waiter.send(Ok(colrev));
+            }
+        }
+    }
+
+    fn handle_change_error(&mut self, msg: ChangeErrorMessage) -> Option<Exit> {
+        let ChangeErrorMessage { code, id, changeid } = msg;
+        warn!(
+            "col-{}: rejected change: {} {:?}",
+            self.config.col, id, code
+        );
+        let waiters = match self.sent.remove(&changeid) {
+            Some((_, waiters)) => waiters,
+            None => Vec::new(),
+        };
+        let fail = |waiters: Vec<Waiter>, code: &ErrorCode| {
+            for waiter in waiters {
+                _ = waiter.send(Err(ClientError::Rejected(code.clone())));
+            }
+        };
+        if let ErrorCode::AuthFailed = code {
+            fail(waiters, &code);
+            return Some(Exit::Error(code));
+        }
+        self.unsaved.insert(id);
+        let Some(item) = self.items.get_mut(&id) else {
+            fail(waiters, &code);
+            return None;
+        };
+        match code {
+            // Document doesn't exist on the server, it is created again to
+            // not lose the data
+            ErrorCode::NotFound => {
+                if item.local.is_none() {
+                    let colrev = self.colrev;
+                    for waiter in waiters {
+                        _ = waiter.send(Ok(colrev));
+                    }
+                    if let Some(item) = self.items.remove(&id) {
+                        item.fail(ClientError::Deleted);
+                    }
+                    return None;
+                }
+                item.remote = None;
+                item.state = ItemState::Changed;
+                item.waiters.extend(waiters);
+                self.flush(id);
+            }
+            // Changes are reverted to the last known remote state
+            ErrorCode::Forbidden => {
+                fail(waiters, &code);
+                match &item.remote {
+                    Some(remote) => {
+                        item.local = Some(remote.fork());
+                        item.state = ItemState::Synchronized;
+                    }
+                    None => {
+                        if let Some(item) = self.items.remove(&id) {
+                            item.fail(ClientError::Rejected(code));
+                        }
+                    }
+                }
+            }
+            // Server couldn't apply the change, document is reloaded
+            code => {
+                fail(waiters, &code);
+                self.request_doc(id);
+            }
+        }
+        None
+    }
+}
+
+// Synchronizes documents of the collection in the background task.
+// Collection keeps working offline, local changes are sent when the
+// connection is restored.
+#[derive(Clone)]
+pub struct SinkronCollection {
+    sender: mpsc::UnboundedSender<Command>,
+    status: watch::Receiver<ConnectionStatus>,
+    events: broadcast::Sender<CollectionEvent>,
+}
+
+impl SinkronCollection {
+    // Should be called inside of the tokio runtime
+    pub fn connect(config: CollectionConfig) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (status_sender, status) =
+            watch::channel(ConnectionStatus::Disconnected);
+        let (events, _) = broadcast::channel(1024);
+        let mut actor = CollectionActor {
+            config,
+            colrev: 0,
+            items: HashMap::new(),
+            sent: HashMap::new(),
+            sent_permissions: HashMap::new(),
+            outgoing: Vec::new(),
+            receiver,
+            status: status_sender,
+            events: events.clone(),
+            unsaved: HashSet::new(),
+            saved_colrev: 0,
+        };
+        tokio::spawn(async move { actor.run().await });
+        Self {
+            sender,
+            status,
+            events,
+        }
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.borrow().clone()
+    }
+
+    // Waits until the initial sync is completed
+    pub async fn ready(&self) -> Result<(), ClientError> {
+        let mut status = self.status.clone();
+        let status = status
+            .wait_for(|status| {
+                matches!(
+                    status,
+                    ConnectionStatus::Ready | ConnectionStatus::Error(_)
+                )
+            })
+            .await
+            .map_err(|_| ClientError::Closed)?;
+        match &*status {
+            ConnectionStatus::Error(code) => {
+                Err(ClientError::Rejected(code.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn events(&self) -> broadcast::Receiver<CollectionEvent> {
+        self.events.subscribe()
+    }
+
+    // Command is sent immediately, returned future only waits for the reply
+    fn request<T>(
+        &self,
+        cmd: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> impl Future<Output = Result<T, ClientError>> {
+        let (sender, receiver) = oneshot::channel();
+        let is_sent = self.sender.send(cmd(sender)).is_ok();
+        async move {
+            if !is_sent {
+                return Err(ClientError::Closed);
+            }
+            receiver.await.map_err(|_| ClientError::Closed)
+        }
+    }
+
+    // Methods that change documents apply the change locally right away.
+    // Returned futures wait until the server confirms the change and resolve
+    // with colrev of the collection after the change. When the client is
+    // offline, they wait until the connection is restored. Futures can be
+    // dropped when confirmation is not needed, the change is sent anyway.
+
+    // Returns id of the created document together with the confirmation
+    pub fn create(
+        &self,
+        doc: LoroDoc,
+    ) -> (Uuid, impl Future<Output = Result<i64, ClientError>>) {
+        let id = Uuid::new_v4();
+        (id, self.create_with_id(id, doc))
+    }
+
+    pub fn create_with_id(
+        &self,
+        id: Uuid,
+        doc: LoroDoc,
+    ) -> impl Future<Output = Result<i64, ClientError>> {
+        let res = self.request(move |reply| Command::Create { id, doc, reply });
+        async move { res.await? }
+    }
+
+    pub fn change(
+        &self,
+        id: Uuid,
+        change: impl FnOnce(&LoroDoc) + Send + 'static,
+    ) -> impl Future<Output = Result<i64, ClientError>> {
+        let change = Box::new(change);
+        let res =
+            self.request(move |reply| Command::Change { id, change, reply });
+        async move { res.await? }
+    }
+
+    pub fn delete(
+        &self,
+        id: Uuid,
+    ) -> impl Future<Output = Result<i64, ClientError>> {
+        let res = self.request(move |reply| Command::Delete { id, reply });
+        async move { res.await? }
+    }
+
+    // Returns copy of the local version of the document
+    pub async fn get(&self, id: Uuid) -> Result<Option<LoroDoc>, ClientError> {
+        self.request(|reply| Command::Get { id, reply }).await
+    }
+
+    // Returns colrevs of all documents, except deleted
+    pub async fn documents(&self) -> Result<Vec<DocumentInfo>, ClientError> {
+        self.request(|reply| Command::Documents { reply }).await
+    }
+
+    // Returns ids of all documents, except deleted
+    pub async fn list(&self) -> Result<Vec<Uuid>, ClientError> {
+        self.request(|reply| Command::List { reply }).await
+    }
+
+    pub async fn colrev(&self) -> Result<i64, ClientError> {
+        self.request(|reply| Command::Colrev { reply }).await
+    }
+
+    // Changes permissions of the document, or of the whole collection when
+    // `id` is not set. Requires connection to the server.
+    pub async fn set_permissions(
+        &self,
+        permissions: Permissions,
+        id: Option<Uuid>,
+    ) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetPermissions {
+            id,
+            permissions,
+            reply,
+        })
+        .await?
+    }
+
+    // Disconnects from the server and stops the background task
+    pub fn close(&self) {
+        _ = self.sender.send(Command::Close);
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+use sinkron::protocol::ErrorCode;
+
+#[derive(Debug, Clone)]
+pub enum ClientError {
+    // Server rejected the change or the sync
+    Rejected(ErrorCode),
+    // Document doesn't exist in the collection
+    NotFound,
+    // Document is deleted and can't be changed
+    Deleted,
+    // Request requires connection to the server
+    Disconnected,
+    // Collection was closed
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Rejected(code) => write!(f, "Rejected: {:?}", code),
+            ClientError::NotFound => write!(f, "Document not found"),
+            ClientError::Deleted => write!(f, "Document is deleted"),
+            ClientError::Disconnected => write!(f, "Not connected"),
+            ClientError::Closed => write!(f, "Collection is closed"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
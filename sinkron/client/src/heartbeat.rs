@@ -0,0 +1,52 @@
+use tokio::time::{Duration, Instant};
+
+const INITIAL_HEARTBEAT_DELAY: Duration = Duration::from_millis(1000);
+
+pub enum HeartbeatEvent {
+    // Heartbeat should be sent with the index
+    Send(i32),
+    // Server didn't respond in time
+    Timeout,
+}
+
+// Keeps track of heartbeats of a single connection
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    i: i32,
+    next: Instant,
+    // Time until the server should respond to the sent heartbeat
+    deadline: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            i: 1,
+            next: Instant::now() + INITIAL_HEARTBEAT_DELAY,
+            deadline: None,
+        }
+    }
+
+    // Time of the next event
+    pub fn next_event(&self) -> Instant {
+        self.deadline.unwrap_or(self.next)
+    }
+
+    // Called when the time of the next event has come
+    pub fn fire(&mut self) -> HeartbeatEvent {
+        if self.deadline.is_some() {
+            return HeartbeatEvent::Timeout;
+        }
+        self.deadline = Some(Instant::now() + self.timeout);
+        HeartbeatEvent::Send(self.i)
+    }
+
+    pub fn handle_response(&mut self, i: i32) {
+        self.deadline = None;
+        self.i = i + 1;
+        self.next = Instant::now() + self.interval;
+    }
+}
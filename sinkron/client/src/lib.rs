@@ -0,0 +1,17 @@
+mod collection;
+mod error;
+mod heartbeat;
+mod store;
+
+pub use collection::{
+    CollectionConfig, CollectionEvent, ConnectionStatus, DocumentInfo,
+    SinkronCollection,
+};
+pub use error::ClientError;
+#[cfg(feature = "sqlite")]
+pub use store::SqliteStore;
+pub use store::{CollectionStore, StoredDocument};
+
+pub use loro;
+pub use sinkron::permissions::Permissions;
+pub use sinkron::protocol::ErrorCode;
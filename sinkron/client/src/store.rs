@@ -0,0 +1,192 @@
+use uuid::Uuid;
+
+// Saved state of the document
+pub struct StoredDocument {
+    pub id: Uuid,
+    // Loro snapshots of the local and remote versions
+    pub local: Option<Vec<u8>>,
+    pub remote: Option<Vec<u8>>,
+    // Colrev of the last change of the remote version
+    pub colrev: i64,
+    // Document has local changes that were not confirmed by the server
+    pub is_changed: bool,
+}
+
+// Persists documents of the collection, so local changes are not lost when
+// the app is restarted while offline.
+//
+// Methods are called from the collection task after each change, they are
+// expected to be fast.
+pub trait CollectionStore: Send {
+    // Returns colrev and documents of the collection
+    fn load(&mut self, col: &str)
+        -> Result<(i64, Vec<StoredDocument>), String>;
+
+    fn save_colrev(&mut self, col: &str, colrev: i64) -> Result<(), String>;
+
+    fn save_document(
+        &mut self,
+        col: &str,
+        doc: &StoredDocument,
+    ) -> Result<(), String>;
+
+    fn delete_document(&mut self, col: &str, id: Uuid) -> Result<(), String>;
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use rusqlite::{params, Connection, OptionalExtension};
+    use uuid::Uuid;
+
+    use super::{CollectionStore, StoredDocument};
+
+    // Each migration is applied once, the number of applied migrations is
+    // stored in the `user_version` of the database
+    const MIGRATIONS: &[&str] = &[
+        r#"
+        CREATE TABLE IF NOT EXISTS "collections" (
+            "id" text NOT NULL PRIMARY KEY,
+            "colrev" bigint NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS "documents" (
+            "id" text NOT NULL,
+            "col" text NOT NULL,
+            "local" blob,
+            "remote" blob,
+            "is_changed" boolean NOT NULL,
+            PRIMARY KEY ("col", "id")
+        );
+        "#,
+        r#"
+        ALTER TABLE "documents" ADD COLUMN "colrev" bigint NOT NULL DEFAULT 0;
+        "#,
+    ];
+
+    // Stores documents of any number of collections in the SQLite database
+    pub struct SqliteStore {
+        conn: Connection,
+    }
+
+    impl SqliteStore {
+        pub fn open(path: &str) -> rusqlite::Result<Self> {
+            let mut conn = Connection::open(path)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            let version: usize =
+                conn.pragma_query_value(None, "user_version", |row| {
+                    row.get(0)
+                })?;
+            for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+                let tx = conn.transaction()?;
+                tx.execute_batch(migration)?;
+                tx.pragma_update(None, "user_version", i + 1)?;
+                tx.commit()?;
+            }
+            Ok(Self { conn })
+        }
+    }
+
+    impl CollectionStore for SqliteStore {
+        fn load(
+            &mut self,
+            col: &str,
+        ) -> Result<(i64, Vec<StoredDocument>), String> {
+            let colrev: Option<i64> = self
+                .conn
+                .query_row(
+                    "SELECT colrev FROM collections WHERE id = ?",
+                    [col],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(|err| err.to_string())?;
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT id, local, remote, colrev, is_changed \
+                    FROM documents WHERE col = ?",
+                )
+                .map_err(|err| err.to_string())?;
+            let rows = stmt
+                .query_map([col], |row| {
+                    let id: String = row.get(0)?;
+                    Ok((id, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+                })
+                .map_err(|err| err.to_string())?;
+            let mut docs = Vec::new();
+            for row in rows {
+                let (id, local, remote, colrev, is_changed) =
+                    row.map_err(|err| err.to_string())?;
+                let id = Uuid::parse_str(&id).map_err(|err| err.to_string())?;
+                docs.push(StoredDocument {
+                    id,
+                    local,
+                    remote,
+                    colrev,
+                    is_changed,
+                });
+            }
+            Ok((colrev.unwrap_or(0), docs))
+        }
+
+        fn save_colrev(
+            &mut self,
+            col: &str,
+            colrev: i64,
+        ) -> Result<(), String> {
+            self.conn
+                .execute(
+                    "INSERT INTO collections (id, colrev) VALUES (?, ?) \
+                    ON CONFLICT (id) DO UPDATE SET colrev = excluded.colrev",
+                    params![col, colrev],
+                )
+                .map_err(|err| err.to_string())?;
+            Ok(())
+        }
+
+        fn save_document(
+            &mut self,
+            col: &str,
+            doc: &StoredDocument,
+        ) -> Result<(), String> {
+            self.conn
+                .execute(
+                    "INSERT INTO documents \
+                    (id, col, local, remote, colrev, is_changed) \
+                    VALUES (?, ?, ?, ?, ?, ?) \
+                    ON CONFLICT (col, id) DO UPDATE SET \
+                    local = excluded.local, \
+                    remote = excluded.remote, \
+                    colrev = excluded.colrev, \
+                    is_changed = excluded.is_changed",
+                    params![
+                        doc.id.to_string(),
+                        col,
+                        doc.local,
+                        doc.remote,
+                        doc.colrev,
+                        doc.is_changed
+                    ],
+                )
+                .map_err(|err| err.to_string())?;
+            Ok(())
+        }
+
+        fn delete_document(
+            &mut self,
+            col: &str,
+            id: Uuid,
+        ) -> Result<(), String> {
+            self.conn
+                .execute(
+                    "DELETE FROM documents WHERE col = ? AND id = ?",
+                    params![col, id.to_string()],
+                )
+                .map_err(|err| err.to_string())?;
+            Ok(())
+        }
+    }
+}
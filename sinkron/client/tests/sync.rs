@@ -0,0 +1,474 @@
+// Runs the client against the sinkron server with in-memory storage
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use loro::LoroDoc;
+use sinkron::permissions::{Permissions, Role};
+use sinkron::protocol::CLOSE_CODE_SLOW_CLIENT;
+use sinkron::storage::{MemoryStorage, Storage};
+use sinkron::{models, ErrorCode, SinkronBuilder, SinkronConfig};
+use sinkron_client::{
+    ClientError, CollectionConfig, CollectionEvent, ConnectionStatus,
+    SinkronCollection,
+};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+const COL: &str = "notes";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Everyone can read, only the "writer" can change documents
+const PERMISSIONS: &str = r#"{
+    "read": [{"kind": "any"}],
+    "create": [{"kind": "user", "id": "writer"}],
+    "update": [{"kind": "user", "id": "writer"}],
+    "delete": [{"kind": "user", "id": "writer"}],
+    "share": [{"kind": "user", "id": "writer"}]
+}"#;
+
+// Starts the server, returns url of the sync endpoint
+async fn start_server() -> String {
+    start_server_with_config(r#"{"apiToken": "token"}"#).await
+}
+
+async fn start_server_with_config(config: &str) -> String {
+    let storage = Arc::new(MemoryStorage::new());
+    let col = models::NewCollection {
+        id: COL.to_string(),
+        is_ref: false,
+        permissions: PERMISSIONS.to_string(),
+        quota: None,
+    };
+    storage.create_collection(col).await.unwrap();
+
+    let config: SinkronConfig = serde_json::from_str(config).unwrap();
+    // Token is the id of the user
+    let sinkron = SinkronBuilder::new(config)
+        .storage(storage)
+        .auth(|token| async move { Ok(token) })
+        .build()
+        .await
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, sinkron.router()).await.unwrap();
+    });
+    format!("ws://{}/sync", addr)
+}
+
+// Forwards connections to the server, allows to break them to test
+// reconnects
+struct Proxy {
+    url: String,
+    is_online: Arc<AtomicBool>,
+    connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Proxy {
+    async fn start(server_url: &str) -> Self {
+        let server_addr = server_url
+            .trim_start_matches("ws://")
+            .trim_end_matches("/sync")
+            .to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/sync", listener.local_addr().unwrap());
+        let is_online = Arc::new(AtomicBool::new(true));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        {
+            let is_online = is_online.clone();
+            let connections = connections.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (mut client, _) = listener.accept().await.unwrap();
+                    if !is_online.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let server_addr = server_addr.clone();
+                    let task = tokio::spawn(async move {
+                        let mut server =
+                            tokio::net::TcpStream::connect(server_addr)
+                                .await
+                                .unwrap();
+                        _ = tokio::io::copy_bidirectional(
+                            &mut client,
+                            &mut server,
+                        )
+                        .await;
+                    });
+                    connections.lock().unwrap().push(task);
+                }
+            });
+        }
+        Self {
+            url,
+            is_online,
+            connections,
+        }
+    }
+
+    // Breaks current connections and refuses new ones
+    fn go_offline(&self) {
+        self.is_online.store(false, Ordering::SeqCst);
+        for task in self.connections.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+
+    fn go_online(&self) {
+        self.is_online.store(true, Ordering::SeqCst);
+    }
+}
+
+fn config(url: &str, user: &str) -> CollectionConfig {
+    let mut config = CollectionConfig::new(url, user, COL);
+    config.reconnect_delay = Duration::from_millis(50);
+    config.max_reconnect_delay = Duration::from_millis(100);
+    config
+}
+
+async fn connect(url: &str, user: &str) -> SinkronCollection {
+    let col = SinkronCollection::connect(config(url, user));
+    timeout(TIMEOUT, col.ready()).await.unwrap().unwrap();
+    col
+}
+
+async fn wait_offline(col: &SinkronCollection) {
+    timeout(TIMEOUT, async {
+        while matches!(col.status(), ConnectionStatus::Ready) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+fn doc_with_text(text: &str) -> LoroDoc {
+    let doc = LoroDoc::new();
+    doc.get_text("text").insert(0, text).unwrap();
+    doc
+}
+
+async fn get_text(col: &SinkronCollection, id: Uuid) -> Option<String> {
+    let doc = col.get(id).await.unwrap()?;
+    Some(doc.get_text("text").to_string())
+}
+
+// Waits for the event about the document
+async fn wait_event(
+    events: &mut tokio::sync::broadcast::Receiver<CollectionEvent>,
+    id: Uuid,
+) -> CollectionEvent {
+    timeout(TIMEOUT, async {
+        loop {
+            let event = events.recv().await.unwrap();
+            let event_id = match &event {
+                CollectionEvent::Changed(id) | CollectionEvent::Deleted(id) => {
+                    Some(*id)
+                }
+                CollectionEvent::Permissions { id, .. } => *id,
+            };
+            if event_id == Some(id) {
+                return event;
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn create_change_and_delete() {
+    let url = start_server().await;
+    let writer = connect(&url, "writer").await;
+    let reader = connect(&url, "reader").await;
+    let mut events = reader.events();
+
+    let (id, created) = writer.create(doc_with_text("Hello"));
+    timeout(TIMEOUT, created).await.unwrap().unwrap();
+    wait_event(&mut events, id).await;
+    assert_eq!(get_text(&reader, id).await.as_deref(), Some("Hello"));
+
+    let change = writer.change(id, |doc| {
+        doc.get_text("text").insert(5, ", world").unwrap();
+    });
+    let colrev = timeout(TIMEOUT, change).await.unwrap().unwrap();
+    assert_eq!(colrev, 2);
+    wait_event(&mut events, id).await;
+    assert_eq!(get_text(&reader, id).await.as_deref(), Some("Hello, world"));
+
+    let colrev = timeout(TIMEOUT, writer.delete(id)).await.unwrap().unwrap();
+    assert_eq!(colrev, 3);
+    let event = wait_event(&mut events, id).await;
+    assert!(matches!(event, CollectionEvent::Deleted(_)));
+    assert!(reader.get(id).await.unwrap().is_none());
+    assert_eq!(writer.colrev().await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn rejected_change_is_reverted() {
+    let url = start_server().await;
+    let writer = connect(&url, "writer").await;
+    let reader = connect(&url, "reader").await;
+    let mut events = reader.events();
+
+    let (id, created) = writer.create(doc_with_text("Hello"));
+    timeout(TIMEOUT, created).await.unwrap().unwrap();
+    wait_event(&mut events, id).await;
+
+    let change = reader.change(id, |doc| {
+        doc.get_text("text").insert(0, "Bye. ").unwrap();
+    });
+    let res = timeout(TIMEOUT, change).await.unwrap();
+    assert!(matches!(
+        res,
+        Err(ClientError::Rejected(ErrorCode::Forbidden))
+    ));
+    assert_eq!(get_text(&reader, id).await.as_deref(), Some("Hello"));
+}
+
+#[tokio::test]
+async fn document_permissions_change() {
+    let url = start_server().await;
+    let writer = connect(&url, "writer").await;
+    let reader = connect(&url, "reader").await;
+    let mut events = reader.events();
+
+    let (id, created) = writer.create(doc_with_text("Hello"));
+    timeout(TIMEOUT, created).await.unwrap().unwrap();
+    wait_event(&mut events, id).await;
+
+    // Reader loses access and the document is removed
+    let shared: Permissions = serde_json::from_str(PERMISSIONS).unwrap();
+    let mut denied = shared.clone();
+    denied.deny.read.push(Role::User {
+        id: "reader".to_string(),
+    });
+    let res = writer.set_permissions(denied, Some(id));
+    timeout(TIMEOUT, res).await.unwrap().unwrap();
+    let event = wait_event(&mut events, id).await;
+    assert!(matches!(event, CollectionEvent::Deleted(_)));
+    assert!(reader.get(id).await.unwrap().is_none());
+
+    // Reader gains access again and receives the document
+    let res = writer.set_permissions(shared.clone(), Some(id));
+    timeout(TIMEOUT, res).await.unwrap().unwrap();
+    loop {
+        let event = wait_event(&mut events, id).await;
+        if matches!(event, CollectionEvent::Changed(_)) {
+            break;
+        }
+    }
+    assert_eq!(get_text(&reader, id).await.as_deref(), Some("Hello"));
+
+    // Client that connects later doesn't receive the document it can't read
+    let mut denied = shared;
+    denied.deny.read.push(Role::User {
+        id: "other".to_string(),
+    });
+    let res = writer.set_permissions(denied, Some(id));
+    timeout(TIMEOUT, res).await.unwrap().unwrap();
+    let other = connect(&url, "other").await;
+    assert!(other.get(id).await.unwrap().is_none());
+    assert_eq!(get_text(&reader, id).await.as_deref(), Some("Hello"));
+}
+
+#[tokio::test]
+async fn reconnect_and_resync() {
+    let url = start_server().await;
+    let proxy = Proxy::start(&url).await;
+    let writer = connect(&url, "writer").await;
+    let offline = connect(&proxy.url, "writer").await;
+
+    let (first, created) = writer.create(doc_with_text("First"));
+    timeout(TIMEOUT, created).await.unwrap().unwrap();
+    let mut events = offline.events();
+    wait_event(&mut events, first).await;
+
+    proxy.go_offline();
+    wait_offline(&offline).await;
+
+    // Changes made on the server while the client is offline
+    let (second, created) = writer.create(doc_with_text("Second"));
+    timeout(TIMEOUT, created).await.unwrap().unwrap();
+    let change = writer.change(first, |doc| {
+        doc.get_text("text").insert(5, " changed").unwrap();
+    });
+    timeout(TIMEOUT, change).await.unwrap().unwrap();
+
+    // Changes made by the client while it is offline
+    let offline_change = offline.change(first, |doc| {
+        doc.get_text("text").insert(0, "(offline) ").unwrap();
+    });
+    let (third, offline_created) = offline.create(doc_with_text("Third"));
+    assert_eq!(get_text(&offline, third).await.as_deref(), Some("Third"));
+
+    let mut writer_events = writer.events();
+    proxy.go_online();
+    timeout(TIMEOUT, offline_change).await.unwrap().unwrap();
+    timeout(TIMEOUT, offline_created).await.unwrap().unwrap();
+    timeout(TIMEOUT, offline.ready()).await.unwrap().unwrap();
+
+    let mut ids = offline.list().await.unwrap();
+    ids.sort();
+    let mut expected = vec![first, second, third];
+    expected.sort();
+    assert_eq!(ids, expected);
+    assert_eq!(get_text(&offline, second).await.as_deref(), Some("Second"));
+
+    // Both sides converge to the same state
+    wait_event(&mut writer_events, first).await;
+    let text = get_text(&writer, first).await;
+    assert_eq!(text.as_deref(), Some("(offline) First changed"));
+    assert_eq!(get_text(&offline, first).await, text);
+    assert_eq!(
+        offline.colrev().await.unwrap(),
+        writer.colrev().await.unwrap()
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn stored_changes_are_sent_after_restart() {
+    use sinkron_client::SqliteStore;
+
+    let url = start_server().await;
+    let proxy = Proxy::start(&url).await;
+    let writer = connect(&url, "writer").await;
+    let (first, created) = writer.create(doc_with_text("First"));
+    timeout(TIMEOUT, created).await.unwrap().unwrap();
+
+    let path =
+        std::env::temp_dir().join(format!("sinkron-{}.db", Uuid::new_v4()));
+    let path = path.to_str().unwrap().to_string();
+    let open = |url: &str| {
+        let mut config = config(url, "writer");
+        config.store = Some(Box::new(SqliteStore::open(&path).unwrap()));
+        SinkronCollection::connect(config)
+    };
+
+    let col = open(&proxy.url);
+    timeout(TIMEOUT, col.ready()).await.unwrap().unwrap();
+    let colrev = col.colrev().await.unwrap();
+    proxy.go_offline();
+    wait_offline(&col).await;
+    _ = col.change(first, |doc| {
+        doc.get_text("text").insert(0, "(offline) ").unwrap();
+    });
+    let (second, _) = col.create(doc_with_text("Second"));
+    // Commands are handled in order, so both changes are saved by now
+    assert!(col.get(second).await.unwrap().is_some());
+    col.close();
+
+    // Documents and colrev are loaded from the store before connecting
+    let col = open(&proxy.url);
+    assert_eq!(col.colrev().await.unwrap(), colrev);
+    let text = get_text(&col, first).await;
+    assert_eq!(text.as_deref(), Some("(offline) First"));
+    assert_eq!(get_text(&col, second).await.as_deref(), Some("Second"));
+
+    // Pending changes are sent after reconnect
+    let mut events = writer.events();
+    proxy.go_online();
+    timeout(TIMEOUT, col.ready()).await.unwrap().unwrap();
+    wait_event(&mut events, second).await;
+    assert_eq!(get_text(&writer, second).await.as_deref(), Some("Second"));
+    if get_text(&writer, first).await != text {
+        wait_event(&mut events, first).await;
+    }
+    assert_eq!(get_text(&writer, first).await, text);
+    col.close();
+
+    for suffix in ["", "-wal", "-shm"] {
+        _ = std::fs::remove_file(format!("{}{}", path, suffix));
+    }
+}
+
+#[tokio::test]
+async fn compressed_sync() {
+    let url = start_server_with_config(
+        r#"{"apiToken": "token", "compression": {"enabled": true, "threshold": 0}}"#,
+    )
+    .await;
+    let writer = connect(&url, "writer").await;
+    let mut ids = Vec::new();
+    for i in 0..3 {
+        let (id, created) = writer.create(doc_with_text(&format!("Doc {}", i)));
+        timeout(TIMEOUT, created).await.unwrap().unwrap();
+        ids.push(id);
+    }
+
+    // Sync response is sent as a binary frame
+    let sync_url = format!(
+        "{}?token=reader&col={}&colrev=0&compression=deflate",
+        url, COL
+    );
+    let (mut ws, _) = tokio_tungstenite::connect_async(sync_url).await.unwrap();
+    let msg = timeout(TIMEOUT, ws.next()).await.unwrap().unwrap().unwrap();
+    assert!(msg.is_binary());
+
+    let mut config = CollectionConfig::new(&url, "reader", COL);
+    config.compression = true;
+    let reader = SinkronCollection::connect(config);
+    timeout(TIMEOUT, reader.ready()).await.unwrap().unwrap();
+    for (i, id) in ids.iter().enumerate() {
+        let text = get_text(&reader, *id).await;
+        assert_eq!(text, Some(format!("Doc {}", i)));
+    }
+    assert_eq!(reader.colrev().await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn slow_client_is_disconnected() {
+    let url = start_server_with_config(
+        r#"{"apiToken": "token", "client": {"mailboxSize": 1}}"#,
+    )
+    .await;
+    let writer = connect(&url, "writer").await;
+
+    // Small receive buffer, so the server can't send much before the
+    // reader reads
+    let addr = url.trim_start_matches("ws://").trim_end_matches("/sync");
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let stream = socket.connect(addr.parse().unwrap()).await.unwrap();
+    let sync_url = format!("{}?token=reader&col={}&colrev=0", url, COL);
+    let (mut reader, _) = tokio_tungstenite::client_async(sync_url, stream)
+        .await
+        .unwrap();
+    loop {
+        let msg = timeout(TIMEOUT, reader.next()).await.unwrap();
+        let text = msg.unwrap().unwrap().into_text().unwrap();
+        if text.contains("sync_complete") {
+            break;
+        }
+    }
+
+    // Reader stops reading, so the socket buffers and then the mailbox of
+    // its client actor fill up
+    for _ in 0..50 {
+        // Random text, so the snapshot can't be compressed
+        let text: String =
+            (0..2000).map(|_| Uuid::new_v4().to_string()).collect();
+        let (_, created) = writer.create(doc_with_text(&text));
+        timeout(TIMEOUT, created).await.unwrap().unwrap();
+    }
+
+    let code = timeout(TIMEOUT, async {
+        while let Some(msg) = reader.next().await {
+            if let Message::Close(frame) = msg.unwrap() {
+                return frame.map(|frame| u16::from(frame.code));
+            }
+        }
+        None
+    })
+    .await
+    .unwrap();
+    assert_eq!(code, Some(CLOSE_CODE_SLOW_CLIENT));
+}
@@ -0,0 +1,4 @@
+ALTER TABLE "collections"
+    DROP COLUMN "documents_count",
+    DROP COLUMN "size",
+    DROP COLUMN "quota";
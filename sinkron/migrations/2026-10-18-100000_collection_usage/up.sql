@@ -0,0 +1,17 @@
+ALTER TABLE "collections"
+    ADD COLUMN "documents_count" bigint NOT NULL DEFAULT 0,
+    ADD COLUMN "size" bigint NOT NULL DEFAULT 0,
+    ADD COLUMN "quota" bigint;
+
+UPDATE "collections" SET
+    "documents_count" = "usage"."documents_count",
+    "size" = "usage"."size"
+FROM (
+    SELECT
+        "col_id",
+        count(*) FILTER (WHERE NOT "is_deleted") AS "documents_count",
+        coalesce(sum(octet_length("data")), 0) AS "size"
+    FROM "documents"
+    GROUP BY "col_id"
+) AS "usage"
+WHERE "collections"."id" = "usage"."col_id";
@@ -0,0 +1 @@
+ALTER TABLE "documents" ADD COLUMN "created_by" text;
@@ -0,0 +1,15 @@
+CREATE TABLE "subgroups" (
+    "id" uuid NOT NULL DEFAULT gen_random_uuid(),
+    "parent" text NOT NULL,
+    "child" text NOT NULL,
+    CONSTRAINT subgroups_pk PRIMARY KEY ("id"),
+    CONSTRAINT subgroups_fk_parent
+        FOREIGN KEY ("parent") REFERENCES "groups"("id")
+            ON DELETE NO ACTION ON UPDATE NO ACTION,
+    CONSTRAINT subgroups_fk_child
+        FOREIGN KEY ("child") REFERENCES "groups"("id")
+            ON DELETE NO ACTION ON UPDATE NO ACTION,
+    CONSTRAINT subgroups_unique UNIQUE ("parent", "child")
+);
+
+CREATE INDEX ON "subgroups" ("child");
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "groups" DROP COLUMN "metadata";
+
+DROP INDEX members_unique;
@@ -0,0 +1,10 @@
+-- Remove duplicate memberships before adding the unique index
+DELETE FROM "members" a
+    USING "members" b
+    WHERE a."group" = b."group"
+        AND a."user" = b."user"
+        AND a.ctid > b.ctid;
+
+CREATE UNIQUE INDEX members_unique ON "members" ("group", "user");
+
+ALTER TABLE "groups" ADD COLUMN "metadata" text;
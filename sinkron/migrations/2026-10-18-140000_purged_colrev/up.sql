@@ -0,0 +1,3 @@
+-- Colrev of the last document removed by gc, clients that synced
+-- before it can't receive removal of such documents anymore
+ALTER TABLE "collections" ADD COLUMN "purged_colrev" bigint NOT NULL DEFAULT 0;
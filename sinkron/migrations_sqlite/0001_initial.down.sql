@@ -0,0 +1,6 @@
+DROP TABLE "subgroups";
+DROP TABLE "members";
+DROP TABLE "groups";
+DROP TABLE "refs";
+DROP TABLE "documents";
+DROP TABLE "collections";
@@ -0,0 +1,55 @@
+CREATE TABLE "collections" (
+    "id" text NOT NULL PRIMARY KEY,
+    "is_ref" boolean NOT NULL DEFAULT false,
+    "colrev" bigint NOT NULL DEFAULT 0,
+    "permissions" text NOT NULL,
+    "documents_count" bigint NOT NULL DEFAULT 0,
+    "size" bigint NOT NULL DEFAULT 0,
+    "quota" bigint
+);
+
+CREATE TABLE "documents" (
+    "id" text NOT NULL PRIMARY KEY,
+    "created_at" text NOT NULL,
+    "updated_at" text NOT NULL,
+    "col_id" text NOT NULL REFERENCES "collections"("id"),
+    "colrev" bigint NOT NULL,
+    "data" blob,
+    "is_deleted" boolean NOT NULL DEFAULT false,
+    "permissions" text NOT NULL,
+    "created_by" text
+);
+
+CREATE INDEX documents_col_colrev ON "documents" ("col_id", "colrev");
+
+CREATE TABLE "refs" (
+    "id" text NOT NULL PRIMARY KEY,
+    "is_removed" boolean NOT NULL DEFAULT false,
+    "colrev" bigint NOT NULL,
+    "col_id" text NOT NULL REFERENCES "collections"("id"),
+    "doc_id" text NOT NULL REFERENCES "documents"("id")
+);
+
+CREATE INDEX refs_doc ON "refs" ("doc_id");
+CREATE INDEX refs_col_colrev ON "refs" ("col_id", "colrev");
+
+CREATE TABLE "groups" (
+    "id" text NOT NULL PRIMARY KEY,
+    "metadata" text
+);
+
+CREATE TABLE "members" (
+    "group" text NOT NULL REFERENCES "groups"("id"),
+    "user" text NOT NULL,
+    PRIMARY KEY ("group", "user")
+);
+
+CREATE INDEX members_user ON "members" ("user");
+
+CREATE TABLE "subgroups" (
+    "parent" text NOT NULL REFERENCES "groups"("id"),
+    "child" text NOT NULL REFERENCES "groups"("id"),
+    PRIMARY KEY ("parent", "child")
+);
+
+CREATE INDEX subgroups_child ON "subgroups" ("child");
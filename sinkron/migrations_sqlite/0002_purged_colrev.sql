@@ -0,0 +1 @@
+ALTER TABLE "collections" ADD COLUMN "purged_colrev" bigint NOT NULL DEFAULT 0;
@@ -1,22 +1,72 @@
+use std::collections::HashSet;
 use std::pin::Pin;
+use std::sync::Arc;
 
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use log::{debug, trace};
+use serde::Deserialize;
 use tokio::{
     select,
-    sync::{mpsc, oneshot},
+    sync::oneshot,
     time::{sleep, Duration, Instant},
 };
+use uuid::Uuid;
 
 use crate::actors::collection;
 use crate::actors::collection::{CollectionHandle, CollectionMessage};
+use crate::actors::mailbox::{Mailbox, MailboxFull, SlowClientPolicy};
 use crate::actors::supervisor::{ExitCallback, Supervisor};
+use crate::compression::{self, CompressionConfig, EncodedBatch};
 use crate::error::SinkronError;
 use crate::protocol::*;
+use crate::rate_limit::{
+    Limits, RateLimitConfig, RequestKind, UserRateLimiter,
+};
+
+fn default_sync_page_size() -> i64 {
+    500
+}
+
+fn default_mailbox_size() -> usize {
+    1000
+}
 
-// Period after which the client is considered inactive and will be disconnected
-// (Client should send heartbeat messages every 30 seconds)
-const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+fn default_disconnect_timeout() -> u64 {
+    60_000
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientConfig {
+    // Number of documents loaded from the database and sent to the client
+    // at once during the sync
+    #[serde(default = "default_sync_page_size")]
+    pub sync_page_size: i64,
+    // Max number of messages waiting to be sent to the client
+    #[serde(default = "default_mailbox_size")]
+    pub mailbox_size: usize,
+    #[serde(default)]
+    pub slow_client_policy: SlowClientPolicy,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    // Period after which the client is considered inactive and will be
+    // disconnected (in milliseconds). Client should send heartbeat messages
+    // every 30 seconds.
+    #[serde(default = "default_disconnect_timeout")]
+    pub disconnect_timeout: u64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            sync_page_size: default_sync_page_size(),
+            mailbox_size: default_mailbox_size(),
+            slow_client_policy: SlowClientPolicy::default(),
+            rate_limit: RateLimitConfig::default(),
+            disconnect_timeout: default_disconnect_timeout(),
+        }
+    }
+}
 
 // Client actor receives messages from the webscoket connection,
 // dispatches them to the Collection and when needed waits for the response
@@ -26,55 +76,148 @@ const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(60);
 pub enum ClientActorMessage {
     Sinkron(ServerMessage),
     Raw(String),
+    Change(QueuedChange),
+    // Sends message and disconnects the client
+    Disconnect(ServerMessage),
+}
+
+// Serialized change of the document
+pub struct QueuedChange {
+    pub id: Uuid,
+    pub colrev: i64,
+    pub changeid: Uuid,
+    // Updates contain the whole snapshot, so queued update can be replaced
+    // with the newer one
+    pub is_update: bool,
+    pub msg: String,
+    // Changeids of the queued updates that were replaced by this one
+    pub collapsed: Vec<Uuid>,
+}
+
+impl QueuedChange {
+    pub fn into_message(self) -> String {
+        if self.collapsed.is_empty() {
+            return self.msg;
+        }
+        match serde_json::from_str::<ServerMessage>(&self.msg) {
+            Ok(ServerMessage::Change(mut msg)) => {
+                msg.changeids = self.collapsed;
+                serde_json::to_string(&ServerMessage::Change(msg))
+                    .unwrap_or(self.msg)
+            }
+            _ => self.msg,
+        }
+    }
+}
+
+type SyncPageReply =
+    oneshot::Receiver<Result<collection::SyncPage, SinkronError>>;
+
+// State of the sync in progress
+struct SyncState {
+    // Colrev of the last delivered document
+    since: i64,
+    // Colrev of the collection at the moment when sync was started, moved
+    // forward when the collection is changed during the sync
+    until: i64,
+    skip_deleted: bool,
+    // Messages received during the sync, they are sent after it is completed
+    buffer: Vec<ClientActorMessage>,
+    // Changes sent by the client during the sync
+    changeids: HashSet<Uuid>,
+}
+
+impl SyncState {
+    fn new(since: i64, until: i64) -> Self {
+        Self {
+            since,
+            until,
+            skip_deleted: since == 0,
+            buffer: Vec::new(),
+            changeids: HashSet::new(),
+        }
+    }
+
+    // Changes of the documents that will be sent in the next pages are not
+    // needed, except changes of the client itself, which have to be confirmed
+    fn is_covered(&self, msg: &ClientActorMessage) -> bool {
+        let ClientActorMessage::Change(change) = msg else {
+            return false;
+        };
+        let is_own = self.changeids.contains(&change.changeid)
+            || change
+                .collapsed
+                .iter()
+                .any(|id| self.changeids.contains(id));
+        change.colrev <= self.until && !is_own
+    }
+
+    fn extend(&mut self, until: i64) {
+        self.until = until;
+        // Documents that were deleted during the sync are needed to remove
+        // them from the client
+        self.skip_deleted = false;
+        let buffer = std::mem::take(&mut self.buffer);
+        self.buffer = buffer
+            .into_iter()
+            .filter(|msg| !self.is_covered(msg))
+            .collect();
+    }
 }
 
 struct ClientActor {
     supervisor: Supervisor,
+    config: ClientConfig,
     client_id: i32,
     user_id: String,
     websocket: WebSocket,
-    receiver: mpsc::UnboundedReceiver<ClientActorMessage>,
+    mailbox: Arc<Mailbox>,
     collection: CollectionHandle,
     colrev: i64,
+    compression: Option<CompressionConfig>,
     timeout: Pin<Box<tokio::time::Sleep>>,
+    sync: Option<SyncState>,
+    sync_page: Option<SyncPageReply>,
+    limits: Limits,
+    user_limiter: Arc<UserRateLimiter>,
+}
+
+async fn recv_sync_page(
+    receiver: &mut Option<SyncPageReply>,
+) -> Result<Result<collection::SyncPage, SinkronError>, oneshot::error::RecvError>
+{
+    match receiver {
+        Some(receiver) => receiver.await,
+        None => std::future::pending().await,
+    }
 }
 
 impl ClientActor {
     async fn run(&mut self) {
         debug!("client-{}: start", self.client_id);
 
-        match self.sync(self.colrev).await {
-            Ok(()) => {
-                debug!("client-{}: sync completed", self.client_id);
-            }
-            Err(e) => {
-                debug!("client-{}: sync failed {:?}", self.client_id, e);
-                return;
-            }
-        };
+        self.start_sync(self.colrev).await;
 
         loop {
             select! {
                 // Always send to ws first, before processing incoming messaged.
                 // This should ensure disconnecting slow clients.
-                // XXX Another option is to use bounded channel and disconnect
-                // client when it reaches limit
                 biased;
                 () = &mut self.timeout => {
                     debug!("client-{}: disconnect by timeout", self.client_id);
                     break
                 },
-                // XXX could break if not Some ?
-                // e.g. if all handles dropped
-                Some(msg) = self.receiver.recv() => {
-                    match msg {
-                        ClientActorMessage::Sinkron(msg) => {
-                            self.send_to_ws(msg).await;
-                        },
-                        ClientActorMessage::Raw(msg) => {
-                            self.send_to_ws_raw(msg).await;
-                        }
-                    };
+                // Mailbox is drained during the sync as well, so disconnect
+                // is handled before the results of the sync
+                msg = self.mailbox.recv() => {
+                    if !self.handle_mailbox_message(msg).await {
+                        break
+                    }
+                },
+                res = recv_sync_page(&mut self.sync_page),
+                    if self.sync_page.is_some() => {
+                    self.sync_page = None;
+                    self.handle_sync_page(res).await;
                 },
                 msg = self.websocket.recv() => {
                     match msg {
@@ -86,13 +229,67 @@ impl ClientActor {
         }
     }
 
+    // Returns false when the client should be disconnected
+    async fn handle_mailbox_message(
+        &mut self,
+        msg: Option<ClientActorMessage>,
+    ) -> bool {
+        match msg {
+            Some(ClientActorMessage::Disconnect(msg)) => {
+                debug!("client-{}: disconnect", self.client_id);
+                self.send_to_ws(msg).await;
+                _ = self.websocket.send(Message::Close(None)).await;
+                false
+            }
+            Some(msg) => {
+                let Some(sync) = &mut self.sync else {
+                    self.deliver(msg).await;
+                    return true;
+                };
+                // Changes are delivered only after the sync is completed
+                if !sync.is_covered(&msg) {
+                    sync.buffer.push(msg);
+                }
+                if sync.buffer.len() > self.config.mailbox_size {
+                    self.disconnect_slow_client().await;
+                    return false;
+                }
+                true
+            }
+            None => {
+                self.disconnect_slow_client().await;
+                false
+            }
+        }
+    }
+
+    async fn deliver(&mut self, msg: ClientActorMessage) {
+        match msg {
+            ClientActorMessage::Sinkron(msg)
+            | ClientActorMessage::Disconnect(msg) => self.send_to_ws(msg).await,
+            ClientActorMessage::Raw(msg) => self.send_to_ws_raw(msg).await,
+            ClientActorMessage::Change(change) => {
+                self.send_to_ws_raw(change.into_message()).await
+            }
+        }
+    }
+
+    async fn disconnect_slow_client(&mut self) {
+        debug!("client-{}: disconnect slow client", self.client_id);
+        self.close_ws(CLOSE_CODE_SLOW_CLIENT, "Client is too slow")
+            .await;
+    }
+
     fn source(&self) -> collection::Source {
         collection::Source::Client {
             user: self.user_id.clone(),
         }
     }
 
-    async fn sync(&mut self, colrev: i64) -> Result<(), SinkronError> {
+    // Sync is performed in pages, so the client can process other messages
+    // (e.g. heartbeats) while it is in progress. Next page is requested only
+    // after the previous one was sent to the websocket.
+    async fn start_sync(&mut self, colrev: i64) {
         let (sender, receiver) = oneshot::channel();
         self.send_to_col(CollectionMessage::Sync(collection::SyncMessage {
             colrev,
@@ -101,44 +298,112 @@ impl ClientActor {
         }));
         match receiver.await {
             Ok(Ok(res)) => {
-                let mut messages: Vec<ServerMessage> = res
-                    .documents
-                    .into_iter()
-                    .map(|doc| {
-                        ServerMessage::Doc(DocMessage {
-                            id: doc.id,
-                            col: doc.col,
-                            colrev: doc.colrev,
-                            data: doc.data,
-                            created_at: doc.created_at,
-                            updated_at: doc.updated_at,
-                        })
-                    })
-                    .collect();
-                messages.push(ServerMessage::SyncComplete(
-                    SyncCompleteMessage {
-                        col: self.collection.id.clone(),
-                        colrev: res.colrev,
-                    },
-                ));
-                self.send_to_ws_many(messages).await;
+                debug!(
+                    "client-{}: sync started, colrev: {} -> {}",
+                    self.client_id, colrev, res.colrev
+                );
+                self.sync = Some(SyncState::new(colrev, res.colrev));
+                self.request_sync_page();
             }
             Ok(Err(err)) => {
-                let msg = ServerMessage::SyncError(SyncErrorMessage {
-                    col: self.collection.id.clone(),
-                    code: err.code,
-                });
-                self.send_to_ws(msg).await;
+                debug!("client-{}: sync failed {:?}", self.client_id, err);
+                self.send_sync_error(err.code).await;
             }
-            _ => {
-                let msg = ServerMessage::SyncError(SyncErrorMessage {
-                    col: self.collection.id.clone(),
-                    code: ErrorCode::InternalServerError,
-                });
-                self.send_to_ws(msg).await;
+            Err(_) => {
+                self.send_sync_error(ErrorCode::InternalServerError).await;
             }
         };
-        Ok(())
+    }
+
+    fn request_sync_page(&mut self) {
+        let Some(sync) = &self.sync else {
+            return;
+        };
+        let (sender, receiver) = oneshot::channel();
+        let msg = CollectionMessage::SyncPage(collection::SyncPageMessage {
+            source: self.source(),
+            since: sync.since,
+            until: sync.until,
+            skip_deleted: sync.skip_deleted,
+            limit: self.config.sync_page_size,
+            reply: sender,
+        });
+        self.sync_page = Some(receiver);
+        self.send_to_col(msg);
+    }
+
+    async fn handle_sync_page(
+        &mut self,
+        res: Result<
+            Result<collection::SyncPage, SinkronError>,
+            oneshot::error::RecvError,
+        >,
+    ) {
+        let page = match res {
+            Ok(Ok(page)) => page,
+            Ok(Err(err)) => {
+                debug!("client-{}: sync failed {:?}", self.client_id, err);
+                self.sync = None;
+                self.send_sync_error(err.code).await;
+                return;
+            }
+            Err(_) => {
+                self.sync = None;
+                self.send_sync_error(ErrorCode::InternalServerError).await;
+                return;
+            }
+        };
+
+        let mut messages: Vec<ServerMessage> = page
+            .documents
+            .into_iter()
+            .map(|doc| {
+                ServerMessage::Doc(DocMessage {
+                    id: doc.id,
+                    col: doc.col,
+                    colrev: doc.colrev,
+                    data: doc.data,
+                    created_at: doc.created_at,
+                    updated_at: doc.updated_at,
+                })
+            })
+            .collect();
+        let col = self.collection.id.clone();
+        if page.is_last {
+            messages.push(ServerMessage::SyncComplete(SyncCompleteMessage {
+                col,
+                colrev: page.colrev,
+            }));
+        } else {
+            messages.push(ServerMessage::SyncProgress(SyncProgressMessage {
+                col,
+                colrev: page.colrev,
+            }));
+        }
+        self.send_to_ws_many(messages).await;
+
+        if page.is_last {
+            debug!("client-{}: sync completed", self.client_id);
+            if let Some(sync) = self.sync.take() {
+                for msg in sync.buffer {
+                    self.deliver(msg).await;
+                }
+            }
+        } else if let Some(sync) = &mut self.sync {
+            sync.since = page.colrev;
+            if page.until > sync.until {
+                sync.extend(page.until);
+            }
+            self.request_sync_page();
+        }
+    }
+
+    async fn send_sync_error(&mut self, code: ErrorCode) {
+        let msg = ServerMessage::SyncError(SyncErrorMessage {
+            col: self.collection.id.clone(),
+            code,
+        });
+        self.send_to_ws(msg).await;
     }
 
     async fn handle_message(&mut self, msg: Message) {
@@ -155,19 +420,44 @@ impl ClientActor {
             ClientMessage::Heartbeat(msg) => self.handle_heartbeat(msg).await,
             ClientMessage::Get(msg) => self.handle_get(msg).await,
             ClientMessage::Change(msg) => self.handle_change(msg).await,
+            ClientMessage::Permissions(msg) => {
+                self.handle_permissions(msg).await
+            }
         };
     }
 
     async fn handle_heartbeat(&mut self, msg: HeartbeatMessage) {
         // reset disconnect timeout
-        self.timeout
-            .as_mut()
-            .reset(Instant::now() + DISCONNECT_TIMEOUT);
+        self.timeout.as_mut().reset(
+            Instant::now()
+                + Duration::from_millis(self.config.disconnect_timeout),
+        );
         let reply = HeartbeatMessage { i: msg.i + 1 };
         self.send_to_ws(ServerMessage::Heartbeat(reply)).await;
     }
 
+    fn check_rate_limit(&mut self, kind: RequestKind) -> bool {
+        let allowed = self.limits.check(kind)
+            && self.user_limiter.check(&self.user_id, kind);
+        if !allowed {
+            debug!(
+                "client-{}: rate limit exceeded: {:?}",
+                self.client_id, kind
+            );
+        }
+        allowed
+    }
+
     async fn handle_get(&mut self, msg: GetMessage) {
+        if !self.check_rate_limit(RequestKind::Get) {
+            let err = GetErrorMessage {
+                id: msg.id,
+                code: ErrorCode::TooManyRequests,
+            };
+            self.send_to_ws(ServerMessage::GetError(err)).await;
+            return;
+        }
+
         let (sender, receiver) = oneshot::channel();
         let get_msg = CollectionMessage::Get(collection::GetMessage {
             id: msg.id,
@@ -206,6 +496,20 @@ impl ClientActor {
     }
 
     async fn handle_change(&mut self, msg: ClientChangeMessage) {
+        if !self.check_rate_limit(RequestKind::Change) {
+            let err = ChangeErrorMessage {
+                id: msg.id,
+                changeid: msg.changeid,
+                code: ErrorCode::TooManyRequests,
+            };
+            self.send_to_ws(ServerMessage::ChangeError(err)).await;
+            return;
+        }
+
+        if let Some(sync) = &mut self.sync {
+            sync.changeids.insert(msg.changeid);
+        }
+
         let (sender, receiver) = oneshot::channel();
 
         let col_msg = match (msg.op, msg.data) {
@@ -227,13 +531,14 @@ impl ClientActor {
                 })
             }
             (Op::Create, Some(data)) => {
-                CollectionMessage::Create(collection::CreateMessage {
+                CollectionMessage::Create(Box::new(collection::CreateMessage {
                     id: msg.id,
                     data,
+                    permissions: msg.permissions.map(|p| *p),
                     source: self.source(),
                     changeid: msg.changeid,
                     reply: sender,
-                })
+                }))
             }
             _ => {
                 let err = ChangeErrorMessage {
@@ -270,6 +575,62 @@ impl ClientActor {
         }
     }
 
+    async fn handle_permissions(&mut self, msg: ClientPermissionsMessage) {
+        let ClientPermissionsMessage {
+            col,
+            id,
+            permissions,
+            changeid,
+        } = msg;
+
+        if !self.check_rate_limit(RequestKind::Change) {
+            let err = PermissionsErrorMessage {
+                code: ErrorCode::TooManyRequests,
+                col,
+                id,
+                changeid,
+            };
+            self.send_to_ws(ServerMessage::PermissionsError(err)).await;
+            return;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let col_msg = match id {
+            Some(id) => CollectionMessage::SetDocumentPermissions(Box::new(
+                collection::SetDocumentPermissionsMessage {
+                    id,
+                    permissions: *permissions,
+                    source: self.source(),
+                    changeid,
+                    reply: sender,
+                },
+            )),
+            None => CollectionMessage::SetPermissions(Box::new(
+                collection::SetPermissionsMessage {
+                    permissions: *permissions,
+                    source: self.source(),
+                    changeid,
+                    reply: sender,
+                },
+            )),
+        };
+        self.send_to_col(col_msg);
+
+        // On success, new permissions are broadcasted to all subscribers
+        let code = match receiver.await {
+            Ok(Ok(())) => return,
+            Ok(Err(err)) => err.code,
+            Err(_) => ErrorCode::InternalServerError,
+        };
+        let err = PermissionsErrorMessage {
+            code,
+            col,
+            id,
+            changeid,
+        };
+        self.send_to_ws(ServerMessage::PermissionsError(err)).await;
+    }
+
     fn send_to_col(&self, msg: CollectionMessage) {
         let res = self.collection.send(msg);
         if res.is_err() {
@@ -289,9 +650,68 @@ impl ClientActor {
     }
 
     async fn send_to_ws_many(&mut self, messages: Vec<ServerMessage>) {
+        let Some(compression) = self.compression else {
+            for msg in messages {
+                self.send_to_ws(msg).await
+            }
+            return;
+        };
+
+        let mut batch: Vec<String> = Vec::new();
+        let mut batch_size = 0;
         for msg in messages {
-            self.send_to_ws(msg).await
+            let Ok(encoded) = serde_json::to_string(&msg) else {
+                continue;
+            };
+            batch_size += encoded.len();
+            batch.push(encoded);
+            if batch_size >= compression.batch_size {
+                self.send_batch_to_ws(std::mem::take(&mut batch), batch_size)
+                    .await;
+                batch_size = 0;
+            }
+        }
+        if !batch.is_empty() {
+            self.send_batch_to_ws(batch, batch_size).await;
+        }
+    }
+
+    async fn send_batch_to_ws(&mut self, batch: Vec<String>, size: usize) {
+        let threshold = self.compression.map_or(usize::MAX, |c| c.threshold);
+        let compressed = match compression::encode_batch(batch, size, threshold)
+        {
+            Ok(EncodedBatch::Plain(batch)) => {
+                for msg in batch {
+                    self.send_to_ws_raw(msg).await;
+                }
+                return;
+            }
+            Ok(EncodedBatch::Compressed(compressed)) => compressed,
+            Err(_) => {
+                self.supervisor.stop();
+                return;
+            }
+        };
+        trace!(
+            "client-{}: compressed batch {} -> {} bytes",
+            self.client_id,
+            size,
+            compressed.len()
+        );
+        let res = self.websocket.send(Message::Binary(compressed)).await;
+        if res.is_err() {
+            self.supervisor.stop();
+            return;
         }
+        trace!("client-{}: sent batch to websocket", self.client_id);
+    }
+
+    async fn close_ws(&mut self, code: u16, reason: &'static str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        _ = self.websocket.send(Message::Close(Some(frame))).await;
     }
 
     async fn send_to_ws_raw(&mut self, msg: String) {
@@ -306,39 +726,68 @@ impl ClientActor {
 
 #[derive(Clone)]
 pub struct ClientHandle {
-    sender: mpsc::UnboundedSender<ClientActorMessage>,
+    pub user_id: String,
+    mailbox: Arc<Mailbox>,
     #[allow(dead_code)]
     pub supervisor: Supervisor,
 }
 
 impl ClientHandle {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         client_id: i32,
         user_id: String,
         websocket: WebSocket,
         collection: CollectionHandle,
         colrev: i64,
+        compression: Option<CompressionConfig>,
+        config: ClientConfig,
+        user_limiter: Arc<UserRateLimiter>,
         on_exit: Option<ExitCallback>,
     ) -> Self {
-        let (sender, receiver) = mpsc::unbounded_channel();
+        let mailbox = Arc::new(Mailbox::new(
+            config.mailbox_size,
+            config.slow_client_policy,
+        ));
         let supervisor = Supervisor::new();
+        let limits = Limits::new(&config.rate_limit.connection);
+        let timeout = sleep(Duration::from_millis(config.disconnect_timeout));
         let mut reader = ClientActor {
             supervisor: supervisor.clone(),
+            config,
             colrev,
+            compression,
             client_id,
-            user_id,
+            user_id: user_id.clone(),
             collection,
             websocket,
-            receiver,
-            timeout: Box::pin(sleep(DISCONNECT_TIMEOUT)),
+            mailbox: mailbox.clone(),
+            timeout: Box::pin(timeout),
+            sync: None,
+            sync_page: None,
+            limits,
+            user_limiter,
         };
         let name = format!("client:{}", client_id);
         supervisor.spawn(name, async move { reader.run().await }, on_exit);
-        Self { supervisor, sender }
+        Self {
+            user_id,
+            supervisor,
+            mailbox,
+        }
+    }
+
+    // Handle without the running actor, messages are kept in the mailbox
+    #[cfg(test)]
+    pub fn detached(user_id: String, mailbox: Arc<Mailbox>) -> Self {
+        Self {
+            user_id,
+            supervisor: Supervisor::new(),
+            mailbox,
+        }
     }
 
-    pub fn send(&self, msg: ClientActorMessage) {
-        // XXX should return error
-        _ = self.sender.send(msg);
+    pub fn send(&self, msg: ClientActorMessage) -> Result<(), MailboxFull> {
+        self.mailbox.send(msg)
     }
 }
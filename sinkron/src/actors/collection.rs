@@ -2,30 +2,50 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use base64::prelude::*;
-use diesel::prelude::*;
-use diesel_async::RunQueryDsl;
 use log::{debug, trace};
+use serde::Deserialize;
 use tokio::sync::{mpsc, oneshot};
 use uuid::Uuid;
 
-use crate::actors::client::{ClientActorMessage, ClientHandle};
+use crate::actors::client::{ClientActorMessage, ClientHandle, QueuedChange};
 use crate::actors::supervisor::{ExitCallback, Supervisor};
-use crate::db;
-use crate::error::{internal_error, SinkronError};
+use crate::error::SinkronError;
 use crate::groups::GroupsApi;
 use crate::models;
 use crate::permissions::{Action, Permissions};
 use crate::protocol::*;
-use crate::schema;
-use crate::types::{Collection, Document};
+use crate::storage::Storage;
+use crate::types::{Collection, Document, User};
+
+// Size limits of the documents, no limit when not set
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionConfig {
+    // Max size of the decoded update (in bytes)
+    pub max_update_size: Option<usize>,
+    // Max size of the document snapshot (in bytes)
+    pub max_document_size: Option<usize>,
+    // Max total size of the snapshots of all documents in the collection,
+    // collection can also have its own quota
+    pub max_collection_size: Option<i64>,
+}
 
 // Collection actor performs document operations over single collection,
 // then replies back with results and also broadcasts messages to all
 // active subscribers of the collection.
 
 pub struct SyncResult {
+    pub colrev: i64,
+}
+
+pub struct SyncPage {
     pub documents: Vec<Document>,
+    // Colrev of the last document in the page
     pub colrev: i64,
+    // Colrev up to which the sync should continue, it is moved forward when
+    // documents were changed while the sync was in progress
+    pub until: i64,
+    pub is_last: bool,
 }
 
 pub enum Source {
@@ -39,6 +59,19 @@ pub struct SyncMessage {
     pub source: Source,
 }
 
+// Requests next page of the documents for the sync.
+// Documents are returned ordered by colrev, starting after the `since` colrev
+// and up to the `until` colrev.
+pub struct SyncPageMessage {
+    pub source: Source,
+    pub since: i64,
+    pub until: i64,
+    // When syncing from the start, deleted documents can be skipped
+    pub skip_deleted: bool,
+    pub limit: i64,
+    pub reply: oneshot::Sender<Result<SyncPage, SinkronError>>,
+}
+
 pub struct GetMessage {
     pub id: Uuid,
     pub source: Source,
@@ -48,6 +81,9 @@ pub struct GetMessage {
 pub struct CreateMessage {
     pub id: Uuid,
     pub data: String,
+    // Permissions of the document, by default permissions of the collection
+    // are used
+    pub permissions: Option<Permissions>,
     pub source: Source,
     pub changeid: Uuid,
     pub reply: oneshot::Sender<Result<Document, SinkronError>>,
@@ -61,6 +97,26 @@ pub struct UpdateMessage {
     pub reply: oneshot::Sender<Result<Document, SinkronError>>,
 }
 
+pub struct SetPermissionsMessage {
+    pub permissions: Permissions,
+    pub source: Source,
+    pub changeid: Uuid,
+    pub reply: oneshot::Sender<Result<(), SinkronError>>,
+}
+
+pub struct SetDocumentPermissionsMessage {
+    pub id: Uuid,
+    pub permissions: Permissions,
+    pub source: Source,
+    pub changeid: Uuid,
+    pub reply: oneshot::Sender<Result<(), SinkronError>>,
+}
+
+pub struct SetQuotaMessage {
+    pub quota: Option<i64>,
+    pub reply: oneshot::Sender<Result<(), SinkronError>>,
+}
+
 pub struct DeleteMessage {
     pub id: Uuid,
     pub source: Source,
@@ -77,15 +133,23 @@ pub enum CollectionMessage {
         client_id: i32,
     },
     Sync(SyncMessage),
+    SyncPage(SyncPageMessage),
     Get(GetMessage),
-    Create(CreateMessage),
+    Create(Box<CreateMessage>),
     Update(UpdateMessage),
     Delete(DeleteMessage),
+    SetQuota(SetQuotaMessage),
+    SetPermissions(Box<SetPermissionsMessage>),
+    SetDocumentPermissions(Box<SetDocumentPermissionsMessage>),
+    RecheckSubscribers,
 }
 
 struct CollectionState {
     colrev: i64,
     permissions: Permissions,
+    documents_count: i64,
+    size: i64,
+    quota: Option<i64>,
 }
 
 impl CollectionState {
@@ -93,15 +157,45 @@ impl CollectionState {
         Self {
             colrev: col.colrev,
             permissions: Permissions::parse_or_empty(&col.permissions),
+            documents_count: col.documents_count,
+            size: col.size,
+            quota: col.quota,
+        }
+    }
+}
+
+// Permissions and owner of the document, used to check who can read it
+#[derive(Clone, Copy)]
+struct DocumentAccess<'a> {
+    permissions: &'a str,
+    owner: Option<&'a str>,
+}
+
+impl<'a> DocumentAccess<'a> {
+    fn of(doc: &'a models::Document) -> Self {
+        Self {
+            permissions: &doc.permissions,
+            owner: doc.created_by.as_deref(),
         }
     }
+
+    // Documents with permissions that couldn't be parsed are not readable
+    // by anyone
+    fn can_read(&self, user: &User) -> bool {
+        serde_json::from_str::<Permissions>(self.permissions).is_ok_and(
+            |permissions| {
+                permissions.check_with_owner(user, Action::Read, self.owner)
+            },
+        )
+    }
 }
 
 struct CollectionActor {
     supervisor: Supervisor,
     id: String,
+    config: CollectionConfig,
     state: CollectionState,
-    pool: db::DbConnectionPool,
+    storage: Arc<dyn Storage>,
     groups_api: Arc<GroupsApi>,
     receiver: mpsc::UnboundedReceiver<CollectionMessage>,
     subscribers: std::collections::HashMap<i32, ClientHandle>,
@@ -112,16 +206,18 @@ impl CollectionActor {
         id: String,
         state: CollectionState,
         receiver: mpsc::UnboundedReceiver<CollectionMessage>,
-        pool: db::DbConnectionPool,
+        storage: Arc<dyn Storage>,
         groups_api: Arc<GroupsApi>,
+        config: CollectionConfig,
         supervisor: Supervisor,
     ) -> Self {
         Self {
             supervisor,
             id,
+            config,
             state,
             receiver,
-            pool,
+            storage,
             groups_api,
             subscribers: HashMap::new(),
         }
@@ -163,12 +259,9 @@ impl CollectionActor {
             Source::Api => Ok(()),
             Source::Client { user } => {
                 let user = self.groups_api.get_user(user).await?;
-                let permissions: Permissions =
-                    serde_json::from_str(&doc.permissions).map_err(|_| {
-                        SinkronError::internal(
-                        "Couldn't parse permissions, data might be corrupted")
-                    })?;
-                if permissions.check(&user, action) {
+                let permissions = Self::doc_permissions(doc)?;
+                let owner = doc.created_by.as_deref();
+                if permissions.check_with_owner(&user, action, owner) {
                     Ok(())
                 } else {
                     Err(SinkronError::forbidden("Operation is forbidden"))
@@ -177,6 +270,16 @@ impl CollectionActor {
         }
     }
 
+    fn doc_permissions(
+        doc: &models::Document,
+    ) -> Result<Permissions, SinkronError> {
+        serde_json::from_str(&doc.permissions).map_err(|_| {
+            SinkronError::internal(
+                "Couldn't parse permissions, data might be corrupted",
+            )
+        })
+    }
+
     async fn handle_message(&mut self, msg: CollectionMessage) {
         match msg {
             CollectionMessage::Subscribe { client_id, handle } => {
@@ -200,6 +303,26 @@ impl CollectionActor {
                 let res = self.handle_sync(colrev, source).await;
                 _ = reply.send(res);
             }
+            CollectionMessage::SyncPage(msg) => {
+                let SyncPageMessage {
+                    source,
+                    since,
+                    until,
+                    skip_deleted,
+                    limit,
+                    reply,
+                } = msg;
+                trace!(
+                    "col-{}: sync page, since: {}, until: {}",
+                    self.id,
+                    since,
+                    until
+                );
+                let res = self
+                    .handle_sync_page(source, since, until, skip_deleted, limit)
+                    .await;
+                _ = reply.send(res);
+            }
             CollectionMessage::Get(msg) => {
                 let GetMessage { id, source, reply } = msg;
                 trace!("col-{}: get document, id: {}", self.id, id);
@@ -210,12 +333,15 @@ impl CollectionActor {
                 let CreateMessage {
                     id,
                     data,
+                    permissions,
                     source,
                     changeid,
                     reply,
-                } = msg;
+                } = *msg;
                 trace!("col-{}: create, id: {}", self.id, id);
-                let res = self.handle_create(id, data, source, changeid).await;
+                let res = self
+                    .handle_create(id, data, permissions, source, changeid)
+                    .await;
                 _ = reply.send(res);
             }
             CollectionMessage::Update(msg) => {
@@ -243,6 +369,48 @@ impl CollectionActor {
                 let res = self.handle_update(id, None, source, changeid).await;
                 _ = reply.send(res);
             }
+            CollectionMessage::SetQuota(msg) => {
+                let SetQuotaMessage { quota, reply } = msg;
+                trace!("col-{}: set quota: {:?}", self.id, quota);
+                let res = self.handle_set_quota(quota).await;
+                _ = reply.send(res);
+            }
+            CollectionMessage::SetPermissions(msg) => {
+                let SetPermissionsMessage {
+                    permissions,
+                    source,
+                    changeid,
+                    reply,
+                } = *msg;
+                trace!("col-{}: set permissions", self.id);
+                let res = self
+                    .handle_set_permissions(permissions, source, changeid)
+                    .await;
+                _ = reply.send(res);
+            }
+            CollectionMessage::SetDocumentPermissions(msg) => {
+                let SetDocumentPermissionsMessage {
+                    id,
+                    permissions,
+                    source,
+                    changeid,
+                    reply,
+                } = *msg;
+                trace!("col-{}: set document permissions, id: {}", self.id, id);
+                let res = self
+                    .handle_set_document_permissions(
+                        id,
+                        permissions,
+                        source,
+                        changeid,
+                    )
+                    .await;
+                _ = reply.send(res);
+            }
+            CollectionMessage::RecheckSubscribers => {
+                trace!("col-{}: recheck subscribers", self.id);
+                self.recheck_subscribers().await;
+            }
         }
     }
 
@@ -258,33 +426,276 @@ impl CollectionActor {
         }
     }
 
-    async fn connect(&self) -> Result<db::DbConnection, SinkronError> {
-        self.pool.get().await.map_err(internal_error)
-    }
-
+    // Increments colrev and updates usage of the collection
     async fn increment_colrev(
         &mut self,
-        conn: &mut db::DbConnection,
+        documents_delta: i64,
+        size_delta: i64,
     ) -> Result<i64, SinkronError> {
-        use schema::collections;
-        let colrev: i64 = diesel::update(collections::table)
-            .filter(collections::id.eq(&self.id))
-            .set(collections::colrev.eq(collections::colrev + 1))
-            .returning(collections::colrev)
-            .get_result(conn)
-            .await
-            .map_err(internal_error)?;
-        self.state.colrev = colrev;
-        Ok(colrev)
-    }
-
-    fn broadcast(&self, msg: ServerMessage) {
-        if let Ok(serialized) = serde_json::to_string(&msg) {
-            for client in self.subscribers.values() {
-                // XXX should handle if it couldn't write to client
-                // should unsubscribe and stop it
-                client.send(ClientActorMessage::Raw(serialized.clone()));
+        let usage = self
+            .storage
+            .increment_colrev(&self.id, documents_delta, size_delta)
+            .await?;
+        self.state.colrev = usage.colrev;
+        self.state.documents_count = usage.documents_count;
+        self.state.size = usage.size;
+        Ok(usage.colrev)
+    }
+
+    // Checks that change of the document size doesn't exceed size limits
+    // and the quota of the collection
+    fn check_size(
+        &self,
+        prev_size: usize,
+        next_size: usize,
+    ) -> Result<(), SinkronError> {
+        if let Some(max) = self.config.max_document_size {
+            if next_size > max {
+                return Err(SinkronError::payload_too_large(
+                    "Document is too large",
+                ));
+            }
+        }
+        let limit = match (self.config.max_collection_size, self.state.quota) {
+            (Some(max), Some(quota)) => Some(max.min(quota)),
+            (max, quota) => max.or(quota),
+        };
+        if let Some(limit) = limit {
+            let next_col_size =
+                self.state.size - (prev_size as i64) + (next_size as i64);
+            if next_size > prev_size && next_col_size > limit {
+                return Err(SinkronError::quota_exceeded(
+                    "Collection size quota exceeded",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_set_quota(
+        &mut self,
+        quota: Option<i64>,
+    ) -> Result<(), SinkronError> {
+        self.storage
+            .update_collection_quota(&self.id, quota)
+            .await?;
+        self.state.quota = quota;
+        Ok(())
+    }
+
+    async fn handle_set_permissions(
+        &mut self,
+        permissions: Permissions,
+        source: Source,
+        changeid: Uuid,
+    ) -> Result<(), SinkronError> {
+        self.check_grant(&source, &self.state.permissions, &permissions, None)
+            .await?;
+        self.check_col_permission(source, Action::Share).await?;
+
+        self.storage
+            .update_collection_permissions(&self.id, &permissions.to_string())
+            .await?;
+        self.state.permissions = permissions;
+        // Clients that lost access are disconnected before the broadcast,
+        // so they don't receive new permissions
+        self.recheck_subscribers().await;
+
+        let msg = ServerPermissionsMessage {
+            col: self.id.clone(),
+            id: None,
+            permissions: Box::new(self.state.permissions.clone()),
+            changeid,
+        };
+        self.broadcast(ServerMessage::Permissions(msg), None).await;
+        Ok(())
+    }
+
+    async fn handle_set_document_permissions(
+        &mut self,
+        id: Uuid,
+        permissions: Permissions,
+        source: Source,
+        changeid: Uuid,
+    ) -> Result<(), SinkronError> {
+        let doc = self.storage.get_document(&self.id, id).await?;
+
+        let current = Self::doc_permissions(&doc)?;
+        let owner = doc.created_by.as_deref();
+        self.check_grant(&source, &current, &permissions, owner)
+            .await?;
+        self.check_doc_permission(&doc, source, Action::Share)
+            .await?;
+
+        // Colrev is incremented, so clients that sync later receive the
+        // document if they gained access, or its deletion if they lost it
+        let next_colrev = self.increment_colrev(0, 0).await?;
+        let permissions_str = permissions.to_string();
+        self.storage
+            .update_document_permissions(
+                &self.id,
+                id,
+                &permissions_str,
+                next_colrev,
+            )
+            .await?;
+
+        let prev_access = DocumentAccess::of(&doc);
+        let next_access = DocumentAccess {
+            permissions: &permissions_str,
+            owner: doc.created_by.as_deref(),
+        };
+        let mut gained = Vec::new();
+        let mut lost = Vec::new();
+        let mut kept = Vec::new();
+        for (client_id, client) in self.subscribers.iter() {
+            let (could_read, can_read) =
+                match self.groups_api.get_user(client.user_id.clone()).await {
+                    Ok(user) => (
+                        prev_access.can_read(&user),
+                        next_access.can_read(&user),
+                    ),
+                    Err(_) => (false, false),
+                };
+            match (could_read, can_read) {
+                (false, true) => gained.push(*client_id),
+                (true, false) => lost.push(*client_id),
+                (true, true) => kept.push(*client_id),
+                (false, false) => {}
+            }
+        }
+
+        // Subscribers that gained access receive the document as created,
+        // and subscribers that lost access receive it as deleted
+        let change = |op: Op, data: Option<String>| {
+            ServerMessage::Change(ServerChangeMessage {
+                id,
+                col: self.id.clone(),
+                colrev: next_colrev,
+                op,
+                data,
+                created_at: doc.created_at,
+                updated_at: doc.updated_at,
+                changeid,
+                changeids: Vec::new(),
+            })
+        };
+        if doc.data.is_some() {
+            let data = doc.data.as_ref().map(|d| BASE64_STANDARD.encode(d));
+            let created = change(Op::Create, data);
+            let deleted = change(Op::Delete, None);
+            self.send_to(&gained, &created);
+            self.send_to(&lost, &deleted);
+        }
+
+        // Permissions are sent to everyone who could read the document
+        // before or after the change, including the client that changed them
+        let msg = ServerMessage::Permissions(ServerPermissionsMessage {
+            col: self.id.clone(),
+            id: Some(id),
+            permissions: Box::new(permissions),
+            changeid,
+        });
+        let readers: Vec<i32> =
+            kept.into_iter().chain(gained).chain(lost).collect();
+        self.send_to(&readers, &msg);
+        Ok(())
+    }
+
+    // Disconnects subscribers that are not allowed to read the collection
+    async fn recheck_subscribers(&mut self) {
+        let mut forbidden = Vec::new();
+        for (client_id, client) in self.subscribers.iter() {
+            let allowed =
+                match self.groups_api.get_user(client.user_id.clone()).await {
+                    Ok(user) => {
+                        self.state.permissions.check(&user, Action::Read)
+                    }
+                    Err(_) => false,
+                };
+            if !allowed {
+                forbidden.push(*client_id);
+            }
+        }
+        for client_id in forbidden {
+            debug!("col-{}: client lost access, id: {}", self.id, client_id);
+            if let Some(client) = self.subscribers.get(&client_id) {
+                let msg = ServerMessage::SyncError(SyncErrorMessage {
+                    col: self.id.clone(),
+                    code: ErrorCode::Forbidden,
+                });
+                _ = client.send(ClientActorMessage::Disconnect(msg));
+            }
+            self.handle_unsubscribe(client_id);
+        }
+    }
+
+    // Sends message to all subscribers, messages about the document are sent
+    // only to subscribers that are allowed to read it
+    async fn broadcast(
+        &mut self,
+        msg: ServerMessage,
+        doc: Option<DocumentAccess<'_>>,
+    ) {
+        let mut client_ids = Vec::new();
+        for (client_id, client) in self.subscribers.iter() {
+            if let Some(doc) = doc {
+                let allowed = match self
+                    .groups_api
+                    .get_user(client.user_id.clone())
+                    .await
+                {
+                    Ok(user) => doc.can_read(&user),
+                    Err(_) => false,
+                };
+                if !allowed {
+                    continue;
+                }
             }
+            client_ids.push(*client_id);
+        }
+        self.send_to(&client_ids, &msg);
+    }
+
+    fn send_to(&mut self, client_ids: &[i32], msg: &ServerMessage) {
+        if client_ids.is_empty() {
+            return;
+        }
+        let Ok(serialized) = serde_json::to_string(msg) else {
+            return;
+        };
+        let change = match msg {
+            ServerMessage::Change(change) => Some(change),
+            _ => None,
+        };
+        let mut failed = Vec::new();
+        for client_id in client_ids {
+            let Some(client) = self.subscribers.get(client_id) else {
+                continue;
+            };
+            let msg = match change {
+                Some(change) => ClientActorMessage::Change(QueuedChange {
+                    id: change.id,
+                    colrev: change.colrev,
+                    changeid: change.changeid,
+                    is_update: matches!(change.op, Op::Update),
+                    msg: serialized.clone(),
+                    collapsed: Vec::new(),
+                }),
+                None => ClientActorMessage::Raw(serialized.clone()),
+            };
+            if client.send(msg).is_err() {
+                failed.push(*client_id);
+            }
+        }
+        // Clients that couldn't receive message will disconnect themselves,
+        // so they don't need to receive any more messages
+        for client_id in failed {
+            debug!(
+                "col-{}: couldn't send to client, id: {}",
+                self.id, client_id
+            );
+            self.handle_unsubscribe(client_id);
         }
     }
 
@@ -297,6 +708,7 @@ impl CollectionActor {
             col: doc.col_id,
             colrev: doc.colrev,
             permissions: doc.permissions,
+            created_by: doc.created_by,
         }
     }
 
@@ -310,35 +722,85 @@ impl CollectionActor {
         if colrev > self.state.colrev {
             return Err(SinkronError::unprocessable("Invalid colrev"));
         }
-
-        if colrev == self.state.colrev {
-            return Ok(SyncResult {
-                documents: Vec::new(),
-                colrev: self.state.colrev,
-            });
+        // Gc could remove documents while the collection is loaded, so the
+        // purge horizon is read from the storage
+        if colrev > 0 {
+            let col = self.storage.get_collection(&self.id).await?;
+            if colrev < col.purged_colrev {
+                return Err(SinkronError::resync_required(
+                    "Deleted documents were purged since the colrev",
+                ));
+            }
         }
 
-        let mut conn = self.connect().await?;
-        let req_base = schema::documents::table
-            .filter(schema::documents::col_id.eq(&self.id))
-            .order(schema::documents::created_at.asc())
-            .into_boxed();
-        let req = if colrev == 0 {
-            // select all doc, except deleted
-            req_base.filter(schema::documents::is_deleted.eq(false))
+        Ok(SyncResult {
+            colrev: self.state.colrev,
+        })
+    }
+
+    async fn handle_sync_page(
+        &self,
+        source: Source,
+        since: i64,
+        until: i64,
+        skip_deleted: bool,
+        limit: i64,
+    ) -> Result<SyncPage, SinkronError> {
+        // Permissions could change while the sync is in progress
+        let user = match &source {
+            Source::Client { user } => {
+                Some(self.groups_api.get_user(user.clone()).await?)
+            }
+            Source::Api => None,
+        };
+        self.check_col_permission(source, Action::Read).await?;
+
+        let documents = if since < until {
+            self.storage
+                .get_documents_page(&self.id, since, until, skip_deleted, limit)
+                .await?
         } else {
-            // select docs since colrev, including deleted
-            req_base.filter(schema::documents::colrev.gt(colrev))
+            Vec::new()
         };
-        let documents: Vec<models::Document> =
-            req.get_results(&mut conn).await.map_err(internal_error)?;
 
-        Ok(SyncResult {
+        let is_exhausted = (documents.len() as i64) < limit;
+        let colrev = if is_exhausted {
+            until
+        } else {
+            documents.last().map_or(until, |doc| doc.colrev)
+        };
+        // Documents that were changed during the sync got colrev greater
+        // than `until`, so they wouldn't be included in any page and the
+        // client would only receive their buffered changes. Instead, the
+        // sync continues until it catches up with the collection.
+        let (until, is_last) = if is_exhausted && until < self.state.colrev {
+            (self.state.colrev, false)
+        } else {
+            (until, is_exhausted)
+        };
+        // Documents that the user is not allowed to read are skipped, the
+        // page range is still based on all documents. When the sync doesn't
+        // start from the beginning, the user could have lost access to them
+        // since the last sync, so they are sent as deleted.
+        Ok(SyncPage {
             documents: documents
                 .into_iter()
-                .map(Self::doc_from_model)
+                .filter_map(|mut doc| {
+                    let can_read = user.as_ref().is_none_or(|user| {
+                        DocumentAccess::of(&doc).can_read(user)
+                    });
+                    if !can_read {
+                        if skip_deleted {
+                            return None;
+                        }
+                        doc.data = None;
+                    }
+                    Some(Self::doc_from_model(doc))
+                })
                 .collect(),
-            colrev: self.state.colrev,
+            colrev,
+            until,
+            is_last,
         })
     }
 
@@ -347,19 +809,7 @@ impl CollectionActor {
         id: Uuid,
         source: Source,
     ) -> Result<Document, SinkronError> {
-        let mut conn = self.connect().await?;
-        let doc: models::Document = schema::documents::table
-            .find(id)
-            .filter(schema::documents::col_id.eq(&self.id))
-            .first(&mut conn)
-            .await
-            .map_err(|err| match err {
-                diesel::NotFound => {
-                    SinkronError::not_found("Document not found")
-                }
-                err => SinkronError::internal(&err.to_string()),
-            })?;
-        drop(conn);
+        let doc = self.storage.get_document(&self.id, id).await?;
 
         self.check_doc_permission(&doc, source, Action::Read)
             .await?;
@@ -367,24 +817,55 @@ impl CollectionActor {
         Ok(Self::doc_from_model(doc))
     }
 
+    // Checks that the client can replace `current` permissions of the
+    // collection or document with the `granted` ones
+    async fn check_grant(
+        &self,
+        source: &Source,
+        current: &Permissions,
+        granted: &Permissions,
+        owner: Option<&str>,
+    ) -> Result<(), SinkronError> {
+        match source {
+            Source::Api => Ok(()),
+            Source::Client { user } => {
+                let user = self.groups_api.get_user(user.clone()).await?;
+                if current.can_grant_with_owner(&user, granted, owner) {
+                    Ok(())
+                } else {
+                    Err(SinkronError::forbidden(
+                        "Not allowed to grant these permissions",
+                    ))
+                }
+            }
+        }
+    }
+
     async fn handle_create(
         &mut self,
         id: Uuid,
         data: String,
+        permissions: Option<Permissions>,
         source: Source,
         changeid: Uuid,
     ) -> Result<Document, SinkronError> {
+        let created_by = match &source {
+            Source::Client { user } => Some(user.clone()),
+            Source::Api => None,
+        };
+        if let Some(permissions) = &permissions {
+            let owner = created_by.as_deref();
+            self.check_grant(
+                &source,
+                &self.state.permissions,
+                permissions,
+                owner,
+            )
+            .await?;
+        }
         self.check_col_permission(source, Action::Create).await?;
 
-        let mut conn = self.connect().await?;
-
-        let cnt: i64 = schema::documents::table
-            .filter(schema::documents::id.eq(&id))
-            .count()
-            .get_result(&mut conn)
-            .await
-            .map_err(internal_error)?;
-        if cnt != 0 {
+        if self.storage.document_exists(id).await? {
             return Err(SinkronError::unprocessable("Duplicate document id"));
         }
 
@@ -393,29 +874,26 @@ impl CollectionActor {
         let decoded = BASE64_STANDARD.decode(&data).map_err(|_| {
             SinkronError::bad_request("Couldn't decode data from base64")
         })?;
+        self.check_size(0, decoded.len())?;
 
         // increment colrev
-        let next_colrev = self.increment_colrev(&mut conn).await?;
+        let next_colrev =
+            self.increment_colrev(1, decoded.len() as i64).await?;
 
         // create document
-        // TODO create document with different permissions that col
-        let permissions = self.state.permissions.to_string();
+        let permissions = permissions
+            .as_ref()
+            .unwrap_or(&self.state.permissions)
+            .to_string();
         let new_doc = models::NewDocument {
             id,
             col_id: self.id.clone(),
             colrev: next_colrev,
             data: decoded,
             permissions: &permissions,
+            created_by: created_by.as_deref(),
         };
-        let created_at: chrono::DateTime<chrono::Utc> =
-            diesel::insert_into(schema::documents::table)
-                .values(&new_doc)
-                .returning(schema::documents::created_at)
-                .get_result(&mut conn)
-                .await
-                .map_err(internal_error)?;
-
-        drop(conn);
+        let created_at = self.storage.create_document(new_doc).await?;
 
         let msg = ServerChangeMessage {
             id,
@@ -426,8 +904,14 @@ impl CollectionActor {
             created_at,
             updated_at: created_at,
             changeid,
+            changeids: Vec::new(),
         };
-        self.broadcast(ServerMessage::Change(msg));
+        let access = DocumentAccess {
+            permissions: &permissions,
+            owner: created_by.as_deref(),
+        };
+        self.broadcast(ServerMessage::Change(msg), Some(access))
+            .await;
 
         // return document
         let doc = Document {
@@ -438,6 +922,7 @@ impl CollectionActor {
             col: self.id.clone(),
             colrev: next_colrev,
             permissions,
+            created_by,
         };
 
         Ok(doc)
@@ -453,6 +938,13 @@ impl CollectionActor {
                 "Couldn't decode update from base64",
             ));
         };
+        if let Some(max) = self.config.max_update_size {
+            if decoded_update.len() > max {
+                return Err(SinkronError::payload_too_large(
+                    "Update is too large",
+                ));
+            }
+        }
         let task = tokio::task::spawn_blocking(move || {
             let loro_doc = loro::LoroDoc::new();
             if loro_doc.import(&snapshot).is_err() {
@@ -490,19 +982,7 @@ impl CollectionActor {
         source: Source,
         changeid: Uuid,
     ) -> Result<Document, SinkronError> {
-        let mut conn = self.connect().await?;
-        let doc: models::Document = schema::documents::table
-            .find(id)
-            .filter(schema::documents::col_id.eq(&self.id))
-            .first(&mut conn)
-            .await
-            .map_err(|err| match err {
-                diesel::NotFound => {
-                    SinkronError::not_found("Document not found")
-                }
-                err => SinkronError::internal(&err.to_string()),
-            })?;
-        drop(conn);
+        let doc = self.storage.get_document(&self.id, id).await?;
 
         let is_delete = data.is_none();
         let action = if is_delete {
@@ -512,6 +992,7 @@ impl CollectionActor {
         };
         self.check_doc_permission(&doc, source, action).await?;
 
+        let prev_size = doc.data.as_ref().map_or(0, |data| data.len());
         let new_data = match &data {
             Some(update) => {
                 let Some(data) = doc.data else {
@@ -519,7 +1000,7 @@ impl CollectionActor {
                         "Couldn't update deleted document",
                     ));
                 };
-                Some(self.update_loro_doc(data, &update).await?)
+                Some(self.update_loro_doc(data, update).await?)
             }
             None => {
                 if doc.data.is_none() {
@@ -531,10 +1012,15 @@ impl CollectionActor {
             }
         };
 
-        let mut conn = self.connect().await?;
+        let next_size = new_data.as_ref().map_or(0, |data| data.len());
+
+        self.check_size(prev_size, next_size)?;
 
         // Increment colrev
-        let next_colrev = self.increment_colrev(&mut conn).await?;
+        let documents_delta = if is_delete { -1 } else { 0 };
+        let size_delta = next_size as i64 - prev_size as i64;
+        let next_colrev =
+            self.increment_colrev(documents_delta, size_delta).await?;
 
         // TODO increment refs colrev
 
@@ -544,16 +1030,7 @@ impl CollectionActor {
             is_deleted: is_delete,
             data: new_data.as_ref(),
         };
-        let updated_at: chrono::DateTime<chrono::Utc> =
-            diesel::update(schema::documents::table)
-                .filter(schema::documents::id.eq(&id))
-                .set(doc_update)
-                .returning(schema::documents::updated_at)
-                .get_result(&mut conn)
-                .await
-                .map_err(internal_error)?;
-
-        drop(conn);
+        let updated_at = self.storage.update_document(id, doc_update).await?;
 
         let serialized_new_data = new_data.map(|d| BASE64_STANDARD.encode(d));
 
@@ -568,8 +1045,14 @@ impl CollectionActor {
             created_at: doc.created_at,
             updated_at,
             changeid,
+            changeids: Vec::new(),
         };
-        self.broadcast(ServerMessage::Change(msg));
+        let access = DocumentAccess {
+            permissions: &doc.permissions,
+            owner: doc.created_by.as_deref(),
+        };
+        self.broadcast(ServerMessage::Change(msg), Some(access))
+            .await;
 
         let updated_doc = Document {
             id: doc.id,
@@ -579,6 +1062,7 @@ impl CollectionActor {
             col: doc.col_id,
             colrev: next_colrev,
             permissions: doc.permissions,
+            created_by: doc.created_by,
         };
         Ok(updated_doc)
     }
@@ -595,8 +1079,9 @@ pub struct CollectionHandle {
 impl CollectionHandle {
     pub fn new(
         col: Collection,
-        pool: db::DbConnectionPool,
+        storage: Arc<dyn Storage>,
         groups_api: Arc<GroupsApi>,
+        config: CollectionConfig,
         on_exit: Option<ExitCallback>,
     ) -> Self {
         let state = CollectionState::new(&col);
@@ -607,8 +1092,9 @@ impl CollectionHandle {
             col.id.clone(),
             state,
             receiver,
-            pool,
+            storage,
             groups_api,
+            config,
             supervisor.clone(),
         );
         let name = format!("collection:{}", &col.id);
@@ -627,3 +1113,596 @@ impl CollectionHandle {
         self.sender.send(msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::mailbox::{Mailbox, SlowClientPolicy};
+    use crate::permissions::Role;
+    use crate::storage::MemoryStorage;
+
+    async fn spawn_collection(
+        storage: Arc<dyn Storage>,
+        permissions: &Permissions,
+    ) -> CollectionHandle {
+        let config = CollectionConfig::default();
+        spawn_collection_with_config(storage, permissions, config).await
+    }
+
+    async fn spawn_collection_with_config(
+        storage: Arc<dyn Storage>,
+        permissions: &Permissions,
+        config: CollectionConfig,
+    ) -> CollectionHandle {
+        let new_col = models::NewCollection {
+            id: "test".to_string(),
+            is_ref: false,
+            permissions: permissions.to_string(),
+            quota: None,
+        };
+        let col = storage.create_collection(new_col).await.unwrap();
+        let groups_api = Arc::new(GroupsApi::new(storage.clone()));
+        CollectionHandle::new(col, storage, groups_api, config, None)
+    }
+
+    fn subscribe(
+        col: &CollectionHandle,
+        client_id: i32,
+        user: &str,
+    ) -> Arc<Mailbox> {
+        let mailbox = Arc::new(Mailbox::new(100, SlowClientPolicy::Disconnect));
+        let handle = ClientHandle::detached(user.to_string(), mailbox.clone());
+        col.send(CollectionMessage::Subscribe { client_id, handle })
+            .unwrap();
+        mailbox
+    }
+
+    fn empty_doc() -> String {
+        let snapshot = loro::LoroDoc::new()
+            .export(loro::ExportMode::Snapshot)
+            .unwrap();
+        BASE64_STANDARD.encode(snapshot)
+    }
+
+    async fn create(
+        col: &CollectionHandle,
+        user: &str,
+        permissions: Option<Permissions>,
+    ) -> Document {
+        let (reply, receiver) = oneshot::channel();
+        let msg = CreateMessage {
+            id: Uuid::new_v4(),
+            data: empty_doc(),
+            permissions,
+            source: Source::Client {
+                user: user.to_string(),
+            },
+            changeid: Uuid::new_v4(),
+            reply,
+        };
+        col.send(CollectionMessage::Create(Box::new(msg))).unwrap();
+        receiver.await.unwrap().unwrap()
+    }
+
+    // Update that inserts the text into the document
+    fn text_update(text: &str) -> String {
+        let doc = loro::LoroDoc::new();
+        doc.get_text("text").insert(0, text).unwrap();
+        let update = doc.export(loro::ExportMode::all_updates()).unwrap();
+        BASE64_STANDARD.encode(update)
+    }
+
+    async fn update(
+        col: &CollectionHandle,
+        user: &str,
+        id: Uuid,
+        data: String,
+    ) -> Result<Document, SinkronError> {
+        let (reply, receiver) = oneshot::channel();
+        let msg = UpdateMessage {
+            id,
+            data,
+            source: Source::Client {
+                user: user.to_string(),
+            },
+            changeid: Uuid::new_v4(),
+            reply,
+        };
+        col.send(CollectionMessage::Update(msg)).unwrap();
+        receiver.await.unwrap()
+    }
+
+    async fn try_create(
+        col: &CollectionHandle,
+        user: &str,
+    ) -> Result<Document, SinkronError> {
+        let (reply, receiver) = oneshot::channel();
+        let msg = CreateMessage {
+            id: Uuid::new_v4(),
+            data: empty_doc(),
+            permissions: None,
+            source: Source::Client {
+                user: user.to_string(),
+            },
+            changeid: Uuid::new_v4(),
+            reply,
+        };
+        col.send(CollectionMessage::Create(Box::new(msg))).unwrap();
+        receiver.await.unwrap()
+    }
+
+    async fn sync_page(col: &CollectionHandle, user: &str) -> SyncPage {
+        sync_range(col, user, 0, i64::MAX).await
+    }
+
+    async fn sync_range(
+        col: &CollectionHandle,
+        user: &str,
+        since: i64,
+        until: i64,
+    ) -> SyncPage {
+        let (reply, receiver) = oneshot::channel();
+        let msg = SyncPageMessage {
+            source: Source::Client {
+                user: user.to_string(),
+            },
+            since,
+            until,
+            skip_deleted: since == 0,
+            limit: 100,
+            reply,
+        };
+        col.send(CollectionMessage::SyncPage(msg)).unwrap();
+        receiver.await.unwrap().unwrap()
+    }
+
+    // Returns ids of the documents in the received change messages
+    async fn received_changes(mailbox: &Mailbox) -> Vec<Uuid> {
+        let mut ids = Vec::new();
+        let recv = || {
+            tokio::time::timeout(
+                tokio::time::Duration::from_millis(10),
+                mailbox.recv(),
+            )
+        };
+        while let Ok(Some(msg)) = recv().await {
+            if let ClientActorMessage::Change(change) = msg {
+                ids.push(change.id);
+            }
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn document_is_hidden_from_users_without_read_access() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut col_permissions = Permissions::empty();
+        col_permissions.read.push(Role::Any);
+        col_permissions.create.push(Role::Any);
+        let col = spawn_collection(storage, &col_permissions).await;
+
+        let owner_mailbox = subscribe(&col, 1, "owner");
+        let reader_mailbox = subscribe(&col, 2, "reader");
+
+        let mut private = Permissions::empty();
+        private.read.push(Role::Owner);
+        let private_doc = create(&col, "owner", Some(private)).await;
+        let shared_doc = create(&col, "owner", None).await;
+
+        assert_eq!(
+            received_changes(&owner_mailbox).await,
+            vec![private_doc.id, shared_doc.id]
+        );
+        assert_eq!(
+            received_changes(&reader_mailbox).await,
+            vec![shared_doc.id]
+        );
+
+        let page = sync_page(&col, "reader").await;
+        let ids: Vec<Uuid> = page.documents.iter().map(|doc| doc.id).collect();
+        assert_eq!(ids, vec![shared_doc.id]);
+        assert!(page.is_last);
+
+        let page = sync_page(&col, "owner").await;
+        assert_eq!(page.documents.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn document_deny_rules_apply_to_sync_and_broadcast() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let group = models::Group {
+            id: "editors".to_string(),
+            metadata: None,
+        };
+        storage.create_group(group).await.unwrap();
+        let members = ["editor".to_string(), "denied".to_string()];
+        storage.add_members("editors", &members).await.unwrap();
+        let editors = Role::Group {
+            id: "editors".to_string(),
+        };
+        let mut col_permissions = Permissions::empty();
+        col_permissions.read.push(editors.clone());
+        col_permissions.create.push(editors.clone());
+        let col = spawn_collection(storage, &col_permissions).await;
+
+        let editor_mailbox = subscribe(&col, 1, "editor");
+        let denied_mailbox = subscribe(&col, 2, "denied");
+
+        // Group "editors", except the user "denied"
+        let mut permissions = Permissions::empty();
+        permissions.read.push(editors);
+        permissions.deny.read.push(Role::User {
+            id: "denied".to_string(),
+        });
+        let doc = create(&col, "editor", Some(permissions)).await;
+
+        assert_eq!(received_changes(&editor_mailbox).await, vec![doc.id]);
+        assert!(received_changes(&denied_mailbox).await.is_empty());
+
+        let page = sync_page(&col, "denied").await;
+        assert!(page.documents.is_empty());
+        let page = sync_page(&col, "editor").await;
+        assert_eq!(page.documents.len(), 1);
+    }
+
+    // Returns messages received by the subscriber
+    async fn received_messages(mailbox: &Mailbox) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        let recv = || {
+            tokio::time::timeout(
+                tokio::time::Duration::from_millis(10),
+                mailbox.recv(),
+            )
+        };
+        while let Ok(Some(msg)) = recv().await {
+            let serialized = match msg {
+                ClientActorMessage::Change(change) => change.msg,
+                ClientActorMessage::Raw(msg) => msg,
+                _ => continue,
+            };
+            messages.push(serde_json::from_str(&serialized).unwrap());
+        }
+        messages
+    }
+
+    async fn set_document_permissions(
+        col: &CollectionHandle,
+        id: Uuid,
+        permissions: Permissions,
+    ) {
+        let (reply, receiver) = oneshot::channel();
+        let msg = SetDocumentPermissionsMessage {
+            id,
+            permissions,
+            source: Source::Api,
+            changeid: Uuid::new_v4(),
+            reply,
+        };
+        col.send(CollectionMessage::SetDocumentPermissions(Box::new(msg)))
+            .unwrap();
+        receiver.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn document_permissions_change_sends_gained_and_lost_documents() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut col_permissions = Permissions::empty();
+        col_permissions.read.push(Role::Any);
+        col_permissions.create.push(Role::Any);
+        let col = spawn_collection(storage, &col_permissions).await;
+
+        let owner_mailbox = subscribe(&col, 1, "owner");
+        let reader_mailbox = subscribe(&col, 2, "reader");
+
+        let mut private = Permissions::empty();
+        private.read.push(Role::Owner);
+        let doc = create(&col, "owner", Some(private.clone())).await;
+        received_messages(&owner_mailbox).await;
+        assert!(received_messages(&reader_mailbox).await.is_empty());
+
+        // Reader gains access and receives the document
+        let mut shared = private.clone();
+        shared.read.push(Role::Any);
+        set_document_permissions(&col, doc.id, shared).await;
+        let messages = received_messages(&reader_mailbox).await;
+        assert_eq!(messages.len(), 2);
+        let ServerMessage::Change(change) = &messages[0] else {
+            panic!("Expected change message");
+        };
+        assert!(matches!(change.op, Op::Create));
+        assert_eq!(change.id, doc.id);
+        assert_eq!(change.data, doc.data);
+        assert!(change.colrev > doc.colrev);
+        let shared_colrev = change.colrev;
+        assert!(matches!(messages[1], ServerMessage::Permissions(_)));
+        let messages = received_messages(&owner_mailbox).await;
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(messages[0], ServerMessage::Permissions(_)));
+
+        let page = sync_range(&col, "reader", doc.colrev, i64::MAX).await;
+        assert_eq!(page.documents.len(), 1);
+
+        // Reader loses access and receives the document as deleted
+        set_document_permissions(&col, doc.id, private).await;
+        let messages = received_messages(&reader_mailbox).await;
+        assert_eq!(messages.len(), 2);
+        let ServerMessage::Change(change) = &messages[0] else {
+            panic!("Expected change message");
+        };
+        assert!(matches!(change.op, Op::Delete));
+        assert!(change.data.is_none());
+
+        // Sync after losing access also returns the document as deleted
+        let page = sync_range(&col, "reader", shared_colrev, i64::MAX).await;
+        assert_eq!(page.documents.len(), 1);
+        assert!(page.documents[0].data.is_none());
+        let page = sync_page(&col, "reader").await;
+        assert!(page.documents.is_empty());
+    }
+
+    #[tokio::test]
+    async fn share_cannot_grant_more_than_current_permissions() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut col_permissions = Permissions::empty();
+        col_permissions.create.push(Role::Any);
+        col_permissions.read.push(Role::Owner);
+        col_permissions.share.push(Role::Owner);
+        let col = spawn_collection(storage, &col_permissions).await;
+
+        let doc = create(&col, "owner", None).await;
+
+        let set_permissions = |permissions: Permissions| {
+            let (reply, receiver) = oneshot::channel();
+            let msg = SetDocumentPermissionsMessage {
+                id: doc.id,
+                permissions,
+                source: Source::Client {
+                    user: "owner".to_string(),
+                },
+                changeid: Uuid::new_v4(),
+                reply,
+            };
+            col.send(CollectionMessage::SetDocumentPermissions(Box::new(msg)))
+                .unwrap();
+            receiver
+        };
+
+        let mut narrowed = col_permissions.clone();
+        narrowed.deny.read.push(Role::User {
+            id: "other".to_string(),
+        });
+        assert!(set_permissions(narrowed).await.unwrap().is_ok());
+
+        let mut widened = col_permissions.clone();
+        widened.read.push(Role::Any);
+        let res = set_permissions(widened).await.unwrap();
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn sync_continues_with_documents_changed_during_sync() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut col_permissions = Permissions::empty();
+        col_permissions.read.push(Role::Any);
+        col_permissions.create.push(Role::Any);
+        let col = spawn_collection(storage, &col_permissions).await;
+
+        let first = create(&col, "user", None).await;
+        // Sync was started at colrev 1, then another document was created
+        let second = create(&col, "user", None).await;
+
+        let page = sync_range(&col, "user", 0, first.colrev).await;
+        assert_eq!(page.documents.len(), 1);
+        assert_eq!(page.until, second.colrev);
+        assert!(!page.is_last);
+
+        let page = sync_range(&col, "user", page.colrev, page.until).await;
+        let ids: Vec<Uuid> = page.documents.iter().map(|doc| doc.id).collect();
+        assert_eq!(ids, vec![second.id]);
+        assert!(page.is_last);
+    }
+
+    fn writable_permissions() -> Permissions {
+        let mut permissions = Permissions::empty();
+        permissions.read.push(Role::Any);
+        permissions.create.push(Role::Any);
+        permissions.update.push(Role::Any);
+        permissions.delete.push(Role::Any);
+        permissions
+    }
+
+    #[tokio::test]
+    async fn update_larger_than_limit_is_rejected() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let config = CollectionConfig {
+            max_update_size: Some(256),
+            ..Default::default()
+        };
+        let permissions = writable_permissions();
+        let col =
+            spawn_collection_with_config(storage, &permissions, config).await;
+
+        let doc = create(&col, "user", None).await;
+        let large = text_update(&"x".repeat(1000));
+        let err = update(&col, "user", doc.id, large).await.err().unwrap();
+        assert!(matches!(err.code, ErrorCode::PayloadTooLarge));
+
+        let small = text_update("Hello");
+        assert!(update(&col, "user", doc.id, small).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn document_larger_than_limit_is_rejected() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let empty_size = BASE64_STANDARD.decode(empty_doc()).unwrap().len();
+        let config = CollectionConfig {
+            max_document_size: Some(empty_size + 256),
+            ..Default::default()
+        };
+        let permissions = writable_permissions();
+        let col =
+            spawn_collection_with_config(storage, &permissions, config).await;
+
+        let doc = create(&col, "user", None).await;
+        let data = text_update("Hello");
+        assert!(update(&col, "user", doc.id, data).await.is_ok());
+        // Each update is small, but the resulting snapshot is too large
+        let mut err = None;
+        for _ in 0..10 {
+            let data = text_update(&Uuid::new_v4().to_string());
+            err = update(&col, "user", doc.id, data).await.err();
+            if err.is_some() {
+                break;
+            }
+        }
+        let err = err.unwrap();
+        assert!(matches!(err.code, ErrorCode::PayloadTooLarge));
+
+        // Rejected update doesn't change the document
+        let page = sync_page(&col, "user").await;
+        let stored = BASE64_STANDARD
+            .decode(page.documents[0].data.as_ref().unwrap())
+            .unwrap();
+        assert!(stored.len() <= empty_size + 256);
+    }
+
+    async fn delete(col: &CollectionHandle, user: &str, id: Uuid) {
+        let (reply, receiver) = oneshot::channel();
+        let msg = DeleteMessage {
+            id,
+            source: Source::Client {
+                user: user.to_string(),
+            },
+            changeid: Uuid::new_v4(),
+            reply,
+        };
+        col.send(CollectionMessage::Delete(msg)).unwrap();
+        receiver.await.unwrap().unwrap();
+    }
+
+    fn data_size(doc: &Document) -> i64 {
+        let data = doc.data.as_ref().unwrap();
+        BASE64_STANDARD.decode(data).unwrap().len() as i64
+    }
+
+    #[tokio::test]
+    async fn changes_keep_collection_usage() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let permissions = writable_permissions();
+        let col = spawn_collection(storage.clone(), &permissions).await;
+        let usage = || async {
+            let col = storage.get_collection("test").await.unwrap();
+            (col.documents_count, col.size)
+        };
+
+        let first = create(&col, "user", None).await;
+        let second = create(&col, "user", None).await;
+        let empty_size = data_size(&first);
+        assert_eq!(usage().await, (2, 2 * empty_size));
+
+        let data = text_update("Hello");
+        let updated = update(&col, "user", first.id, data).await.unwrap();
+        assert_eq!(usage().await, (2, data_size(&updated) + empty_size));
+
+        delete(&col, "user", second.id).await;
+        assert_eq!(usage().await, (1, data_size(&updated)));
+        delete(&col, "user", first.id).await;
+        assert_eq!(usage().await, (0, 0));
+    }
+
+    #[tokio::test]
+    async fn collection_quota_rejects_writes() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let permissions = writable_permissions();
+        let col = spawn_collection(storage.clone(), &permissions).await;
+
+        let doc = create(&col, "user", None).await;
+        let (reply, receiver) = oneshot::channel();
+        let msg = SetQuotaMessage {
+            quota: Some(data_size(&doc)),
+            reply,
+        };
+        col.send(CollectionMessage::SetQuota(msg)).unwrap();
+        receiver.await.unwrap().unwrap();
+        let stored = storage.get_collection("test").await.unwrap();
+        assert_eq!(stored.quota, Some(data_size(&doc)));
+
+        let err = try_create(&col, "user").await.err().unwrap();
+        assert!(matches!(err.code, ErrorCode::QuotaExceeded));
+        let data = text_update("Hello");
+        let err = update(&col, "user", doc.id, data).await.err().unwrap();
+        assert!(matches!(err.code, ErrorCode::QuotaExceeded));
+        // Deleting is allowed when the quota is exceeded
+        delete(&col, "user", doc.id).await;
+        assert!(try_create(&col, "user").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn collection_larger_than_limit_is_rejected() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let empty_size = BASE64_STANDARD.decode(empty_doc()).unwrap().len();
+        let config = CollectionConfig {
+            max_collection_size: Some(2 * empty_size as i64),
+            ..Default::default()
+        };
+        let permissions = writable_permissions();
+        let col =
+            spawn_collection_with_config(storage, &permissions, config).await;
+
+        let first = create(&col, "user", None).await;
+        create(&col, "user", None).await;
+        let err = try_create(&col, "user").await.err().unwrap();
+        assert!(matches!(err.code, ErrorCode::QuotaExceeded));
+        let data = text_update("Hello");
+        let err = update(&col, "user", first.id, data).await.err().unwrap();
+        assert!(matches!(err.code, ErrorCode::QuotaExceeded));
+
+        // Deleting the document frees the space
+        delete(&col, "user", first.id).await;
+        assert!(try_create(&col, "user").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn sync_after_purge_requires_resync() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut col_permissions = Permissions::empty();
+        col_permissions.read.push(Role::Any);
+        col_permissions.create.push(Role::Any);
+        col_permissions.delete.push(Role::Any);
+        let col = spawn_collection(storage.clone(), &col_permissions).await;
+
+        let doc = create(&col, "user", None).await;
+        create(&col, "user", None).await;
+        let (reply, receiver) = oneshot::channel();
+        let msg = DeleteMessage {
+            id: doc.id,
+            source: Source::Client {
+                user: "user".to_string(),
+            },
+            changeid: Uuid::new_v4(),
+            reply,
+        };
+        col.send(CollectionMessage::Delete(msg)).unwrap();
+        let deleted = receiver.await.unwrap().unwrap();
+
+        let before = chrono::Utc::now() + chrono::Duration::seconds(1);
+        storage.purge_deleted_documents(before).await.unwrap();
+
+        let sync = |colrev: i64| {
+            let (reply, receiver) = oneshot::channel();
+            let msg = SyncMessage {
+                colrev,
+                source: Source::Client {
+                    user: "user".to_string(),
+                },
+                reply,
+            };
+            col.send(CollectionMessage::Sync(msg)).unwrap();
+            receiver
+        };
+        let err = sync(1).await.unwrap().err().unwrap();
+        assert!(matches!(err.code, ErrorCode::ResyncRequired));
+        assert!(sync(0).await.unwrap().is_ok());
+        assert!(sync(deleted.colrev).await.unwrap().is_ok());
+    }
+}
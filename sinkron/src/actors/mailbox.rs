@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+use crate::actors::client::{ClientActorMessage, QueuedChange};
+
+// What to do when the client doesn't keep up with incoming messages and
+// its mailbox is full
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SlowClientPolicy {
+    // Disconnect the client, it will resync after reconnecting
+    #[default]
+    Disconnect,
+    // Replace queued updates of the document with the newest one, changeids
+    // of the replaced updates are sent with it. If there is nothing to
+    // collapse, client is disconnected.
+    Collapse,
+}
+
+#[derive(Debug)]
+pub struct MailboxFull;
+
+// Bounded queue of messages to the client actor
+pub struct Mailbox {
+    queue: Mutex<VecDeque<ClientActorMessage>>,
+    notify: Notify,
+    capacity: usize,
+    policy: SlowClientPolicy,
+    overflow: AtomicBool,
+}
+
+impl Mailbox {
+    pub fn new(capacity: usize, policy: SlowClientPolicy) -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            capacity,
+            policy,
+            overflow: AtomicBool::new(false),
+        }
+    }
+
+    pub fn send(&self, mut msg: ClientActorMessage) -> Result<(), MailboxFull> {
+        if self.overflow.load(Ordering::Acquire) {
+            return Err(MailboxFull);
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.capacity {
+            let collapsed = match (self.policy, &mut msg) {
+                (
+                    SlowClientPolicy::Collapse,
+                    ClientActorMessage::Change(change),
+                ) if change.is_update => Self::collapse(&mut queue, change),
+                _ => false,
+            };
+            if !collapsed {
+                drop(queue);
+                self.overflow.store(true, Ordering::Release);
+                self.notify.notify_one();
+                return Err(MailboxFull);
+            }
+        }
+        queue.push_back(msg);
+        drop(queue);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    // Removes queued update of the same document, so the new message can
+    // take its place at the end of the queue (this keeps colrevs ordered).
+    // Changeids of the removed update are moved to the new one, so the
+    // client that made the change still receives the confirmation.
+    fn collapse(
+        queue: &mut VecDeque<ClientActorMessage>,
+        change: &mut QueuedChange,
+    ) -> bool {
+        let pos = queue.iter().position(|queued| {
+            matches!(queued, ClientActorMessage::Change(queued)
+                if queued.is_update && queued.id == change.id)
+        });
+        let Some(ClientActorMessage::Change(removed)) =
+            pos.and_then(|pos| queue.remove(pos))
+        else {
+            return false;
+        };
+        change.collapsed.push(removed.changeid);
+        change.collapsed.extend(removed.collapsed);
+        true
+    }
+
+    // Returns next message, or `None` when the mailbox has overflowed
+    pub async fn recv(&self) -> Option<ClientActorMessage> {
+        loop {
+            if self.overflow.load(Ordering::Acquire) {
+                return None;
+            }
+            if let Some(msg) = self.queue.lock().unwrap().pop_front() {
+                return Some(msg);
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::protocol::{Op, ServerChangeMessage, ServerMessage};
+
+    fn change(id: Uuid, colrev: i64, op: Op) -> ClientActorMessage {
+        let changeid = Uuid::new_v4();
+        let is_update = matches!(op, Op::Update);
+        let msg = ServerMessage::Change(ServerChangeMessage {
+            id,
+            col: "test".to_string(),
+            op,
+            data: None,
+            changeid,
+            changeids: Vec::new(),
+            colrev,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        });
+        ClientActorMessage::Change(QueuedChange {
+            id,
+            colrev,
+            changeid,
+            is_update,
+            msg: serde_json::to_string(&msg).unwrap(),
+            collapsed: Vec::new(),
+        })
+    }
+
+    fn changeid(msg: &ClientActorMessage) -> Uuid {
+        match msg {
+            ClientActorMessage::Change(change) => change.changeid,
+            _ => panic!("not a change"),
+        }
+    }
+
+    #[tokio::test]
+    async fn overflow_closes_mailbox() {
+        let mailbox = Arc::new(Mailbox::new(2, SlowClientPolicy::Disconnect));
+        let doc = Uuid::new_v4();
+        mailbox.send(change(doc, 1, Op::Update)).unwrap();
+        mailbox.send(change(doc, 2, Op::Update)).unwrap();
+        assert!(mailbox.recv().await.is_some());
+        mailbox.send(change(doc, 3, Op::Update)).unwrap();
+
+        // Receiver that waits for the message is woken up by the overflow
+        let receiver = {
+            let mailbox = mailbox.clone();
+            tokio::spawn(async move { while mailbox.recv().await.is_some() {} })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        mailbox.send(change(doc, 4, Op::Update)).unwrap();
+        mailbox.send(change(doc, 5, Op::Update)).unwrap();
+        assert!(mailbox.send(change(doc, 6, Op::Update)).is_err());
+        tokio::time::timeout(Duration::from_secs(1), receiver)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(mailbox.recv().await.is_none());
+        assert!(mailbox.send(ClientActorMessage::Raw("h".into())).is_err());
+    }
+
+    #[tokio::test]
+    async fn collapse_replaces_queued_update() {
+        let mailbox = Mailbox::new(2, SlowClientPolicy::Collapse);
+        let doc = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let first = change(doc, 1, Op::Update);
+        let first_id = changeid(&first);
+        mailbox.send(first).unwrap();
+        let created = change(other, 2, Op::Create);
+        let created_id = changeid(&created);
+        mailbox.send(created).unwrap();
+
+        let second = change(doc, 3, Op::Update);
+        let second_id = changeid(&second);
+        mailbox.send(second).unwrap();
+        let third = change(doc, 4, Op::Update);
+        let third_id = changeid(&third);
+        mailbox.send(third).unwrap();
+
+        // Order of colrevs is kept, replaced changeids are carried over
+        assert_eq!(changeid(&mailbox.recv().await.unwrap()), created_id);
+        let Some(ClientActorMessage::Change(last)) = mailbox.recv().await
+        else {
+            panic!("not a change");
+        };
+        assert_eq!(last.changeid, third_id);
+        assert_eq!(last.collapsed, vec![second_id, first_id]);
+        let ServerMessage::Change(msg) =
+            serde_json::from_str(&last.into_message()).unwrap()
+        else {
+            panic!("not a change");
+        };
+        assert_eq!(msg.changeid, third_id);
+        assert_eq!(msg.changeids, vec![second_id, first_id]);
+
+        // Messages other than updates can't be collapsed
+        let mailbox = Mailbox::new(1, SlowClientPolicy::Collapse);
+        mailbox.send(change(doc, 1, Op::Update)).unwrap();
+        assert!(mailbox.send(change(doc, 2, Op::Delete)).is_err());
+        assert!(mailbox.recv().await.is_none());
+    }
+}
@@ -1,4 +1,5 @@
 pub mod supervisor;
 pub mod client;
 pub mod collection;
+pub mod mailbox;
 pub mod sinkron;
@@ -2,20 +2,21 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use axum::extract::ws::{Message, WebSocket};
-use diesel::prelude::*;
-use diesel_async::RunQueryDsl;
 use log::debug;
 use tokio::select;
 use tokio::sync::{mpsc, oneshot};
 
-use crate::actors::client::ClientHandle;
-use crate::actors::collection::{CollectionHandle, CollectionMessage};
+use crate::actors::client::{ClientConfig, ClientHandle};
+use crate::actors::collection::{
+    CollectionConfig, CollectionHandle, CollectionMessage,
+};
 use crate::actors::supervisor::ExitCallback;
-use crate::db;
-use crate::error::{internal_error, SinkronError};
+use crate::compression::CompressionConfig;
+use crate::error::SinkronError;
 use crate::groups::GroupsApi;
 use crate::protocol::*;
-use crate::schema;
+use crate::rate_limit::UserRateLimiter;
+use crate::storage::Storage;
 use crate::types::Collection;
 
 pub struct ConnectMessage {
@@ -23,6 +24,7 @@ pub struct ConnectMessage {
     pub user: String,
     pub col: String,
     pub colrev: i64,
+    pub compression: Option<CompressionConfig>,
 }
 
 pub struct GetCollectionMessage {
@@ -31,8 +33,11 @@ pub struct GetCollectionMessage {
 }
 
 pub enum SinkronActorMessage {
-    Connect(ConnectMessage),
+    Connect(Box<ConnectMessage>),
     GetCollection(GetCollectionMessage),
+    // Groups of the users were changed, so all active collections should
+    // re-check permissions of their subscribers
+    RecheckSubscribers,
 }
 
 struct SinkronActor {
@@ -40,26 +45,45 @@ struct SinkronActor {
     client_id: i32,
     collections: HashMap<String, CollectionHandle>,
     groups_api: Arc<GroupsApi>,
-    pool: db::DbConnectionPool,
+    storage: Arc<dyn Storage>,
+    client_config: ClientConfig,
+    collection_config: CollectionConfig,
     exit_channel: (
         mpsc::UnboundedSender<String>,
         mpsc::UnboundedReceiver<String>,
     ),
+    // Number of active connections of each user
+    connections: HashMap<String, usize>,
+    user_limiter: Arc<UserRateLimiter>,
+    client_exit_channel: (
+        mpsc::UnboundedSender<String>,
+        mpsc::UnboundedReceiver<String>,
+    ),
 }
 
 impl SinkronActor {
     fn new(
         receiver: mpsc::UnboundedReceiver<SinkronActorMessage>,
         groups_api: Arc<GroupsApi>,
-        pool: db::DbConnectionPool,
+        storage: Arc<dyn Storage>,
+        client_config: ClientConfig,
+        collection_config: CollectionConfig,
     ) -> Self {
+        let user_limiter = Arc::new(UserRateLimiter::new(
+            client_config.rate_limit.user.clone(),
+        ));
         Self {
             receiver,
             client_id: 0,
             groups_api,
-            pool,
+            storage,
+            client_config,
+            collection_config,
             collections: HashMap::new(),
             exit_channel: mpsc::unbounded_channel(),
+            connections: HashMap::new(),
+            user_limiter,
+            client_exit_channel: mpsc::unbounded_channel(),
         }
     }
 
@@ -79,6 +103,9 @@ impl SinkronActor {
                     debug!("sinkron: col exit, id: {}", id);
                     self.collections.remove(&id);
                 },
+                Some(user) = self.client_exit_channel.1.recv() => {
+                    self.handle_client_exit(user);
+                },
             }
         }
         debug!("sinkron: actor exit");
@@ -87,56 +114,62 @@ impl SinkronActor {
     async fn handle_message(&mut self, msg: SinkronActorMessage) {
         match msg {
             SinkronActorMessage::Connect(msg) => {
-                self.handle_connect(msg).await;
+                self.handle_connect(*msg).await;
             }
             SinkronActorMessage::GetCollection(msg) => {
                 let GetCollectionMessage { col, reply } = msg;
                 let res = self.get_collection_actor_by_id(&col).await;
                 _ = reply.send(res);
             }
+            SinkronActorMessage::RecheckSubscribers => {
+                debug!("sinkron: recheck subscribers");
+                for col in self.collections.values() {
+                    _ = col.send(CollectionMessage::RecheckSubscribers);
+                }
+            }
         }
     }
 
-    async fn connect(&self) -> Result<db::DbConnection, SinkronError> {
-        self.pool.get().await.map_err(internal_error)
-    }
-
     async fn handle_connect(&mut self, msg: ConnectMessage) {
         let ConnectMessage {
             mut websocket,
             user,
             col,
             colrev,
+            compression,
         } = msg;
 
         debug!("sinkron: client connect: {}", user);
 
-        let Ok(mut conn) = self.connect().await else {
-            let msg = ServerMessage::SyncError(SyncErrorMessage {
-                col,
-                code: ErrorCode::InternalServerError,
-            });
-            if let Ok(encoded) = serde_json::to_string(&msg) {
-                let _ = websocket.send(Message::Text(encoded)).await;
+        if let Some(max) =
+            self.client_config.rate_limit.max_connections_per_user
+        {
+            let num = self.connections.get(&user).copied().unwrap_or(0);
+            if num >= max {
+                debug!("sinkron: too many connections: {}", user);
+                let msg = ServerMessage::SyncError(SyncErrorMessage {
+                    col,
+                    code: ErrorCode::TooManyRequests,
+                });
+                if let Ok(encoded) = serde_json::to_string(&msg) {
+                    let _ = websocket.send(Message::Text(encoded)).await;
+                }
+                return;
             }
-            return;
-        };
-        let res = schema::collections::table
-            .find(&col)
-            .first::<Collection>(&mut conn)
-            .await;
-        drop(conn);
-
-        let Ok(col_model) = res else {
-            // collection not found
-            let msg = ServerMessage::SyncError(SyncErrorMessage {
-                col,
-                code: ErrorCode::NotFound,
-            });
-            if let Ok(encoded) = serde_json::to_string(&msg) {
-                let _ = websocket.send(Message::Text(encoded)).await;
+        }
+
+        let col_model = match self.storage.get_collection(&col).await {
+            Ok(col_model) => col_model,
+            Err(err) => {
+                let msg = ServerMessage::SyncError(SyncErrorMessage {
+                    col,
+                    code: err.code,
+                });
+                if let Ok(encoded) = serde_json::to_string(&msg) {
+                    let _ = websocket.send(Message::Text(encoded)).await;
+                }
+                return;
             }
-            return;
         };
 
         let collection = self.get_collection_actor(col_model);
@@ -145,18 +178,25 @@ impl SinkronActor {
         let client_id = self.next_client_id();
         let on_exit: ExitCallback = {
             let collection = collection.clone();
+            let exit_sender = self.client_exit_channel.0.clone();
+            let user = user.clone();
             Box::new(move || {
                 debug!("client-{}: exit", client_id);
                 _ = collection
                     .send(CollectionMessage::Unsubscribe { client_id });
+                _ = exit_sender.send(user);
             })
         };
+        *self.connections.entry(user.clone()).or_insert(0) += 1;
         let client = ClientHandle::new(
             client_id,
             user,
             websocket,
             collection.clone(),
             colrev,
+            compression,
+            self.client_config.clone(),
+            self.user_limiter.clone(),
             Some(on_exit),
         );
 
@@ -167,6 +207,16 @@ impl SinkronActor {
         });
     }
 
+    fn handle_client_exit(&mut self, user: String) {
+        let Some(num) = self.connections.get_mut(&user) else {
+            return;
+        };
+        *num -= 1;
+        if *num == 0 {
+            self.connections.remove(&user);
+        }
+    }
+
     fn next_client_id(&mut self) -> i32 {
         self.client_id += 1;
         self.client_id
@@ -183,17 +233,7 @@ impl SinkronActor {
         &mut self,
         id: &str,
     ) -> Result<CollectionHandle, SinkronError> {
-        let mut conn = self.connect().await?;
-        let col = schema::collections::table
-            .find(id)
-            .first::<Collection>(&mut conn)
-            .await
-            .map_err(|err| match err {
-                diesel::NotFound => {
-                    SinkronError::not_found("Collection not found")
-                }
-                err => SinkronError::internal(&err.to_string()),
-            })?;
+        let col = self.storage.get_collection(id).await?;
         Ok(self.get_collection_actor(col))
     }
 
@@ -208,8 +248,9 @@ impl SinkronActor {
         let id = col.id.clone();
         let col_handle = CollectionHandle::new(
             col,
-            self.pool.clone(),
+            self.storage.clone(),
             self.groups_api.clone(),
+            self.collection_config.clone(),
             Some(on_exit),
         );
         self.collections.insert(id, col_handle.clone());
@@ -223,9 +264,20 @@ pub struct SinkronHandle {
 }
 
 impl SinkronHandle {
-    pub fn new(pool: db::DbConnectionPool, groups_api: Arc<GroupsApi>) -> Self {
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        groups_api: Arc<GroupsApi>,
+        client_config: ClientConfig,
+        collection_config: CollectionConfig,
+    ) -> Self {
         let (sender, receiver) = mpsc::unbounded_channel();
-        let mut actor = SinkronActor::new(receiver, groups_api, pool);
+        let mut actor = SinkronActor::new(
+            receiver,
+            groups_api,
+            storage,
+            client_config,
+            collection_config,
+        );
         tokio::spawn(async move { actor.run().await });
         Self { sender }
     }
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::error::{internal_error, SinkronError};
+use crate::models;
+use crate::permissions::Permissions;
+use crate::storage::Storage;
+
+// Collection archive is a gzipped tar file with the following entries:
+//
+// - "manifest.json" - metadata of the collection and its documents
+// - "documents/{id}.loro" - Loro snapshot of each document, deleted
+//   documents don't have snapshots
+//
+// Refs are not exported, since collections can't contain refs yet.
+
+const FORMAT: &str = "sinkron-collection";
+const VERSION: u32 = 1;
+
+const MANIFEST_PATH: &str = "manifest.json";
+
+const PAGE_SIZE: i64 = 1000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    format: String,
+    version: u32,
+    exported_at: chrono::DateTime<chrono::Utc>,
+    collection: ArchivedCollection,
+    documents: Vec<ArchivedDocument>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchivedCollection {
+    id: String,
+    is_ref: bool,
+    colrev: i64,
+    permissions: Value,
+    quota: Option<i64>,
+    #[serde(default)]
+    purged_colrev: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchivedDocument {
+    id: Uuid,
+    colrev: i64,
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+    is_deleted: bool,
+    permissions: Value,
+    created_by: Option<String>,
+    // Path of the snapshot in the archive
+    snapshot: Option<String>,
+}
+
+// Permissions are stored as JSON strings, archive keeps them as JSON values
+// to be readable
+fn permissions_to_json(permissions: String) -> Value {
+    serde_json::from_str(&permissions).unwrap_or(Value::String(permissions))
+}
+
+// Imported permissions are validated, so the collection doesn't end up with
+// permissions that are silently treated as empty
+fn permissions_from_json(permissions: Value) -> Result<String, SinkronError> {
+    let permissions: Permissions = match permissions {
+        Value::String(permissions) => serde_json::from_str(&permissions),
+        permissions => serde_json::from_value(permissions),
+    }
+    .map_err(|err| {
+        let msg = format!("Invalid permissions: {}", err);
+        SinkronError::bad_request(&msg)
+    })?;
+    Ok(permissions.to_string())
+}
+
+// Limits of the imported archives, they protect from archives that unpack
+// into huge amounts of data
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportLimits {
+    // Max size of the uploaded archive (in bytes)
+    #[serde(default = "default_max_archive_size")]
+    pub max_archive_size: usize,
+    // Max total size of the unpacked files (in bytes)
+    #[serde(default = "default_max_unpacked_size")]
+    pub max_unpacked_size: u64,
+    // Max size of a single unpacked file (in bytes)
+    #[serde(default = "default_max_entry_size")]
+    pub max_entry_size: u64,
+}
+
+fn default_max_archive_size() -> usize {
+    256 * 1024 * 1024
+}
+
+fn default_max_unpacked_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_max_entry_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+impl Default for ImportLimits {
+    fn default() -> Self {
+        Self {
+            max_archive_size: default_max_archive_size(),
+            max_unpacked_size: default_max_unpacked_size(),
+            max_entry_size: default_max_entry_size(),
+        }
+    }
+}
+
+// Fails the read when more than `remaining` bytes are read in total
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    is_exceeded: bool,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        match self.remaining.checked_sub(n as u64) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(n)
+            }
+            None => {
+                self.is_exceeded = true;
+                Err(std::io::Error::other("Archive is too large"))
+            }
+        }
+    }
+}
+
+fn tar_append(
+    tar: &mut tar::Builder<GzEncoder<Vec<u8>>>,
+    path: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<(), SinkronError> {
+    let mut header = tar::Header::new_ustar();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_entry_type(tar::EntryType::Regular);
+    tar.append_data(&mut header, path, data)
+        .map_err(internal_error)
+}
+
+// Reads regular files from the archive, path -> contents
+fn tar_read(
+    archive: &[u8],
+    limits: &ImportLimits,
+) -> Result<HashMap<String, Vec<u8>>, SinkronError> {
+    let mut reader = LimitedReader {
+        inner: GzDecoder::new(archive),
+        remaining: limits.max_unpacked_size,
+        is_exceeded: false,
+    };
+    let res = read_entries(&mut tar::Archive::new(&mut reader), limits);
+    if reader.is_exceeded {
+        return Err(SinkronError::payload_too_large("Archive is too large"));
+    }
+    res
+}
+
+fn read_entries<R: Read>(
+    tar: &mut tar::Archive<R>,
+    limits: &ImportLimits,
+) -> Result<HashMap<String, Vec<u8>>, SinkronError> {
+    let invalid = |_| SinkronError::bad_request("Invalid archive");
+    let mut files = HashMap::new();
+    for entry in tar.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path().map_err(invalid)?;
+        let Some(path) = path.to_str().map(str::to_string) else {
+            return Err(SinkronError::bad_request("Invalid archive"));
+        };
+        let size = entry.size();
+        if size > limits.max_entry_size {
+            let msg = format!("Archive entry is too large: {}", path);
+            return Err(SinkronError::payload_too_large(&msg));
+        }
+        let mut data = Vec::with_capacity(size as usize);
+        entry.read_to_end(&mut data).map_err(invalid)?;
+        files.insert(path, data);
+    }
+    Ok(files)
+}
+
+// Export
+
+pub async fn export_collection(
+    storage: &dyn Storage,
+    id: &str,
+) -> Result<Vec<u8>, SinkronError> {
+    let col = storage.get_collection(id).await?;
+    let now = chrono::Utc::now();
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut tar = tar::Builder::new(encoder);
+    let mut documents = Vec::new();
+    let mut since = 0;
+    loop {
+        let page = storage
+            .get_documents_page(id, since, col.colrev, false, PAGE_SIZE)
+            .await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        since = last.colrev;
+        for doc in page {
+            let snapshot = match doc.data {
+                Some(data) => {
+                    let path = format!("documents/{}.loro", doc.id);
+                    let mtime = doc.updated_at.timestamp().max(0) as u64;
+                    tar_append(&mut tar, &path, &data, mtime)?;
+                    Some(path)
+                }
+                None => None,
+            };
+            documents.push(ArchivedDocument {
+                id: doc.id,
+                colrev: doc.colrev,
+                created_at: doc.created_at,
+                updated_at: doc.updated_at,
+                is_deleted: doc.is_deleted,
+                permissions: permissions_to_json(doc.permissions),
+                created_by: doc.created_by,
+                snapshot,
+            });
+        }
+    }
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        exported_at: now,
+        collection: ArchivedCollection {
+            id: col.id,
+            is_ref: col.is_ref,
+            colrev: col.colrev,
+            permissions: permissions_to_json(col.permissions),
+            quota: col.quota,
+            purged_colrev: col.purged_colrev,
+        },
+        documents,
+    };
+    let manifest =
+        serde_json::to_vec_pretty(&manifest).map_err(internal_error)?;
+    tar_append(&mut tar, MANIFEST_PATH, &manifest, now.timestamp() as u64)?;
+    let encoder = tar.into_inner().map_err(internal_error)?;
+    encoder.finish().map_err(internal_error)
+}
+
+// Import
+
+#[derive(Deserialize, Default)]
+pub struct ImportOptions {
+    // Id of the new collection, id from the archive is used when not set
+    #[serde(default)]
+    pub id: Option<String>,
+    // Generate new ids for the documents, required to import collection
+    // to the same server where it was exported from
+    #[serde(default)]
+    pub remap_ids: bool,
+}
+
+pub async fn import_collection(
+    storage: &dyn Storage,
+    archive: &[u8],
+    options: ImportOptions,
+    limits: &ImportLimits,
+) -> Result<models::Collection, SinkronError> {
+    if archive.len() > limits.max_archive_size {
+        return Err(SinkronError::payload_too_large("Archive is too large"));
+    }
+    let mut files = tar_read(archive, limits)?;
+
+    let Some(manifest) = files.remove(MANIFEST_PATH) else {
+        return Err(SinkronError::bad_request("Archive manifest not found"));
+    };
+    let manifest: Manifest = serde_json::from_slice(&manifest)
+        .map_err(|err| SinkronError::bad_request(&err.to_string()))?;
+    if manifest.format != FORMAT || manifest.version > VERSION {
+        return Err(SinkronError::bad_request("Unsupported archive format"));
+    }
+
+    let col_id = options.id.unwrap_or(manifest.collection.id);
+    let mut colrev = manifest.collection.colrev;
+    let mut documents_count = 0;
+    let mut size = 0;
+    let mut docs = Vec::with_capacity(manifest.documents.len());
+    for doc in manifest.documents {
+        // Deleted documents are only needed to resume sync of the clients,
+        // which is not possible with the new ids
+        if options.remap_ids && doc.is_deleted {
+            continue;
+        }
+        let data = match &doc.snapshot {
+            Some(path) => match files.remove(path) {
+                Some(data) => Some(data),
+                None => {
+                    let msg = format!("Snapshot not found: {}", path);
+                    return Err(SinkronError::bad_request(&msg));
+                }
+            },
+            None => None,
+        };
+        if !doc.is_deleted {
+            documents_count += 1;
+            size += data.as_ref().map_or(0, |data| data.len() as i64);
+        }
+        colrev = colrev.max(doc.colrev);
+        let id = if options.remap_ids {
+            Uuid::new_v4()
+        } else {
+            doc.id
+        };
+        docs.push(models::Document {
+            id,
+            created_at: doc.created_at,
+            updated_at: doc.updated_at,
+            col_id: col_id.clone(),
+            colrev: doc.colrev,
+            data,
+            is_deleted: doc.is_deleted,
+            permissions: permissions_from_json(doc.permissions)?,
+            created_by: doc.created_by,
+        });
+    }
+
+    // Clients that synced before the import can't resume with the new ids
+    let purged_colrev = if options.remap_ids {
+        colrev
+    } else {
+        manifest.collection.purged_colrev
+    };
+    let col = models::Collection {
+        id: col_id,
+        is_ref: manifest.collection.is_ref,
+        colrev,
+        permissions: permissions_from_json(manifest.collection.permissions)?,
+        documents_count,
+        size,
+        quota: manifest.collection.quota,
+        purged_colrev,
+    };
+    storage.import_collection(col.clone(), docs).await?;
+    Ok(col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    async fn create_collection(storage: &dyn Storage) {
+        let col = models::NewCollection {
+            id: "col".to_string(),
+            is_ref: false,
+            permissions: Permissions::empty().to_string(),
+            quota: None,
+        };
+        storage.create_collection(col).await.unwrap();
+        let usage = storage.increment_colrev("col", 1, 3).await.unwrap();
+        let doc = models::NewDocument {
+            id: Uuid::new_v4(),
+            col_id: "col".to_string(),
+            colrev: usage.colrev,
+            data: vec![1, 2, 3],
+            permissions: &Permissions::empty().to_string(),
+            created_by: None,
+        };
+        storage.create_document(doc).await.unwrap();
+    }
+
+    fn gzipped_tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let mut tar = tar::Builder::new(encoder);
+        for (path, data) in files {
+            tar_append(&mut tar, path, data, 0).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn export_and_import() {
+        let storage = MemoryStorage::new();
+        create_collection(&storage).await;
+        let archive = export_collection(&storage, "col").await.unwrap();
+
+        let options = ImportOptions {
+            id: Some("copy".to_string()),
+            remap_ids: true,
+        };
+        let limits = ImportLimits::default();
+        let col = import_collection(&storage, &archive, options, &limits)
+            .await
+            .unwrap();
+        assert_eq!((col.documents_count, col.size), (1, 3));
+    }
+
+    #[tokio::test]
+    async fn import_is_limited() {
+        let storage = MemoryStorage::new();
+        let large = vec![0; 10_000];
+        let archive = gzipped_tar(&[("a", &large), ("b", &large)]);
+
+        let limits = ImportLimits {
+            max_entry_size: 1000,
+            ..ImportLimits::default()
+        };
+        let res = import_collection(
+            &storage,
+            &archive,
+            ImportOptions::default(),
+            &limits,
+        )
+        .await;
+        assert!(res.is_err_and(|err| err.message.contains("entry")));
+
+        // Archive is small, but unpacks into large files
+        let limits = ImportLimits {
+            max_unpacked_size: 15_000,
+            ..ImportLimits::default()
+        };
+        assert!(archive.len() < 1000);
+        let res = import_collection(
+            &storage,
+            &archive,
+            ImportOptions::default(),
+            &limits,
+        )
+        .await;
+        assert!(res.is_err_and(|err| err.message == "Archive is too large"));
+    }
+
+    #[tokio::test]
+    async fn import_rejects_invalid_permissions() {
+        let storage = MemoryStorage::new();
+        create_collection(&storage).await;
+        let archive = export_collection(&storage, "col").await.unwrap();
+        let mut files = tar_read(&archive, &ImportLimits::default()).unwrap();
+        let mut manifest: Manifest =
+            serde_json::from_slice(&files[MANIFEST_PATH]).unwrap();
+        manifest.collection.permissions =
+            serde_json::json!({ "read": "everyone" });
+        files.insert(
+            MANIFEST_PATH.to_string(),
+            serde_json::to_vec(&manifest).unwrap(),
+        );
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(path, data)| (path.as_str(), data.as_slice()))
+            .collect();
+        let archive = gzipped_tar(&files);
+
+        let options = ImportOptions {
+            id: Some("copy".to_string()),
+            remap_ids: true,
+        };
+        let limits = ImportLimits::default();
+        let res = import_collection(&storage, &archive, options, &limits).await;
+        assert!(res.is_err_and(|err| err.message.contains("permissions")));
+        assert!(storage.get_collection("copy").await.is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::SinkronError;
+use crate::models;
+use crate::permissions::Permissions;
+use crate::storage::Storage;
+
+const PAGE_SIZE: i64 = 1000;
+
+#[derive(Serialize)]
+pub struct InvalidPermissions {
+    pub col: String,
+    // Not set when permissions of the collection itself are invalid
+    pub doc: Option<Uuid>,
+    pub error: String,
+}
+
+fn parse_error(permissions: &str) -> Option<String> {
+    serde_json::from_str::<Permissions>(permissions)
+        .err()
+        .map(|err| err.to_string())
+}
+
+// Finds collections and documents with permissions that couldn't be parsed
+pub async fn check_permissions(
+    storage: &dyn Storage,
+) -> Result<Vec<InvalidPermissions>, SinkronError> {
+    let mut report = Vec::new();
+
+    let mut last: Option<String> = None;
+    loop {
+        let cols = storage
+            .list_collections(None, last.as_deref(), PAGE_SIZE)
+            .await?;
+        let is_last = (cols.len() as i64) < PAGE_SIZE;
+        for col in cols {
+            if let Some(error) = parse_error(&col.permissions) {
+                report.push(InvalidPermissions {
+                    col: col.id.clone(),
+                    doc: None,
+                    error,
+                });
+            }
+            last = Some(col.id);
+        }
+        if is_last {
+            break;
+        }
+    }
+
+    let mut last: Option<Uuid> = None;
+    loop {
+        let docs = storage.list_documents_permissions(last, PAGE_SIZE).await?;
+        let is_last = (docs.len() as i64) < PAGE_SIZE;
+        for (id, col, permissions) in docs {
+            last = Some(id);
+            if let Some(error) = parse_error(&permissions) {
+                report.push(InvalidPermissions {
+                    col,
+                    doc: Some(id),
+                    error,
+                });
+            }
+        }
+        if is_last {
+            break;
+        }
+    }
+
+    Ok(report)
+}
+
+// Problem with the stored data found by the integrity check
+#[derive(Serialize)]
+pub struct Issue {
+    pub col: String,
+    // Not set when the issue is with the collection itself
+    pub doc: Option<Uuid>,
+    pub error: String,
+}
+
+fn check_document(
+    col: &models::Collection,
+    doc: &models::Document,
+) -> Option<String> {
+    if doc.colrev > col.colrev {
+        let msg = format!(
+            "Colrev {} is greater than colrev of the collection {}",
+            doc.colrev, col.colrev
+        );
+        return Some(msg);
+    }
+    match (&doc.data, doc.is_deleted) {
+        (None, false) => Some("Document has no data".to_string()),
+        (Some(_), true) => Some("Deleted document has data".to_string()),
+        (Some(data), false) => loro::LoroDoc::new()
+            .import(data)
+            .err()
+            .map(|err| format!("Invalid snapshot: {}", err)),
+        (None, true) => None,
+    }
+}
+
+async fn check_collection(
+    storage: &dyn Storage,
+    col: &models::Collection,
+    report: &mut Vec<Issue>,
+) -> Result<(), SinkronError> {
+    let mut documents_count = 0;
+    let mut size = 0;
+    let mut colrevs = HashSet::new();
+    let mut since = 0;
+    loop {
+        // Documents with colrev greater than colrev of the collection are
+        // also checked
+        let docs = storage
+            .get_documents_page(&col.id, since, i64::MAX, false, PAGE_SIZE)
+            .await?;
+        let Some(last) = docs.last() else {
+            break;
+        };
+        since = last.colrev;
+        for doc in docs {
+            if !doc.is_deleted {
+                documents_count += 1;
+            }
+            size += doc.data.as_ref().map_or(0, |data| data.len() as i64);
+            let error = if colrevs.insert(doc.colrev) {
+                check_document(col, &doc)
+            } else {
+                Some(format!("Duplicate colrev {}", doc.colrev))
+            };
+            if let Some(error) = error {
+                report.push(Issue {
+                    col: col.id.clone(),
+                    doc: Some(doc.id),
+                    error,
+                });
+            }
+        }
+    }
+    if documents_count != col.documents_count {
+        report.push(Issue {
+            col: col.id.clone(),
+            doc: None,
+            error: format!(
+                "Documents count is {}, actual count is {}",
+                col.documents_count, documents_count
+            ),
+        });
+    }
+    if size != col.size {
+        report.push(Issue {
+            col: col.id.clone(),
+            doc: None,
+            error: format!("Size is {}, actual size is {}", col.size, size),
+        });
+    }
+    Ok(())
+}
+
+// Checks consistency of the collections and their documents: usage counters,
+// colrevs, snapshots and permissions
+pub async fn check_integrity(
+    storage: &dyn Storage,
+) -> Result<Vec<Issue>, SinkronError> {
+    let mut report: Vec<Issue> = check_permissions(storage)
+        .await?
+        .into_iter()
+        .map(|item| Issue {
+            col: item.col,
+            doc: item.doc,
+            error: format!("Invalid permissions: {}", item.error),
+        })
+        .collect();
+
+    let mut last: Option<String> = None;
+    loop {
+        let cols = storage
+            .list_collections(None, last.as_deref(), PAGE_SIZE)
+            .await?;
+        let is_last = (cols.len() as i64) < PAGE_SIZE;
+        for col in cols {
+            check_collection(storage, &col, &mut report).await?;
+            last = Some(col.id);
+        }
+        if is_last {
+            break;
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,134 @@
+use std::io::Write;
+
+use flate2::write::DeflateEncoder;
+use serde::Deserialize;
+
+fn default_threshold() -> usize {
+    16 * 1024
+}
+
+fn default_batch_size() -> usize {
+    1024 * 1024
+}
+
+// Compression of the sync responses.
+//
+// When client connects with "compression=deflate" query param, server
+// groups documents sent during the sync into batches. Batch is sent as a
+// binary frame, containing JSON array of messages compressed with raw
+// DEFLATE (permessage-deflate is not supported by the websocket
+// implementation). Batches smaller than the threshold are not compressed and
+// messages are sent as usual text frames.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Minimal size of the batch to be compressed (in bytes)
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+    // Max size of the uncompressed batch (in bytes)
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: default_threshold(),
+            batch_size: default_batch_size(),
+        }
+    }
+}
+
+pub fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder =
+        DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Joins serialized messages into a JSON array
+pub fn join_batch(messages: &[String]) -> String {
+    let len = messages.iter().map(|m| m.len() + 1).sum::<usize>() + 1;
+    let mut batch = String::with_capacity(len);
+    batch.push('[');
+    for (i, msg) in messages.iter().enumerate() {
+        if i > 0 {
+            batch.push(',');
+        }
+        batch.push_str(msg);
+    }
+    batch.push(']');
+    batch
+}
+
+pub enum EncodedBatch {
+    // Messages are sent as separate text frames
+    Plain(Vec<String>),
+    // Deflated JSON array of the messages, sent as a binary frame
+    Compressed(Vec<u8>),
+}
+
+/// Compresses the batch when its size reaches the threshold
+pub fn encode_batch(
+    batch: Vec<String>,
+    size: usize,
+    threshold: usize,
+) -> std::io::Result<EncodedBatch> {
+    if size < threshold {
+        return Ok(EncodedBatch::Plain(batch));
+    }
+    let joined = join_batch(&batch);
+    drop(batch);
+    Ok(EncodedBatch::Compressed(deflate(joined.as_bytes())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+
+    fn inflate(data: &[u8]) -> String {
+        let mut text = String::new();
+        DeflateDecoder::new(data).read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn join_and_deflate_round_trip() {
+        assert_eq!(join_batch(&[]), "[]");
+        let messages = vec![
+            r#"{"kind":"doc","id":1}"#.to_string(),
+            r#"{"kind":"doc","id":2}"#.to_string(),
+        ];
+        let joined = join_batch(&messages);
+        let parsed: Vec<serde_json::Value> =
+            serde_json::from_str(&joined).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1]["id"], 2);
+
+        let compressed = deflate(joined.as_bytes()).unwrap();
+        assert_eq!(inflate(&compressed), joined);
+    }
+
+    #[test]
+    fn small_batch_is_not_compressed() {
+        let messages = vec![r#"{"kind":"h","i":1}"#.to_string()];
+        let size = messages[0].len();
+        match encode_batch(messages.clone(), size, size + 1).unwrap() {
+            EncodedBatch::Plain(plain) => assert_eq!(plain, messages),
+            EncodedBatch::Compressed(_) => panic!("batch is compressed"),
+        }
+        match encode_batch(messages.clone(), size, size).unwrap() {
+            EncodedBatch::Compressed(data) => {
+                assert_eq!(inflate(&data), join_batch(&messages))
+            }
+            EncodedBatch::Plain(_) => panic!("batch is not compressed"),
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use std::path::Path;
+
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use serde_json::{Map, Value};
+
+use crate::sinkron::SinkronConfig;
+
+// Config is assembled from the following sources, each next source overrides
+// values from the previous ones:
+//
+// - Config file, TOML when it has ".toml" extension, otherwise JSON
+// - JSON from the "SINKRON_CONFIG" env variable
+// - Env variables with "SINKRON_" prefix, nested keys are separated with "__"
+//   and converted to camelCase, e.g. "SINKRON_API_TOKEN" sets "apiToken" and
+//   "SINKRON_DB__POOL_SIZE" sets "db.poolSize"
+//
+// "DATABASE_URL" env variable sets "db.url" only when neither "db.url" nor
+// "db.host" is set by the other sources.
+//
+// Values of the env variables are kept as strings and converted when the
+// config field is a number or a boolean. JSON objects and arrays are parsed,
+// unless they replace a string.
+
+const PREFIX: &str = "SINKRON_";
+const SEPARATOR: &str = "__";
+const CONFIG_VAR: &str = "SINKRON_CONFIG";
+const DATABASE_URL_VAR: &str = "DATABASE_URL";
+// Variables with the prefix that are not config values
+const RESERVED_VARS: &[&str] = &[CONFIG_VAR, "SINKRON_CONFIG_FILE"];
+
+fn camel_case(key: &str) -> String {
+    let mut res = String::with_capacity(key.len());
+    for (i, part) in key.split('_').filter(|s| !s.is_empty()).enumerate() {
+        let part = part.to_lowercase();
+        let mut chars = part.chars();
+        if i > 0 {
+            if let Some(first) = chars.next() {
+                res.extend(first.to_uppercase());
+            }
+        }
+        res.extend(chars);
+    }
+    res
+}
+
+fn read_file(path: &Path) -> Result<Value, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+    let is_toml = path.extension().is_some_and(|ext| ext == "toml");
+    let res = if is_toml {
+        toml::from_str(&text).map_err(|err| err.to_string())
+    } else {
+        serde_json::from_str(&text).map_err(|err| err.to_string())
+    };
+    res.map_err(|err| format!("Couldn't parse {}:\n{}", path.display(), err))
+}
+
+// Recursively merges objects, other values are replaced
+fn merge(base: &mut Value, value: Value) {
+    match (base, value) {
+        (Value::Object(base), Value::Object(value)) => {
+            for (key, value) in value {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+// Returns value at the path, missing objects are created
+fn entry<'a>(config: &'a mut Value, path: &[String]) -> &'a mut Value {
+    let mut node = config;
+    for key in path {
+        if !node.is_object() {
+            *node = Value::Object(Map::new());
+        }
+        node = match node {
+            Value::Object(map) => map.entry(key.clone()).or_insert(Value::Null),
+            _ => unreachable!(),
+        };
+    }
+    node
+}
+
+// Deserializes config values, strings are converted when the field is a
+// number or a boolean, so env variables don't have to know the field types
+struct ConfigValue(Value);
+
+impl<'de> IntoDeserializer<'de, serde_json::Error> for ConfigValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident)*) => {$(
+        fn $method<V: Visitor<'de>>(
+            self,
+            visitor: V,
+        ) -> Result<V::Value, serde_json::Error> {
+            match self.0 {
+                Value::String(raw) => match serde_json::from_str(&raw) {
+                    Ok(Value::Number(num)) => Value::Number(num).$method(visitor),
+                    Ok(Value::Bool(b)) => Value::Bool(b).$method(visitor),
+                    _ => Value::String(raw).$method(visitor),
+                },
+                value => value.$method(visitor),
+            }
+        }
+    )*};
+}
+
+impl<'de> serde::Deserializer<'de> for ConfigValue {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, serde_json::Error> {
+        match self.0 {
+            Value::Object(map) => {
+                let entries = map.into_iter().map(|(k, v)| (k, ConfigValue(v)));
+                let mut de = MapDeserializer::new(entries);
+                let res = visitor.visit_map(&mut de)?;
+                de.end()?;
+                Ok(res)
+            }
+            Value::Array(items) => {
+                let mut de =
+                    SeqDeserializer::new(items.into_iter().map(ConfigValue));
+                let res = visitor.visit_seq(&mut de)?;
+                de.end()?;
+                Ok(res)
+            }
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, serde_json::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, serde_json::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, serde_json::Error> {
+        self.0.deserialize_enum(name, variants, visitor)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32
+        deserialize_i64 deserialize_u8 deserialize_u16 deserialize_u32
+        deserialize_u64 deserialize_f32 deserialize_f64
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+fn apply_env(
+    config: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> bool {
+    let mut is_set = false;
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(PREFIX) else {
+            continue;
+        };
+        if RESERVED_VARS.contains(&name.as_str()) {
+            continue;
+        }
+        let path: Vec<String> = key.split(SEPARATOR).map(camel_case).collect();
+        if path.iter().any(String::is_empty) {
+            continue;
+        }
+        let node = entry(config, &path);
+        *node = match serde_json::from_str::<Value>(&raw) {
+            // Quoted strings are still accepted, e.g. '"1234"'
+            Ok(value @ Value::String(_)) => value,
+            Ok(value @ (Value::Object(_) | Value::Array(_)))
+                if !node.is_string() =>
+            {
+                value
+            }
+            _ => Value::String(raw),
+        };
+        is_set = true;
+    }
+    is_set
+}
+
+// Sets "db.url" from the "DATABASE_URL", unless connection is configured
+fn apply_database_url(config: &mut Value, vars: &[(String, String)]) {
+    let Some((_, url)) = vars.iter().find(|(name, _)| name == DATABASE_URL_VAR)
+    else {
+        return;
+    };
+    let db = &config["db"];
+    if !db["url"].is_null() || !db["host"].is_null() {
+        return;
+    }
+    let path = ["db".to_string(), "url".to_string()];
+    *entry(config, &path) = Value::String(url.clone());
+}
+
+fn build_config(
+    path: Option<&Path>,
+    vars: Vec<(String, String)>,
+) -> Result<SinkronConfig, String> {
+    let mut config = Value::Object(Map::new());
+    let mut is_found = false;
+
+    if let Some(path) = path {
+        merge(&mut config, read_file(path)?);
+        is_found = true;
+    }
+
+    let json = vars.iter().find(|(name, _)| name == CONFIG_VAR);
+    if let Some((_, json)) = json {
+        let value = serde_json::from_str(json).map_err(|err| {
+            format!("Couldn't parse \"{}\":\n{}", CONFIG_VAR, err)
+        })?;
+        merge(&mut config, value);
+        is_found = true;
+    }
+
+    // Variables are applied in the sorted order, so nested keys override
+    // objects that contain them
+    let mut vars = vars;
+    vars.sort();
+    if apply_env(&mut config, vars.iter().cloned()) {
+        is_found = true;
+    }
+    apply_database_url(&mut config, &vars);
+
+    if !is_found {
+        return Err(format!(
+            "Config not found! Pass config file or set env variable \"{}\"",
+            CONFIG_VAR
+        ));
+    }
+    serde::Deserialize::deserialize(ConfigValue(config))
+        .map_err(|err| format!("Invalid config: {}", err))
+}
+
+// Loads config from the file and environment
+pub fn load_config(path: Option<&Path>) -> Result<SinkronConfig, String> {
+    build_config(path, std::env::vars().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn write_file(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "sinkron-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn sources_override_previous_ones() {
+        let path = write_file(
+            "config.toml",
+            "apiToken = \"file\"\nport = 80\n[db]\npoolSize = 5\n",
+        );
+        let config = build_config(
+            Some(&path),
+            vars(&[
+                ("SINKRON_CONFIG", r#"{"apiToken": "json", "port": 81}"#),
+                ("SINKRON_PORT", "82"),
+                ("SINKRON_DB__POOL_SIZE", "6"),
+            ]),
+        )
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(config.api_token, "json");
+        assert_eq!(config.port, 82);
+        assert_eq!(config.db.unwrap().pool_size, 6);
+    }
+
+    #[test]
+    fn env_values_are_converted_to_field_types() {
+        let config = build_config(
+            None,
+            vars(&[
+                ("SINKRON_API_TOKEN", "1234"),
+                ("SINKRON_PORT", "3000"),
+                ("SINKRON_DB__PASSWORD", "true"),
+                ("SINKRON_DB__STATEMENT_TIMEOUT", "500"),
+                ("SINKRON_DB__USER", r#""5678""#),
+                ("SINKRON_COLLECTION", r#"{"maxDocumentSize": 10}"#),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.api_token, "1234");
+        assert_eq!(config.port, 3000);
+        let db = config.db.unwrap();
+        assert_eq!(db.password, "true");
+        assert_eq!(db.statement_timeout, Some(500));
+        assert_eq!(db.user, "5678");
+        assert_eq!(config.collection.max_document_size, Some(10));
+
+        let res = build_config(
+            None,
+            vars(&[("SINKRON_API_TOKEN", "token"), ("SINKRON_PORT", "abc")]),
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn database_url_is_fallback() {
+        let url = "postgres://localhost/db";
+        let config = build_config(
+            None,
+            vars(&[("SINKRON_API_TOKEN", "token"), ("DATABASE_URL", url)]),
+        )
+        .unwrap();
+        assert_eq!(config.db.unwrap().url.as_deref(), Some(url));
+
+        let config = build_config(
+            None,
+            vars(&[
+                ("SINKRON_API_TOKEN", "token"),
+                ("SINKRON_DB__HOST", "db"),
+                ("DATABASE_URL", url),
+            ]),
+        )
+        .unwrap();
+        let db = config.db.unwrap();
+        assert_eq!(db.url, None);
+        assert_eq!(db.host, "db");
+
+        let config = build_config(
+            None,
+            vars(&[
+                ("SINKRON_API_TOKEN", "token"),
+                ("SINKRON_DB__URL", "postgres://other/db"),
+                ("DATABASE_URL", url),
+            ]),
+        )
+        .unwrap();
+        let url = config.db.unwrap().url;
+        assert_eq!(url.as_deref(), Some("postgres://other/db"));
+    }
+}
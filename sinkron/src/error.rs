@@ -39,6 +39,24 @@ impl SinkronError {
             message: msg.to_string(),
         }
     }
+    pub fn payload_too_large(msg: &str) -> Self {
+        Self {
+            code: ErrorCode::PayloadTooLarge,
+            message: msg.to_string(),
+        }
+    }
+    pub fn quota_exceeded(msg: &str) -> Self {
+        Self {
+            code: ErrorCode::QuotaExceeded,
+            message: msg.to_string(),
+        }
+    }
+    pub fn resync_required(msg: &str) -> Self {
+        Self {
+            code: ErrorCode::ResyncRequired,
+            message: msg.to_string(),
+        }
+    }
     pub fn internal(msg: &str) -> Self {
         Self {
             code: ErrorCode::InternalServerError,
@@ -55,3 +73,10 @@ where
     error!("internal error: {:?}", err);
     SinkronError::internal(&err.to_string())
 }
+
+// Allows to use `SinkronError` as an error type of the database transactions
+impl From<diesel::result::Error> for SinkronError {
+    fn from(err: diesel::result::Error) -> Self {
+        internal_error(err)
+    }
+}
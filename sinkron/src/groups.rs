@@ -1,16 +1,15 @@
+use std::collections::HashSet;
 use std::num::NonZeroUsize;
+use std::sync::Arc;
 
-use diesel::prelude::*;
-use diesel_async::RunQueryDsl;
 use lru::LruCache;
 use serde::Deserialize;
 use tokio::sync::Mutex;
 
-use crate::db;
-use crate::error::{internal_error, SinkronError};
+use crate::error::SinkronError;
 use crate::models;
-use crate::schema;
-use crate::types::{Group, User};
+use crate::storage::Storage;
+use crate::types::{page_limit, Group, Page, User};
 
 #[derive(Deserialize)]
 pub struct AddRemoveUserToGroup {
@@ -18,35 +17,116 @@ pub struct AddRemoveUserToGroup {
     pub group: String,
 }
 
+#[derive(Deserialize)]
+pub struct AddRemoveUsersToGroup {
+    pub users: Vec<String>,
+    pub group: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateGroup {
+    pub id: String,
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateGroupMetadata {
+    pub id: String,
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct AddRemoveGroupToGroup {
+    // Group that is included into the parent group
+    pub group: String,
+    pub parent: String,
+}
+
+#[derive(Deserialize)]
+pub struct ListGroups {
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ListGroupMembers {
+    pub group: String,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
 pub struct GroupsApi {
-    pool: db::DbConnectionPool,
+    storage: Arc<dyn Storage>,
     cache: Mutex<LruCache<String, User>>,
+    // Serializes changes of the groups hierarchy, so concurrent inclusions
+    // can't create a cycle after both passed the check
+    hierarchy: Mutex<()>,
 }
 
 impl GroupsApi {
-    pub fn new(pool: db::DbConnectionPool) -> Self {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
         Self {
-            pool,
+            storage,
             cache: Mutex::new(LruCache::new(NonZeroUsize::new(5000).unwrap())),
+            hierarchy: Mutex::new(()),
         }
     }
 
-    async fn connect(&self) -> Result<db::DbConnection, SinkronError> {
-        self.pool.get().await.map_err(internal_error)
+    // Returns all groups that include any of the `groups`, directly or
+    // through other groups
+    async fn get_ancestors(
+        &self,
+        groups: &[String],
+    ) -> Result<HashSet<String>, SinkronError> {
+        let mut result = HashSet::new();
+        let mut next = groups.to_vec();
+        while !next.is_empty() {
+            let parents = self.storage.get_parent_groups(&next).await?;
+            // Groups that were already visited are skipped, so this
+            // terminates even if the hierarchy somehow contains a cycle
+            next = parents
+                .into_iter()
+                .filter(|group| result.insert(group.clone()))
+                .collect();
+        }
+        Ok(result)
     }
 
-    async fn group_exists(
+    // Returns all groups that are included into the `group`, directly or
+    // through other groups
+    async fn get_descendants(
         &self,
-        conn: &mut db::DbConnection,
-        id: &str,
-    ) -> Result<bool, SinkronError> {
-        let cnt: i64 = schema::groups::table
-            .filter(schema::groups::id.eq(&id))
-            .count()
-            .get_result(conn)
-            .await
-            .map_err(internal_error)?;
-        Ok(cnt != 0)
+        group: &str,
+    ) -> Result<HashSet<String>, SinkronError> {
+        let mut result = HashSet::new();
+        let mut next = vec![group.to_string()];
+        while !next.is_empty() {
+            let children = self.storage.get_child_groups(&next).await?;
+            next = children
+                .into_iter()
+                .filter(|group| result.insert(group.clone()))
+                .collect();
+        }
+        Ok(result)
+    }
+
+    // Returns all users whose groups depend on the `group`, i.e. members
+    // of the group and of all groups included into it
+    async fn get_affected_users(
+        &self,
+        group: &str,
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut groups = self.get_descendants(group).await?;
+        groups.insert(group.to_string());
+        let groups: Vec<String> = groups.into_iter().collect();
+        self.storage.get_members(&groups).await
+    }
+
+    async fn invalidate_group(&self, group: &str) -> Result<(), SinkronError> {
+        let users = self.get_affected_users(group).await?;
+        self.remove_users_from_cache(&users).await;
+        Ok(())
     }
 
     async fn get_user_from_cache(&self, id: &str) -> Option<User> {
@@ -64,17 +144,24 @@ impl GroupsApi {
         cache.pop(id);
     }
 
+    async fn remove_users_from_cache(&self, ids: &[String]) {
+        let mut cache = self.cache.lock().await;
+        for id in ids {
+            cache.pop(id);
+        }
+    }
+
     pub async fn get_user(&self, id: String) -> Result<User, SinkronError> {
         if let Some(user) = self.get_user_from_cache(&id).await {
             return Ok(user);
         }
-        let mut conn = self.connect().await?;
-        let groups: Vec<String> = schema::members::table
-            .filter(schema::members::user.eq(&id))
-            .select(schema::members::group)
-            .get_results(&mut conn)
-            .await
-            .map_err(internal_error)?;
+        let mut groups = self.storage.get_user_groups(&id).await?;
+        let inherited = self.get_ancestors(&groups).await?;
+        for group in inherited {
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
         let user = User {
             id: id.clone(),
             groups,
@@ -84,49 +171,78 @@ impl GroupsApi {
     }
 
     pub async fn get_group(&self, id: String) -> Result<Group, SinkronError> {
-        let mut conn = self.connect().await?;
-        let exists = self.group_exists(&mut conn, &id).await?;
-        if !exists {
-            return Err(SinkronError::not_found("Group not found"));
-        }
-        let members: Vec<String> = schema::members::table
-            .filter(schema::members::group.eq(&id))
-            .select(schema::members::user)
-            .get_results(&mut conn)
-            .await
-            .map_err(internal_error)?;
-        Ok(Group { id, members })
+        let group = self.storage.get_group(&id).await?;
+        let ids = [id.clone()];
+        let members = self.storage.get_members(&ids).await?;
+        let subgroups = self.storage.get_child_groups(&ids).await?;
+        Ok(Group {
+            id,
+            members,
+            subgroups,
+            metadata: group
+                .metadata
+                .and_then(|m| serde_json::from_str(&m).ok()),
+        })
+    }
+
+    pub async fn list_groups(
+        &self,
+        props: ListGroups,
+    ) -> Result<Page<String>, SinkronError> {
+        let ListGroups { cursor, limit } = props;
+        let limit = page_limit(limit);
+        let groups = self.storage.list_groups(cursor.as_deref(), limit).await?;
+        Ok(Page::new(groups, limit, String::clone))
     }
 
-    pub async fn create_group(&self, id: String) -> Result<(), SinkronError> {
-        let mut conn = self.connect().await?;
-        let new_group = models::Group { id };
-        let _ = diesel::insert_into(schema::groups::table)
-            .values(&new_group)
-            .execute(&mut conn)
+    pub async fn list_group_members(
+        &self,
+        props: ListGroupMembers,
+    ) -> Result<Page<String>, SinkronError> {
+        let ListGroupMembers {
+            group,
+            cursor,
+            limit,
+        } = props;
+        let limit = page_limit(limit);
+        self.storage.get_group(&group).await?;
+        let members = self
+            .storage
+            .list_group_members(&group, cursor.as_deref(), limit)
+            .await?;
+        Ok(Page::new(members, limit, String::clone))
+    }
+
+    pub async fn create_group(
+        &self,
+        props: CreateGroup,
+    ) -> Result<(), SinkronError> {
+        let CreateGroup { id, metadata } = props;
+        let new_group = models::Group {
+            id,
+            metadata: metadata.map(|m| m.to_string()),
+        };
+        self.storage.create_group(new_group).await
+    }
+
+    pub async fn update_group_metadata(
+        &self,
+        props: UpdateGroupMetadata,
+    ) -> Result<(), SinkronError> {
+        let UpdateGroupMetadata { id, metadata } = props;
+        self.storage
+            .update_group_metadata(&id, metadata.map(|m| m.to_string()))
             .await
-            .map_err(internal_error)?;
-        Ok(())
     }
 
+    // Deletes the group together with its memberships and inclusions into
+    // other groups
     pub async fn delete_group(&self, id: String) -> Result<(), SinkronError> {
-        let mut conn = self.connect().await?;
-        let _ = diesel::delete(schema::members::table)
-            .filter(schema::members::group.eq(&id))
-            .execute(&mut conn)
-            .await
-            .map_err(internal_error)?;
-        // TODO invalidate users that were members of the group
-        let num = diesel::delete(schema::groups::table)
-            .filter(schema::groups::id.eq(&id))
-            .execute(&mut conn)
-            .await
-            .map_err(internal_error)?;
-        if num == 0 {
-            Err(SinkronError::not_found("Group not found"))
-        } else {
-            Ok(())
-        }
+        let users = self.storage.delete_group(&id).await?;
+        // Cache is cleared after the commit, so users can't be loaded again
+        // with the deleted group
+        self.remove_users_from_cache(&users).await;
+        Ok(())
     }
 
     pub async fn add_user_to_group(
@@ -134,20 +250,9 @@ impl GroupsApi {
         props: AddRemoveUserToGroup,
     ) -> Result<(), SinkronError> {
         let AddRemoveUserToGroup { user, group } = props;
-        let mut conn = self.connect().await?;
-        let exists = self.group_exists(&mut conn, &group).await?;
-        if !exists {
-            return Err(SinkronError::not_found("Group not found"));
-        }
-        let new_member = models::Member {
-            user: user.clone(),
-            group,
-        };
-        let _ = diesel::insert_into(schema::members::table)
-            .values(&new_member)
-            .execute(&mut conn)
-            .await
-            .map_err(internal_error)?;
+        self.storage
+            .add_members(&group, std::slice::from_ref(&user))
+            .await?;
         self.remove_user_from_cache(&user).await;
         Ok(())
     }
@@ -157,13 +262,10 @@ impl GroupsApi {
         props: AddRemoveUserToGroup,
     ) -> Result<(), SinkronError> {
         let AddRemoveUserToGroup { user, group } = props;
-        let mut conn = self.connect().await?;
-        let num = diesel::delete(schema::members::table)
-            .filter(schema::members::user.eq(&user))
-            .filter(schema::members::group.eq(&group))
-            .execute(&mut conn)
-            .await
-            .map_err(internal_error)?;
+        let num = self
+            .storage
+            .remove_members(&group, std::slice::from_ref(&user))
+            .await?;
         if num == 0 {
             Err(SinkronError::not_found("Group member not found"))
         } else {
@@ -172,17 +274,150 @@ impl GroupsApi {
         }
     }
 
+    pub async fn add_group_to_group(
+        &self,
+        props: AddRemoveGroupToGroup,
+    ) -> Result<(), SinkronError> {
+        let AddRemoveGroupToGroup { group, parent } = props;
+        for id in [&group, &parent] {
+            self.storage.get_group(id).await?;
+        }
+        let _lock = self.hierarchy.lock().await;
+        // Group can't include itself or any group that already includes it
+        let descendants = self.get_descendants(&group).await?;
+        if group == parent || descendants.contains(&parent) {
+            return Err(SinkronError::unprocessable(
+                "Group can't be included into itself",
+            ));
+        }
+        self.storage.add_subgroup(&parent, &group).await?;
+        self.invalidate_group(&group).await?;
+        Ok(())
+    }
+
+    pub async fn remove_group_from_group(
+        &self,
+        props: AddRemoveGroupToGroup,
+    ) -> Result<(), SinkronError> {
+        let AddRemoveGroupToGroup { group, parent } = props;
+        let num = self.storage.remove_subgroup(&parent, &group).await?;
+        if num == 0 {
+            Err(SinkronError::not_found("Subgroup not found"))
+        } else {
+            self.invalidate_group(&group).await?;
+            Ok(())
+        }
+    }
+
+    // Adds users to the group, users that are already members of the group
+    // are skipped
+    pub async fn add_users_to_group(
+        &self,
+        props: AddRemoveUsersToGroup,
+    ) -> Result<(), SinkronError> {
+        let AddRemoveUsersToGroup { users, group } = props;
+        self.storage.add_members(&group, &users).await?;
+        self.remove_users_from_cache(&users).await;
+        Ok(())
+    }
+
+    // Removes users from the group, users that are not members of the group
+    // are skipped
+    pub async fn remove_users_from_group(
+        &self,
+        props: AddRemoveUsersToGroup,
+    ) -> Result<(), SinkronError> {
+        let AddRemoveUsersToGroup { users, group } = props;
+        self.storage.remove_members(&group, &users).await?;
+        self.remove_users_from_cache(&users).await;
+        Ok(())
+    }
+
     pub async fn remove_user_from_all_groups(
         &self,
         id: String,
     ) -> Result<(), SinkronError> {
-        let mut conn = self.connect().await?;
-        let _ = diesel::delete(schema::members::table)
-            .filter(schema::members::user.eq(&id))
-            .execute(&mut conn)
-            .await
-            .map_err(internal_error)?;
+        self.storage.remove_user_from_all_groups(&id).await?;
         self.remove_user_from_cache(&id).await;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+    use crate::storage::MemoryStorage;
+
+    async fn groups_api(groups: &[&str]) -> GroupsApi {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let api = GroupsApi::new(storage);
+        for id in groups {
+            let props = CreateGroup {
+                id: id.to_string(),
+                metadata: None,
+            };
+            api.create_group(props).await.unwrap();
+        }
+        api
+    }
+
+    async fn include(
+        api: &GroupsApi,
+        group: &str,
+        parent: &str,
+    ) -> Result<(), SinkronError> {
+        let props = AddRemoveGroupToGroup {
+            group: group.to_string(),
+            parent: parent.to_string(),
+        };
+        api.add_group_to_group(props).await
+    }
+
+    #[tokio::test]
+    async fn direct_cycle_is_rejected() {
+        let api = groups_api(&["a", "b"]).await;
+        let err = include(&api, "a", "a").await.err().unwrap();
+        assert!(matches!(err.code, ErrorCode::UnprocessableContent));
+
+        include(&api, "a", "b").await.unwrap();
+        let err = include(&api, "b", "a").await.err().unwrap();
+        assert!(matches!(err.code, ErrorCode::UnprocessableContent));
+    }
+
+    #[tokio::test]
+    async fn indirect_cycle_is_rejected() {
+        let api = groups_api(&["a", "b", "c"]).await;
+        include(&api, "a", "b").await.unwrap();
+        include(&api, "b", "c").await.unwrap();
+        let err = include(&api, "c", "a").await.err().unwrap();
+        assert!(matches!(err.code, ErrorCode::UnprocessableContent));
+        // Group can be included through several paths
+        assert!(include(&api, "a", "c").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn user_groups_are_resolved_transitively() {
+        let api = groups_api(&["a", "b", "c"]).await;
+        let props = AddRemoveUserToGroup {
+            user: "user".to_string(),
+            group: "a".to_string(),
+        };
+        api.add_user_to_group(props).await.unwrap();
+        include(&api, "a", "b").await.unwrap();
+        include(&api, "b", "c").await.unwrap();
+
+        let mut groups = api.get_user("user".to_string()).await.unwrap().groups;
+        groups.sort();
+        assert_eq!(groups, vec!["a", "b", "c"]);
+
+        // Removing the inclusion invalidates cached groups of the user
+        let props = AddRemoveGroupToGroup {
+            group: "a".to_string(),
+            parent: "b".to_string(),
+        };
+        api.remove_group_from_group(props).await.unwrap();
+        let groups = api.get_user("user".to_string()).await.unwrap().groups;
+        assert_eq!(groups, vec!["a"]);
+    }
+}
@@ -0,0 +1,30 @@
+mod actors;
+mod archive;
+mod check;
+mod compression;
+mod config;
+mod db;
+mod error;
+mod groups;
+pub mod models;
+pub mod permissions;
+pub mod protocol;
+mod rate_limit;
+mod schema;
+mod sinkron;
+pub mod storage;
+mod tls;
+mod types;
+
+pub use archive::{
+    export_collection, import_collection, ImportLimits, ImportOptions,
+};
+pub use check::{
+    check_integrity, check_permissions, InvalidPermissions, Issue,
+};
+pub use config::load_config;
+pub use db::{create_pool, DbConfig, DbConnectionPool};
+pub use error::SinkronError;
+pub use protocol::ErrorCode;
+pub use sinkron::{AuthFuture, Sinkron, SinkronBuilder, SinkronConfig};
+pub use tls::TlsConfig;
@@ -1,34 +1,523 @@
-mod actors;
-mod db;
-mod error;
-mod groups;
-mod models;
-mod permissions;
-mod protocol;
-mod schema;
-mod sinkron;
-mod types;
-
-use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() {
-    env_logger::init();
+use clap::{Parser, Subcommand, ValueEnum};
+use uuid::Uuid;
+
+use sinkron::permissions::{Permissions, Role};
+use sinkron::storage::{self, Storage};
+
+/// Sinkron server. All commands use the storage from the config.
+///
+/// Config is read from the file and the "SINKRON_CONFIG" env variable
+/// (JSON), values can be overridden with env variables, e.g.
+/// "SINKRON_API_TOKEN" or "SINKRON_DB__PASSWORD".
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Path to the config file, TOML or JSON
+    #[arg(long, global = true, env = "SINKRON_CONFIG_FILE")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (default command)
+    Serve {
+        /// Don't run migrations before start
+        #[arg(long)]
+        no_migrate: bool,
+    },
+    /// Manage migrations of the storage schema (default: up)
+    Migrate {
+        #[command(subcommand)]
+        command: Option<MigrateCommand>,
+    },
+    /// Create collection
+    CreateCollection {
+        id: String,
+        /// Permissions as JSON, by default nobody has access
+        #[arg(long)]
+        permissions: Option<String>,
+        /// Max total size of the documents in bytes
+        #[arg(long)]
+        quota: Option<i64>,
+        #[arg(long)]
+        is_ref: bool,
+    },
+    /// Allow role to perform actions on the collection or document
+    ///
+    /// When the server is running, changes have to be sent through its api
+    /// with "--server".
+    Grant(PermissionsArgs),
+    /// Remove role from the allowed roles of the collection or document
+    ///
+    /// When the server is running, changes have to be sent through its api
+    /// with "--server".
+    Revoke(PermissionsArgs),
+    /// Export collection to the archive
+    #[command(alias = "export-collection")]
+    Export { id: String, file: String },
+    /// Import collection from the archive
+    #[command(alias = "import-collection")]
+    Import {
+        file: String,
+        /// Id of the new collection, by default id from the archive is used
+        id: Option<String>,
+        /// Generate new ids for the documents
+        #[arg(long)]
+        remap_ids: bool,
+    },
+    /// Remove deleted documents
+    ///
+    /// Clients that were offline for longer than the given period have to
+    /// sync the collection from the start.
+    Gc {
+        /// Remove documents that were deleted more than DAYS ago
+        #[arg(long, value_name = "DAYS")]
+        older_than: u32,
+    },
+    /// Check integrity of the stored data
+    ///
+    /// Found issues are printed as JSON lines, exits with error when there
+    /// are any.
+    Check {
+        /// Only check that permissions are valid
+        #[arg(long)]
+        permissions_only: bool,
+    },
+    /// Same as "check --permissions-only"
+    #[command(hide = true)]
+    CheckPermissions,
+}
+
+#[derive(Subcommand)]
+enum MigrateCommand {
+    /// Apply pending migrations
+    Up,
+    /// Revert applied migrations
+    Down {
+        /// Number of migrations to revert
+        #[arg(long, default_value_t = 1)]
+        steps: u32,
+    },
+    /// List migrations
+    Status,
+}
+
+#[derive(clap::Args)]
+struct PermissionsArgs {
+    col: String,
+    /// "any", "owner", "user:<id>" or "group:<id>"
+    #[arg(value_parser = parse_role)]
+    role: Role,
+    #[arg(required = true, value_enum)]
+    actions: Vec<Action>,
+    /// Change permissions of the document instead of the collection
+    #[arg(long)]
+    doc: Option<Uuid>,
+    /// Url of the running server, e.g. "http://localhost:3000". Changes
+    /// are sent to its api, so they are applied to the loaded collections
+    /// and connected clients right away.
+    #[arg(long, env = "SINKRON_SERVER_URL")]
+    server: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum Action {
+    Read,
+    Create,
+    Update,
+    Delete,
+    Share,
+}
+
+fn parse_role(input: &str) -> Result<Role, String> {
+    match input.split_once(':') {
+        None if input == "any" => Ok(Role::Any),
+        None if input == "owner" => Ok(Role::Owner),
+        Some(("user", id)) if !id.is_empty() => {
+            Ok(Role::User { id: id.to_string() })
+        }
+        Some(("group", id)) if !id.is_empty() => {
+            Ok(Role::Group { id: id.to_string() })
+        }
+        _ => Err(format!("invalid role \"{}\"", input)),
+    }
+}
+
+fn roles(permissions: &mut Permissions, action: Action) -> &mut Vec<Role> {
+    match action {
+        Action::Read => &mut permissions.read,
+        Action::Create => &mut permissions.create,
+        Action::Update => &mut permissions.update,
+        Action::Delete => &mut permissions.delete,
+        Action::Share => &mut permissions.share,
+    }
+}
+
+fn denied_roles(
+    permissions: &mut Permissions,
+    action: Action,
+) -> &mut Vec<Role> {
+    let deny = &mut permissions.deny;
+    match action {
+        Action::Read => &mut deny.read,
+        Action::Create => &mut deny.create,
+        Action::Update => &mut deny.update,
+        Action::Delete => &mut deny.delete,
+        Action::Share => &mut deny.share,
+    }
+}
+
+// Granted role is also removed from the denied roles, otherwise it would
+// have no effect
+fn grant(permissions: &mut Permissions, role: &Role, actions: &[Action]) {
+    for action in actions {
+        let list = roles(permissions, *action);
+        if !list.contains(role) {
+            list.push(role.clone());
+        }
+        denied_roles(permissions, *action).retain(|item| item != role);
+    }
+}
+
+fn revoke(permissions: &mut Permissions, role: &Role, actions: &[Action]) {
+    for action in actions {
+        roles(permissions, *action).retain(|item| item != role);
+    }
+}
+
+fn parse_permissions(input: &str) -> Result<Permissions, String> {
+    serde_json::from_str(input)
+        .map_err(|err| format!("Invalid permissions: {}", err))
+}
 
-    let Ok(config_str) = &env::var("SINKRON_CONFIG") else {
-        log::error!("Config not found! Set env variable \"SINKRON_CONFIG\"");
-        return;
+async fn open_storage(
+    config: sinkron::SinkronConfig,
+) -> Result<Arc<dyn Storage>, String> {
+    storage::create_storage(config.storage, config.db)
+        .await
+        .map_err(|err| format!("Couldn't open storage: {}", err))
+}
+
+async fn migrate(
+    storage: &dyn Storage,
+    command: MigrateCommand,
+) -> Result<(), String> {
+    match command {
+        MigrateCommand::Up => {
+            storage
+                .migrate()
+                .await
+                .map_err(|err| format!("Couldn't migrate: {:?}", err))?;
+            println!("Storage is migrated");
+        }
+        MigrateCommand::Down { steps } => {
+            for _ in 0..steps {
+                let reverted =
+                    storage.revert_migration().await.map_err(|err| {
+                        format!("Couldn't revert migration: {:?}", err)
+                    })?;
+                match reverted {
+                    Some(name) => println!("Reverted {}", name),
+                    None => {
+                        println!("No applied migrations");
+                        break;
+                    }
+                }
+            }
+        }
+        MigrateCommand::Status => {
+            let status = storage
+                .migration_status()
+                .await
+                .map_err(|err| format!("Couldn't get migrations: {:?}", err))?;
+            for migration in status {
+                let mark = if migration.is_applied { "x" } else { " " };
+                println!("[{}] {}", mark, migration.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Checks if the server from the config accepts connections
+async fn is_server_running(config: &sinkron::SinkronConfig) -> bool {
+    let host = match config.host.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        host => host,
+    };
+    let addr = format!("{}:{}", host, config.port);
+    let connect = tokio::net::TcpStream::connect(addr);
+    matches!(
+        tokio::time::timeout(std::time::Duration::from_secs(1), connect).await,
+        Ok(Ok(_))
+    )
+}
+
+// Sends new permissions to the api of the running server
+async fn send_permissions(
+    server: &str,
+    api_token: &str,
+    col: &str,
+    doc: Option<Uuid>,
+    permissions: &Permissions,
+) -> Result<(), String> {
+    let (path, body) = match doc {
+        Some(id) => (
+            "update_document_permissions",
+            serde_json::json!({
+                "id": id,
+                "col": col,
+                "permissions": permissions,
+            }),
+        ),
+        None => (
+            "update_collection_permissions",
+            serde_json::json!({ "id": col, "permissions": permissions }),
+        ),
+    };
+    let url = format!("{}/{}", server.trim_end_matches('/'), path);
+    let res = reqwest::Client::new()
+        .post(&url)
+        .header("x-sinkron-api-token", api_token)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|err| format!("Couldn't send to {}: {}", url, err))?;
+    if !res.status().is_success() {
+        let text = res.text().await.unwrap_or_default();
+        return Err(format!("Server rejected the change: {}", text));
+    }
+    Ok(())
+}
+
+async fn update_permissions(
+    config: sinkron::SinkronConfig,
+    args: PermissionsArgs,
+    update: fn(&mut Permissions, &Role, &[Action]),
+) -> Result<(), String> {
+    let PermissionsArgs {
+        col,
+        role,
+        actions,
+        doc,
+        server,
+    } = args;
+    // Running server keeps permissions of the loaded collections in memory,
+    // so writing to the storage directly would have no effect on them
+    if server.is_none() && is_server_running(&config).await {
+        return Err(format!(
+            "Server is running on port {}, use \"--server\" to send \
+            changes through its api",
+            config.port
+        ));
+    }
+    let api_token = config.api_token.clone();
+    let storage = open_storage(config).await?;
+    let err = |err: sinkron::SinkronError| err.message;
+    let mut permissions = match doc {
+        Some(id) => {
+            storage
+                .get_document(&col, id)
+                .await
+                .map_err(err)?
+                .permissions
+        }
+        None => storage.get_collection(&col).await.map_err(err)?.permissions,
     };
+    let mut parsed = parse_permissions(&permissions)?;
+    update(&mut parsed, &role, &actions);
+    permissions = parsed.to_string();
+    if let Some(server) = server {
+        send_permissions(&server, &api_token, &col, doc, &parsed).await?;
+        println!("{}", permissions);
+        return Ok(());
+    }
+    match doc {
+        Some(id) => {
+            let usage =
+                storage.increment_colrev(&col, 0, 0).await.map_err(err)?;
+            storage
+                .update_document_permissions(
+                    &col,
+                    id,
+                    &permissions,
+                    usage.colrev,
+                )
+                .await
+                .map_err(err)?
+        }
+        None => storage
+            .update_collection_permissions(&col, &permissions)
+            .await
+            .map_err(err)?,
+    }
+    println!("{}", permissions);
+    Ok(())
+}
+
+fn print_report<T: serde::Serialize>(report: &[T]) {
+    for item in report {
+        if let Ok(line) = serde_json::to_string(item) {
+            println!("{}", line);
+        }
+    }
+}
 
-    let config =
-        match serde_json::from_str::<sinkron::SinkronConfig>(config_str) {
-            Ok(config) => config,
-            Err(err) => {
-                log::error!("Error parsing config:\n{}\n\n{}", err, config_str);
-                return;
+async fn run(
+    command: Command,
+    config: sinkron::SinkronConfig,
+) -> Result<(), String> {
+    match command {
+        Command::Serve { no_migrate } => {
+            let sinkron = sinkron::Sinkron::new(config)
+                .await
+                .map_err(|err| format!("Couldn't start: {}", err))?;
+            if !no_migrate {
+                sinkron
+                    .migrate()
+                    .await
+                    .map_err(|err| format!("Couldn't migrate: {:?}", err))?;
             }
-        };
+            sinkron.serve().await;
+        }
+        Command::Migrate { command } => {
+            let storage = open_storage(config).await?;
+            migrate(storage.as_ref(), command.unwrap_or(MigrateCommand::Up))
+                .await?;
+        }
+        Command::CreateCollection {
+            id,
+            permissions,
+            quota,
+            is_ref,
+        } => {
+            let permissions = match permissions {
+                Some(permissions) => parse_permissions(&permissions)?,
+                None => Permissions::empty(),
+            };
+            let storage = open_storage(config).await?;
+            let col = sinkron::models::NewCollection {
+                id,
+                is_ref,
+                permissions: permissions.to_string(),
+                quota,
+            };
+            let col = storage
+                .create_collection(col)
+                .await
+                .map_err(|err| err.message)?;
+            println!("Created collection \"{}\"", col.id);
+        }
+        Command::Grant(args) => {
+            update_permissions(config, args, grant).await?;
+        }
+        Command::Revoke(args) => {
+            update_permissions(config, args, revoke).await?;
+        }
+        Command::Export { id, file } => {
+            let storage = open_storage(config).await?;
+            let data = sinkron::export_collection(storage.as_ref(), &id)
+                .await
+                .map_err(|err| {
+                    format!("Couldn't export collection: {}", err.message)
+                })?;
+            std::fs::write(&file, data)
+                .map_err(|err| format!("Couldn't write {}: {}", file, err))?;
+            println!("Exported collection \"{}\"", id);
+        }
+        Command::Import {
+            file,
+            id,
+            remap_ids,
+        } => {
+            let data = std::fs::read(&file)
+                .map_err(|err| format!("Couldn't read {}: {}", file, err))?;
+            let limits = config.import.clone();
+            let storage = open_storage(config).await?;
+            storage
+                .migrate()
+                .await
+                .map_err(|err| format!("Couldn't migrate: {:?}", err))?;
+            let options = sinkron::ImportOptions { id, remap_ids };
+            let col = sinkron::import_collection(
+                storage.as_ref(),
+                &data,
+                options,
+                &limits,
+            )
+            .await
+            .map_err(|err| {
+                format!("Couldn't import collection: {}", err.message)
+            })?;
+            println!(
+                "Imported collection \"{}\" with {} documents",
+                col.id, col.documents_count
+            );
+        }
+        Command::Gc { older_than } => {
+            let storage = open_storage(config).await?;
+            let before = chrono::Utc::now()
+                - chrono::Duration::days(i64::from(older_than));
+            let removed = storage
+                .purge_deleted_documents(before)
+                .await
+                .map_err(|err| format!("Couldn't remove: {}", err.message))?;
+            println!("Removed {} deleted documents", removed);
+        }
+        Command::Check { permissions_only } if !permissions_only => {
+            let storage = open_storage(config).await?;
+            let report = sinkron::check_integrity(storage.as_ref())
+                .await
+                .map_err(|err| format!("Couldn't check: {}", err.message))?;
+            print_report(&report);
+            if !report.is_empty() {
+                return Err(format!("Found {} issues", report.len()));
+            }
+        }
+        Command::Check { .. } | Command::CheckPermissions => {
+            let storage = open_storage(config).await?;
+            let report = sinkron::check_permissions(storage.as_ref())
+                .await
+                .map_err(|err| format!("Couldn't check: {}", err.message))?;
+            print_report(&report);
+            if !report.is_empty() {
+                return Err(format!(
+                    "Found {} invalid permissions",
+                    report.len()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
+async fn main() -> ExitCode {
+    env_logger::init();
+
+    let cli = Cli::parse();
+
+    let config = match sinkron::load_config(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let sinkron = sinkron::Sinkron::new(config).await;
-    sinkron.run().await;
+    let command = cli.command.unwrap_or(Command::Serve { no_migrate: false });
+    match run(command, config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -3,7 +3,7 @@ use uuid::Uuid;
 
 use crate::schema;
 
-#[derive(serde::Serialize, Selectable, Queryable)]
+#[derive(serde::Serialize, Selectable, Queryable, Insertable, Clone)]
 #[diesel(table_name = schema::collections)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Collection {
@@ -11,18 +11,27 @@ pub struct Collection {
     pub is_ref: bool,
     pub colrev: i64,
     pub permissions: String,
+    // Number of documents, except deleted
+    pub documents_count: i64,
+    // Total size of the snapshots of all documents (in bytes)
+    pub size: i64,
+    // Max total size of the documents (in bytes)
+    pub quota: Option<i64>,
+    // Max colrev of the deleted documents removed by gc
+    pub purged_colrev: i64,
 }
 
-#[derive(serde::Deserialize, Insertable)]
+#[derive(Insertable)]
 #[diesel(table_name = schema::collections)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct NewCollection {
     pub id: String,
     pub is_ref: bool,
     pub permissions: String,
+    pub quota: Option<i64>,
 }
 
-#[derive(serde::Serialize, Selectable, Queryable)]
+#[derive(serde::Serialize, Selectable, Queryable, Insertable, Clone)]
 #[diesel(table_name = schema::documents)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Document {
@@ -34,6 +43,7 @@ pub struct Document {
     pub data: Option<Vec<u8>>,
     pub is_deleted: bool,
     pub permissions: String,
+    pub created_by: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -43,7 +53,8 @@ pub struct NewDocument<'a> {
     pub col_id: String,
     pub colrev: i64,
     pub data: Vec<u8>,
-    pub permissions: &'a str
+    pub permissions: &'a str,
+    pub created_by: Option<&'a str>,
 }
 
 #[derive(AsChangeset)]
@@ -72,11 +83,12 @@ pub struct NewRef {
     pub col_id: String,
 }
 
-#[derive(Insertable)]
+#[derive(Insertable, Selectable, Queryable, Clone)]
 #[diesel(table_name = schema::groups)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Group {
     pub id: String,
+    pub metadata: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -86,3 +98,11 @@ pub struct Member {
     pub user: String,
     pub group: String,
 }
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::subgroups)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Subgroup {
+    pub parent: String,
+    pub child: String,
+}
@@ -2,28 +2,84 @@ use serde::{Deserialize, Serialize};
 
 use crate::types::User;
 
+#[derive(Clone, Copy)]
 pub enum Action {
     Read,
     Create,
     Update,
     Delete,
+    // Changing permissions of the collection or document
+    Share,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "kind")]
 pub enum Role {
     Any,
     User { id: String },
     Group { id: String },
+    // Creator of the document
+    Owner,
 }
 
-#[derive(Serialize, Deserialize)]
+impl Role {
+    fn matches(&self, user: &User, owner: Option<&str>) -> bool {
+        match self {
+            Role::Any => true,
+            Role::User { id } => user.id == *id,
+            Role::Group { id } => user.groups.contains(id),
+            Role::Owner => owner == Some(user.id.as_str()),
+        }
+    }
+}
+
+// Roles that are not allowed to perform actions, even when they are allowed
+// by other roles
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Deny {
+    #[serde(default)]
+    pub read: Vec<Role>,
+    #[serde(default)]
+    pub create: Vec<Role>,
+    #[serde(default)]
+    pub update: Vec<Role>,
+    #[serde(default)]
+    pub delete: Vec<Role>,
+    #[serde(default)]
+    pub share: Vec<Role>,
+}
+
+impl Deny {
+    fn is_empty(&self) -> bool {
+        self.read.is_empty()
+            && self.create.is_empty()
+            && self.update.is_empty()
+            && self.delete.is_empty()
+            && self.share.is_empty()
+    }
+
+    fn list(&self, action: Action) -> &Vec<Role> {
+        match action {
+            Action::Read => &self.read,
+            Action::Create => &self.create,
+            Action::Update => &self.update,
+            Action::Delete => &self.delete,
+            Action::Share => &self.share,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Permissions {
     pub read: Vec<Role>,
     pub create: Vec<Role>,
     pub update: Vec<Role>,
     pub delete: Vec<Role>,
+    #[serde(default)]
+    pub share: Vec<Role>,
+    #[serde(default, skip_serializing_if = "Deny::is_empty")]
+    pub deny: Deny,
 }
 
 impl Permissions {
@@ -33,6 +89,8 @@ impl Permissions {
             create: Vec::new(),
             update: Vec::new(),
             delete: Vec::new(),
+            share: Vec::new(),
+            deny: Deny::default(),
         }
     }
 
@@ -40,31 +98,87 @@ impl Permissions {
         serde_json::from_str(input).unwrap_or_else(|_| Permissions::empty())
     }
 
-    pub fn check(&self, user: &User, action: Action) -> bool {
-        let list = match action {
+    fn list(&self, action: Action) -> &Vec<Role> {
+        match action {
             Action::Read => &self.read,
             Action::Create => &self.create,
             Action::Update => &self.update,
             Action::Delete => &self.delete,
-        };
-        for item in list {
-            match item {
-                Role::Any => {
-                    return true;
-                }
-                Role::User { id } => {
-                    if user.id == *id {
-                        return true;
-                    }
-                }
-                Role::Group { id } => {
-                    if user.groups.contains(id) {
-                        return true;
-                    }
+            Action::Share => &self.share,
+        }
+    }
+
+    pub fn check(&self, user: &User, action: Action) -> bool {
+        self.check_with_owner(user, action, None)
+    }
+
+    // Checks permission of the user to perform action on the document,
+    // created by the `owner`.
+    //
+    // Deny rules always take precedence: if any of the denied roles matches
+    // the user, action is not allowed, regardless of the allowed roles.
+    // Otherwise action is allowed when any of the allowed roles matches.
+    pub fn check_with_owner(
+        &self,
+        user: &User,
+        action: Action,
+        owner: Option<&str>,
+    ) -> bool {
+        let is_denied = self
+            .deny
+            .list(action)
+            .iter()
+            .any(|role| role.matches(user, owner));
+        if is_denied {
+            return false;
+        }
+        self.list(action)
+            .iter()
+            .any(|role| role.matches(user, owner))
+    }
+
+    // Checks that user can grant permissions to other users.
+    // User can grant only roles that already have the same permission, or
+    // grant themselves (or owner of the document created by them)
+    // permissions that they already have, so granted permissions can only
+    // narrow down permissions of the collection. Deny rules can always be
+    // added, since they only narrow down permissions.
+    pub fn can_grant(&self, user: &User, granted: &Permissions) -> bool {
+        // Created document will be owned by the user
+        self.can_grant_with_owner(user, granted, Some(&user.id))
+    }
+
+    // Same as `can_grant`, but for the existing document created by the
+    // `owner`
+    pub fn can_grant_with_owner(
+        &self,
+        user: &User,
+        granted: &Permissions,
+        owner: Option<&str>,
+    ) -> bool {
+        let is_owner = owner == Some(user.id.as_str());
+        let actions = [
+            Action::Read,
+            Action::Create,
+            Action::Update,
+            Action::Delete,
+            Action::Share,
+        ];
+        for action in actions {
+            let list = self.list(action);
+            let is_allowed = self.check_with_owner(user, action, owner);
+            for role in granted.list(action) {
+                let is_self = match role {
+                    Role::User { id } => *id == user.id,
+                    Role::Owner => is_owner,
+                    _ => false,
+                };
+                if !(list.contains(role) || is_self && is_allowed) {
+                    return false;
                 }
             }
         }
-        false
+        true
     }
 }
 
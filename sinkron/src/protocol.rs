@@ -1,6 +1,8 @@
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
 
+use crate::permissions::Permissions;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ErrorCode {
     #[serde(rename = "bad_request")]
@@ -13,10 +15,24 @@ pub enum ErrorCode {
     Forbidden,
     #[serde(rename = "unprocessable_content")]
     UnprocessableContent,
+    #[serde(rename = "too_many_requests")]
+    TooManyRequests,
+    #[serde(rename = "payload_too_large")]
+    PayloadTooLarge,
+    #[serde(rename = "quota_exceeded")]
+    QuotaExceeded,
+    // Documents deleted since the client's colrev were removed, client has
+    // to drop its documents and sync from the start
+    #[serde(rename = "resync_required")]
+    ResyncRequired,
     #[serde(rename = "internal_server_error")]
     InternalServerError,
 }
 
+// Websocket close code used when the client is disconnected because it
+// couldn't keep up with incoming messages
+pub const CLOSE_CODE_SLOW_CLIENT: u16 = 4008;
+
 #[derive(Serialize, Deserialize)]
 pub struct HeartbeatMessage {
     pub i: i32,
@@ -34,6 +50,14 @@ pub struct SyncCompleteMessage {
     pub colrev: i64,
 }
 
+// Sent during the sync after each page of documents. Client can use colrev
+// to resume interrupted sync from this point.
+#[derive(Serialize, Deserialize)]
+pub struct SyncProgressMessage {
+    pub col: String,
+    pub colrev: i64,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GetMessage {
     pub id: Uuid,
@@ -74,6 +98,9 @@ pub struct ClientChangeMessage {
     pub op: Op,
     pub data: Option<String>,
     pub changeid: Uuid,
+    // Permissions of the created document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Box<Permissions>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -84,6 +111,10 @@ pub struct ServerChangeMessage {
     pub op: Op,
     pub data: Option<String>,
     pub changeid: Uuid,
+    // Changes that were replaced by this one when the client couldn't keep
+    // up with the updates of the document
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changeids: Vec<Uuid>,
     pub colrev: i64,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
@@ -96,6 +127,37 @@ pub struct ChangeErrorMessage {
     pub changeid: Uuid,
 }
 
+// Request to change permissions of the document, or of the whole collection
+// when `id` is not set
+#[derive(Serialize, Deserialize)]
+pub struct ClientPermissionsMessage {
+    pub col: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    pub permissions: Box<Permissions>,
+    pub changeid: Uuid,
+}
+
+// Sent to subscribers of the collection when permissions of the collection
+// or document were changed
+#[derive(Serialize, Deserialize)]
+pub struct ServerPermissionsMessage {
+    pub col: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    pub permissions: Box<Permissions>,
+    pub changeid: Uuid,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PermissionsErrorMessage {
+    pub code: ErrorCode,
+    pub col: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    pub changeid: Uuid,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "kind")]
 pub enum ClientMessage {
@@ -107,6 +169,9 @@ pub enum ClientMessage {
 
     #[serde(rename = "change")]
     Change(ClientChangeMessage),
+
+    #[serde(rename = "permissions")]
+    Permissions(ClientPermissionsMessage),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -115,6 +180,9 @@ pub enum ServerMessage {
     #[serde(rename = "h")]
     Heartbeat(HeartbeatMessage),
 
+    #[serde(rename = "sync_progress")]
+    SyncProgress(SyncProgressMessage),
+
     #[serde(rename = "sync_complete")]
     SyncComplete(SyncCompleteMessage),
 
@@ -132,4 +200,10 @@ pub enum ServerMessage {
 
     #[serde(rename = "change_error")]
     ChangeError(ChangeErrorMessage),
+
+    #[serde(rename = "permissions")]
+    Permissions(ServerPermissionsMessage),
+
+    #[serde(rename = "permissions_error")]
+    PermissionsError(PermissionsErrorMessage),
 }
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use tokio::time::{Duration, Instant};
+
+// Limits of the user are removed when they were not used for this long and
+// all buckets are full again, so removing them doesn't reset anything
+const USER_IDLE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BucketConfig {
+    // Max number of requests that can be made at once
+    pub burst: u32,
+    // Number of requests per second
+    pub rate: f64,
+}
+
+// Limits for each kind of client messages, no limit when not set.
+// Heartbeats are never limited.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LimitsConfig {
+    pub get: Option<BucketConfig>,
+    pub change: Option<BucketConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitConfig {
+    // Limits for each websocket connection
+    #[serde(default)]
+    pub connection: LimitsConfig,
+    // Limits for the user across all connections
+    #[serde(default)]
+    pub user: LimitsConfig,
+    // Max number of simultaneous connections of the user
+    pub max_connections_per_user: Option<usize>,
+}
+
+struct TokenBucket {
+    config: BucketConfig,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: BucketConfig) -> Self {
+        Self {
+            config,
+            tokens: config.burst as f64,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.updated_at = now;
+        self.tokens = (self.tokens + elapsed * self.config.rate)
+            .min(self.config.burst as f64);
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.config.burst as f64
+    }
+
+    fn try_take(&mut self) -> bool {
+        self.refill(Instant::now());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum RequestKind {
+    Get,
+    Change,
+}
+
+pub struct Limits {
+    get: Option<TokenBucket>,
+    change: Option<TokenBucket>,
+    used_at: Instant,
+}
+
+impl Limits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            get: config.get.map(TokenBucket::new),
+            change: config.change.map(TokenBucket::new),
+            used_at: Instant::now(),
+        }
+    }
+
+    fn is_idle(&mut self, now: Instant) -> bool {
+        now.duration_since(self.used_at) >= USER_IDLE_TTL
+            && [&mut self.get, &mut self.change]
+                .into_iter()
+                .flatten()
+                .all(|bucket| bucket.is_full(now))
+    }
+
+    /// Returns `false` if the request exceeds the limit
+    pub fn check(&mut self, kind: RequestKind) -> bool {
+        self.used_at = Instant::now();
+        let bucket = match kind {
+            RequestKind::Get => &mut self.get,
+            RequestKind::Change => &mut self.change,
+        };
+        match bucket {
+            Some(bucket) => bucket.try_take(),
+            None => true,
+        }
+    }
+}
+
+struct UserLimits {
+    users: HashMap<String, Limits>,
+    swept_at: Instant,
+}
+
+// Limits shared between all connections of the same user. They are kept
+// after the user disconnects, so reconnecting doesn't give a new burst.
+pub struct UserRateLimiter {
+    config: LimitsConfig,
+    state: Mutex<UserLimits>,
+}
+
+impl UserRateLimiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(UserLimits {
+                users: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Returns `false` if the request exceeds the limit
+    pub fn check(&self, user: &str, kind: RequestKind) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(state.swept_at) >= USER_IDLE_TTL {
+            state.users.retain(|_, limits| !limits.is_idle(now));
+            state.swept_at = now;
+        }
+        state
+            .users
+            .entry(user.to_string())
+            .or_insert_with(|| Limits::new(&self.config))
+            .check(kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(burst: u32, rate: f64) -> Option<BucketConfig> {
+        Some(BucketConfig { burst, rate })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_allows_burst_and_refills() {
+        let mut bucket = TokenBucket::new(BucketConfig {
+            burst: 2,
+            rate: 2.0,
+        });
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        // Tokens don't accumulate over the burst
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_are_checked_by_kind() {
+        let config = LimitsConfig {
+            get: bucket(1, 1.0),
+            change: None,
+        };
+        let mut limits = Limits::new(&config);
+        assert!(limits.check(RequestKind::Get));
+        assert!(!limits.check(RequestKind::Get));
+        for _ in 0..100 {
+            assert!(limits.check(RequestKind::Change));
+        }
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limits.check(RequestKind::Get));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn user_limits_are_kept_until_idle() {
+        let limiter = UserRateLimiter::new(LimitsConfig {
+            get: None,
+            change: bucket(2, 0.02),
+        });
+        assert!(limiter.check("user", RequestKind::Change));
+        assert!(limiter.check("user", RequestKind::Change));
+        assert!(!limiter.check("user", RequestKind::Change));
+        assert!(limiter.check("other", RequestKind::Change));
+
+        // Bucket is not full yet, so it is kept after the idle period
+        tokio::time::advance(USER_IDLE_TTL).await;
+        assert!(limiter.check("user", RequestKind::Change));
+        assert!(!limiter.check("user", RequestKind::Change));
+
+        // Buckets of both users are full again
+        tokio::time::advance(Duration::from_secs(200)).await;
+        limiter.check("third", RequestKind::Change);
+        let users = &limiter.state.lock().unwrap().users;
+        assert!(!users.contains_key("user"));
+        assert!(!users.contains_key("other"));
+        assert!(users.contains_key("third"));
+    }
+}
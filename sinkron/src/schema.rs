@@ -6,6 +6,10 @@ diesel::table! {
         is_ref -> Bool,
         colrev -> Int8,
         permissions -> Text,
+        documents_count -> Int8,
+        size -> Int8,
+        quota -> Nullable<Int8>,
+        purged_colrev -> Int8,
     }
 }
 
@@ -19,12 +23,14 @@ diesel::table! {
         data -> Nullable<Bytea>,
         is_deleted -> Bool,
         permissions -> Text,
+        created_by -> Nullable<Text>,
     }
 }
 
 diesel::table! {
     groups (id) {
         id -> Text,
+        metadata -> Nullable<Text>,
     }
 }
 
@@ -46,6 +52,14 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    subgroups (id) {
+        id -> Uuid,
+        parent -> Text,
+        child -> Text,
+    }
+}
+
 diesel::joinable!(documents -> collections (col_id));
 diesel::joinable!(members -> groups (group));
 diesel::joinable!(refs -> collections (col_id));
@@ -57,4 +71,5 @@ diesel::allow_tables_to_appear_in_same_query!(
     groups,
     members,
     refs,
+    subgroups,
 );
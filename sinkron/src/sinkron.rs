@@ -1,47 +1,90 @@
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
 
 use axum::{
+    async_trait,
+    body::Bytes,
+    extract::rejection::JsonRejection,
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    extract::{Query, Request, State},
-    http::StatusCode,
+    extract::DefaultBodyLimit,
+    extract::{FromRequest, Query, Request, State},
+    http::{header, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{any, get, post},
     Json, Router,
 };
-use diesel::prelude::*;
-use diesel_async::RunQueryDsl;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
+use crate::actors::client::ClientConfig;
 use crate::actors::collection;
-use crate::actors::collection::{CollectionHandle, CollectionMessage};
+use crate::actors::collection::{
+    CollectionConfig, CollectionHandle, CollectionMessage,
+};
 use crate::actors::sinkron::{
     ConnectMessage, GetCollectionMessage, SinkronActorMessage, SinkronHandle,
 };
+use crate::archive::{self, ImportLimits, ImportOptions};
+use crate::compression::CompressionConfig;
 use crate::db;
 use crate::error::{internal_error, SinkronError};
-use crate::groups::{AddRemoveUserToGroup, GroupsApi};
+use crate::groups::{
+    AddRemoveGroupToGroup, AddRemoveUserToGroup, AddRemoveUsersToGroup,
+    CreateGroup, GroupsApi, ListGroupMembers, ListGroups, UpdateGroupMetadata,
+};
 use crate::models;
+use crate::permissions::{Action, Permissions};
 use crate::protocol::*;
-use crate::schema;
-use crate::types::{Collection, Document};
+use crate::storage::{create_storage, PostgresStorage, Storage, StorageConfig};
+use crate::tls::{TlsConfig, TlsConnection, TlsServer};
+use crate::types::{page_limit, Collection, Document, Page};
 
-type CreateCollection = models::NewCollection;
+#[derive(Deserialize)]
+struct CreateCollection {
+    id: String,
+    is_ref: bool,
+    permissions: Permissions,
+    #[serde(default)]
+    quota: Option<i64>,
+}
 
 #[derive(Deserialize)]
 struct UpdateCollectionPermissions {
     id: String,
-    permissions: String,
+    permissions: Permissions,
+}
+
+#[derive(Deserialize)]
+struct UpdateCollectionQuota {
+    id: String,
+    quota: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ListCollections {
+    // Only return collections with ids starting with the prefix
+    prefix: Option<String>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+// Collections that the user is allowed to read
+#[derive(Deserialize)]
+struct ListUserCollections {
+    user: String,
+    cursor: Option<String>,
+    limit: Option<i64>,
 }
 
 #[derive(Deserialize)]
 struct UpdateDocumentPermissions {
     id: Uuid,
     col: String,
-    permissions: String,
+    permissions: Permissions,
 }
 
 fn default_host() -> String {
@@ -60,41 +103,135 @@ pub struct SinkronConfig {
     pub port: u32,
     pub api_token: String,
     pub sync_auth_url: Option<String>,
-    pub db: db::DbConfig,
+    // Connection to Postgres, required when using Postgres storage
+    pub db: Option<db::DbConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub client: ClientConfig,
+    #[serde(default)]
+    pub collection: CollectionConfig,
+    // Max size of the websocket message or api request body (in bytes)
+    pub max_message_size: Option<usize>,
+    #[serde(default)]
+    pub import: ImportLimits,
+    // Serve over HTTPS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
 }
 
-#[derive(Clone)]
-pub struct Sinkron {
-    pool: db::DbConnectionPool,
-    actor: SinkronHandle,
-    host: String,
-    port: u32,
-    api_token: String,
-    sync_auth_url: Option<String>,
-    groups_api: Arc<GroupsApi>,
+pub type AuthFuture =
+    Pin<Box<dyn Future<Output = Result<String, SinkronError>> + Send>>;
+
+// Receives token of the connecting client and returns id of the user
+type AuthHook = Arc<dyn Fn(String) -> AuthFuture + Send + Sync>;
+
+// Builds sinkron instance for embedding into another service
+pub struct SinkronBuilder {
+    config: SinkronConfig,
+    storage: Option<Arc<dyn Storage>>,
+    auth: Option<AuthHook>,
+    routes: Router,
 }
 
-impl Sinkron {
-    pub async fn new(config: SinkronConfig) -> Self {
-        let pool = db::create_pool(config.db).await;
-        let groups_api = Arc::new(GroupsApi::new(pool.clone()));
-        let actor = SinkronHandle::new(pool.clone(), groups_api.clone());
+impl SinkronBuilder {
+    pub fn new(config: SinkronConfig) -> Self {
         Self {
-            pool,
+            config,
+            storage: None,
+            auth: None,
+            routes: Router::new(),
+        }
+    }
+
+    // Uses existing Postgres connection pool instead of creating a new one
+    // from the config
+    pub fn pool(self, pool: db::DbConnectionPool) -> Self {
+        self.storage(Arc::new(PostgresStorage::new(pool)))
+    }
+
+    // Uses custom storage instead of creating one from the config
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    // Authenticates sync clients, replaces the `sync_auth_url`
+    pub fn auth<F, Fut>(mut self, auth: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, SinkronError>> + Send + 'static,
+    {
+        self.auth = Some(Arc::new(move |token| Box::pin(auth(token))));
+        self
+    }
+
+    // Adds routes to the sinkron router. Routes are not checked for
+    // the api token, they should handle auth themselves.
+    pub fn routes(mut self, router: Router) -> Self {
+        self.routes = self.routes.merge(router);
+        self
+    }
+
+    pub async fn build(self) -> Result<Sinkron, String> {
+        let SinkronBuilder {
+            config,
+            storage,
+            auth,
+            routes,
+        } = self;
+        let tls = config.tls.map(TlsServer::new).transpose()?;
+        let storage = match storage {
+            Some(storage) => storage,
+            None => create_storage(config.storage, config.db).await?,
+        };
+        let groups_api = Arc::new(GroupsApi::new(storage.clone()));
+        let actor = SinkronHandle::new(
+            storage.clone(),
+            groups_api.clone(),
+            config.client,
+            config.collection,
+        );
+        Ok(Sinkron {
+            storage,
             actor,
             host: config.host,
             port: config.port,
             api_token: config.api_token,
             sync_auth_url: config.sync_auth_url,
             groups_api,
-        }
+            compression: config.compression,
+            max_message_size: config.max_message_size,
+            import_limits: config.import,
+            tls,
+            auth,
+            routes,
+        })
     }
+}
 
-    async fn connect(&self) -> Result<db::DbConnection, SinkronError> {
-        self.pool.get().await.map_err(|e| {
-            println!("{:?}", e);
-            internal_error(e)
-        })
+#[derive(Clone)]
+pub struct Sinkron {
+    storage: Arc<dyn Storage>,
+    actor: SinkronHandle,
+    host: String,
+    port: u32,
+    api_token: String,
+    sync_auth_url: Option<String>,
+    groups_api: Arc<GroupsApi>,
+    compression: CompressionConfig,
+    max_message_size: Option<usize>,
+    import_limits: ImportLimits,
+    tls: Option<TlsServer>,
+    auth: Option<AuthHook>,
+    // Custom routes added with the builder
+    routes: Router,
+}
+
+impl Sinkron {
+    pub async fn new(config: SinkronConfig) -> Result<Self, String> {
+        SinkronBuilder::new(config).build().await
     }
 
     async fn get_collection_actor(
@@ -115,40 +252,113 @@ impl Sinkron {
         &self,
         props: CreateCollection,
     ) -> Result<Collection, SinkronError> {
-        let mut conn = self.connect().await?;
-        let cnt: i64 = schema::collections::table
-            .filter(schema::collections::id.eq(&props.id))
-            .count()
-            .get_result(&mut conn)
-            .await
-            .map_err(internal_error)?;
-        if cnt != 0 {
-            return Err(SinkronError::unprocessable("Duplicate collection id"));
-        }
-        let col = diesel::insert_into(schema::collections::table)
-            .values(&props)
-            .returning(models::Collection::as_returning())
-            .get_result(&mut conn)
-            .await
-            .map_err(internal_error)?;
-        Ok(col)
+        let new_col = models::NewCollection {
+            id: props.id,
+            is_ref: props.is_ref,
+            permissions: props.permissions.to_string(),
+            quota: props.quota,
+        };
+        self.storage.create_collection(new_col).await
     }
 
     async fn get_collection(
         &self,
         id: String,
     ) -> Result<models::Collection, SinkronError> {
-        let mut conn = self.connect().await?;
-        schema::collections::table
-            .find(id)
-            .first(&mut conn)
-            .await
-            .map_err(|err| match err {
-                diesel::NotFound => {
-                    SinkronError::not_found("Collection not found")
+        self.storage.get_collection(&id).await
+    }
+
+    async fn list_collections(
+        &self,
+        props: ListCollections,
+    ) -> Result<Page<Collection>, SinkronError> {
+        let ListCollections {
+            prefix,
+            cursor,
+            limit,
+        } = props;
+        let limit = page_limit(limit);
+        let cols = self
+            .storage
+            .list_collections(prefix.as_deref(), cursor.as_deref(), limit)
+            .await?;
+        Ok(Page::new(cols, limit, |col| col.id.clone()))
+    }
+
+    // Permissions are stored as JSON, so collections are checked one by one
+    async fn list_user_collections(
+        &self,
+        props: ListUserCollections,
+    ) -> Result<Page<Collection>, SinkronError> {
+        let ListUserCollections {
+            user,
+            mut cursor,
+            limit,
+        } = props;
+        let limit = page_limit(limit);
+        let user = self.groups_api.get_user(user).await?;
+        let mut items = Vec::new();
+        loop {
+            let cols = self
+                .storage
+                .list_collections(None, cursor.as_deref(), limit)
+                .await?;
+            let is_last = (cols.len() as i64) < limit;
+            for col in cols {
+                cursor = Some(col.id.clone());
+                let permissions = Permissions::parse_or_empty(&col.permissions);
+                if permissions.check(&user, Action::Read) {
+                    items.push(col);
+                    if items.len() as i64 == limit {
+                        return Ok(Page { items, cursor });
+                    }
                 }
-                err => SinkronError::internal(&err.to_string()),
-            })
+            }
+            if is_last {
+                return Ok(Page {
+                    items,
+                    cursor: None,
+                });
+            }
+        }
+    }
+
+    async fn update_collection_quota(
+        &self,
+        props: UpdateCollectionQuota,
+    ) -> Result<(), SinkronError> {
+        let UpdateCollectionQuota { id, quota } = props;
+
+        let col = self.get_collection_actor(id).await?;
+
+        let (sender, receiver) = oneshot::channel();
+        col.send(CollectionMessage::SetQuota(collection::SetQuotaMessage {
+            quota,
+            reply: sender,
+        }))
+        .map_err(internal_error)?;
+        receiver.await.map_err(internal_error)?
+    }
+
+    async fn export_collection(
+        &self,
+        id: String,
+    ) -> Result<Vec<u8>, SinkronError> {
+        archive::export_collection(self.storage.as_ref(), &id).await
+    }
+
+    async fn import_collection(
+        &self,
+        data: &[u8],
+        options: ImportOptions,
+    ) -> Result<models::Collection, SinkronError> {
+        archive::import_collection(
+            self.storage.as_ref(),
+            data,
+            options,
+            &self.import_limits,
+        )
+        .await
     }
 
     // Documents
@@ -184,13 +394,16 @@ impl Sinkron {
         let col = self.get_collection_actor(col).await?;
 
         let (sender, receiver) = oneshot::channel();
-        col.send(CollectionMessage::Create(collection::CreateMessage {
-            id,
-            data,
-            source: collection::Source::Api,
-            changeid: Uuid::new_v4(),
-            reply: sender,
-        }))
+        col.send(CollectionMessage::Create(Box::new(
+            collection::CreateMessage {
+                id,
+                data,
+                permissions,
+                source: collection::Source::Api,
+                changeid: Uuid::new_v4(),
+                reply: sender,
+            },
+        )))
         .map_err(internal_error)?;
         receiver.await.map_err(internal_error)?
     }
@@ -233,46 +446,132 @@ impl Sinkron {
         receiver.await.map_err(internal_error)?
     }
 
+    // Groups
+
+    // Changes of the groups can revoke access of the users to collections
+    // (removed membership, or added membership in the denied group), so
+    // active subscribers have to be re-checked
+    fn recheck_subscribers(&self) -> Result<(), SinkronError> {
+        self.actor
+            .send(SinkronActorMessage::RecheckSubscribers)
+            .map_err(internal_error)
+    }
+
+    async fn delete_group(&self, id: String) -> Result<(), SinkronError> {
+        self.groups_api.delete_group(id).await?;
+        self.recheck_subscribers()
+    }
+
+    async fn add_user_to_group(
+        &self,
+        props: AddRemoveUserToGroup,
+    ) -> Result<(), SinkronError> {
+        self.groups_api.add_user_to_group(props).await?;
+        self.recheck_subscribers()
+    }
+
+    async fn remove_user_from_group(
+        &self,
+        props: AddRemoveUserToGroup,
+    ) -> Result<(), SinkronError> {
+        self.groups_api.remove_user_from_group(props).await?;
+        self.recheck_subscribers()
+    }
+
+    async fn add_users_to_group(
+        &self,
+        props: AddRemoveUsersToGroup,
+    ) -> Result<(), SinkronError> {
+        self.groups_api.add_users_to_group(props).await?;
+        self.recheck_subscribers()
+    }
+
+    async fn remove_users_from_group(
+        &self,
+        props: AddRemoveUsersToGroup,
+    ) -> Result<(), SinkronError> {
+        self.groups_api.remove_users_from_group(props).await?;
+        self.recheck_subscribers()
+    }
+
+    async fn remove_user_from_all_groups(
+        &self,
+        id: String,
+    ) -> Result<(), SinkronError> {
+        self.groups_api.remove_user_from_all_groups(id).await?;
+        self.recheck_subscribers()
+    }
+
+    async fn add_group_to_group(
+        &self,
+        props: AddRemoveGroupToGroup,
+    ) -> Result<(), SinkronError> {
+        self.groups_api.add_group_to_group(props).await?;
+        self.recheck_subscribers()
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        props: AddRemoveGroupToGroup,
+    ) -> Result<(), SinkronError> {
+        self.groups_api.remove_group_from_group(props).await?;
+        self.recheck_subscribers()
+    }
+
     // Permissions
 
     async fn update_collection_permissions(
         &self,
         props: UpdateCollectionPermissions,
     ) -> Result<(), SinkronError> {
-        let mut conn = self.connect().await?;
-        let num: usize = diesel::update(schema::collections::table)
-            .filter(schema::collections::id.eq(&props.id))
-            .set(schema::collections::permissions.eq(props.permissions))
-            .execute(&mut conn)
-            .await
-            .map_err(internal_error)?;
-        if num == 0 {
-            Err(SinkronError::not_found("Collection not found"))
-        } else {
-            Ok(())
-        }
+        let UpdateCollectionPermissions { id, permissions } = props;
+
+        let col = self.get_collection_actor(id).await?;
+
+        let (sender, receiver) = oneshot::channel();
+        col.send(CollectionMessage::SetPermissions(Box::new(
+            collection::SetPermissionsMessage {
+                permissions,
+                source: collection::Source::Api,
+                changeid: Uuid::new_v4(),
+                reply: sender,
+            },
+        )))
+        .map_err(internal_error)?;
+        receiver.await.map_err(internal_error)?
     }
 
     async fn update_document_permissions(
         &self,
         props: UpdateDocumentPermissions,
     ) -> Result<(), SinkronError> {
-        let mut conn = self.connect().await?;
-        let num: usize = diesel::update(schema::documents::table)
-            .filter(schema::documents::id.eq(&props.id))
-            .filter(schema::documents::col_id.eq(&props.col))
-            .set(schema::documents::permissions.eq(props.permissions))
-            .execute(&mut conn)
-            .await
-            .map_err(internal_error)?;
-        if num == 0 {
-            Err(SinkronError::not_found("Document not found"))
-        } else {
-            Ok(())
-        }
+        let UpdateDocumentPermissions {
+            id,
+            col,
+            permissions,
+        } = props;
+
+        let col = self.get_collection_actor(col).await?;
+
+        let (sender, receiver) = oneshot::channel();
+        col.send(CollectionMessage::SetDocumentPermissions(Box::new(
+            collection::SetDocumentPermissionsMessage {
+                id,
+                permissions,
+                source: collection::Source::Api,
+                changeid: Uuid::new_v4(),
+                reply: sender,
+            },
+        )))
+        .map_err(internal_error)?;
+        receiver.await.map_err(internal_error)?
     }
 
-    fn app(&self) -> Router {
+    // Returns router with the sync and api endpoints, it can be nested into
+    // the router of another service. Storage should be migrated before use.
+    // Client certificates from the "tls.clientCa" are only checked when the
+    // router is served with `serve`.
+    pub fn router(&self) -> Router {
         let api_router = Router::new()
             .route("/get_document", post(get_document))
             .route("/create_document", post(create_document))
@@ -281,6 +580,10 @@ impl Sinkron {
             // Collections
             .route("/create_collection", post(create_collection))
             .route("/get_collection", post(get_collection))
+            .route("/list_collections", post(list_collections))
+            .route("/list_user_collections", post(list_user_collections))
+            .route("/update_collection_quota", post(update_collection_quota))
+            .route("/export_collection", post(export_collection))
             // .route("/delete_collection", post(delete_collection))
             /*
             // Refs
@@ -296,14 +599,21 @@ impl Sinkron {
             // Groups & users
             .route("/get_user", post(get_user))
             .route("/get_group", post(get_group))
+            .route("/list_groups", post(list_groups))
+            .route("/list_group_members", post(list_group_members))
             .route("/create_group", post(create_group))
+            .route("/update_group_metadata", post(update_group_metadata))
             .route("/delete_group", post(delete_group))
             .route("/add_user_to_group", post(add_user_to_group))
             .route("/remove_user_from_group", post(remove_user_from_group))
+            .route("/add_users_to_group", post(add_users_to_group))
+            .route("/remove_users_from_group", post(remove_users_from_group))
             .route(
                 "/remove_user_from_all_groups",
                 post(remove_user_from_all_groups),
             )
+            .route("/add_group_to_group", post(add_group_to_group))
+            .route("/remove_group_from_group", post(remove_group_from_group))
             // Permissions
             .route(
                 "/update_collection_permissions",
@@ -318,27 +628,54 @@ impl Sinkron {
                 check_auth_token,
             ))
             .with_state(self.clone());
+        let api_router = match self.max_message_size {
+            Some(size) => api_router.layer(DefaultBodyLimit::max(size)),
+            None => api_router,
+        };
+        // Archives can be much larger than messages, so the import route
+        // has its own limit
+        let import_router = Router::new()
+            .route("/import_collection", post(import_collection))
+            .layer(DefaultBodyLimit::max(self.import_limits.max_archive_size))
+            .layer(middleware::from_fn_with_state(
+                self.clone(),
+                check_auth_token,
+            ))
+            .with_state(self.clone());
 
         Router::new()
             .route("/", get(root))
             .route("/sync", any(sync_handler))
             .merge(api_router)
+            .merge(import_router)
             .with_state(self.clone())
+            .merge(self.routes.clone())
+    }
+
+    pub async fn migrate(&self) -> Result<(), SinkronError> {
+        self.storage.migrate().await
     }
 
+    // Migrates storage and serves the api
     pub async fn run(&self) {
-        let conn = self.connect().await.unwrap();
-        db::run_migrations(deadpool::managed::Object::take(conn))
-            .await
-            .unwrap();
+        self.migrate().await.unwrap();
+        self.serve().await;
+    }
 
-        let app = self.app();
+    pub async fn serve(&self) {
+        let app = self.router();
         let host = format!("{}:{}", self.host, self.port);
         let listener = tokio::net::TcpListener::bind(host).await.unwrap();
-        axum::serve(listener, app).await.unwrap();
+        match &self.tls {
+            Some(tls) => tls.clone().serve(listener, app).await,
+            None => axum::serve(listener, app).await.unwrap(),
+        }
     }
 
     async fn auth(&self, token: &str) -> Result<String, SinkronError> {
+        if let Some(auth) = &self.auth {
+            return auth(token.to_string()).await;
+        }
         match &self.sync_auth_url {
             Some(auth_url) => {
                 let url = "".to_string() + auth_url + token;
@@ -382,13 +719,20 @@ impl Sinkron {
                 return;
             }
         };
+        let compression = match query.compression.as_deref() {
+            Some("deflate") if self.compression.enabled => {
+                Some(self.compression)
+            }
+            _ => None,
+        };
         self.actor
-            .send(SinkronActorMessage::Connect(ConnectMessage {
+            .send(SinkronActorMessage::Connect(Box::new(ConnectMessage {
                 websocket,
                 user,
                 col: query.col,
                 colrev: query.colrev,
-            }))
+                compression,
+            })))
             .expect("SinkronActor shoudn't exit");
     }
 }
@@ -404,6 +748,8 @@ struct SyncQuery {
     col: String,
     colrev: i64,
     token: String,
+    // Compression of the sync response requested by client ("deflate")
+    compression: Option<String>,
 }
 
 async fn sync_handler(
@@ -411,6 +757,10 @@ async fn sync_handler(
     Query(query): Query<SyncQuery>,
     State(sinkron): State<Sinkron>,
 ) -> Response {
+    let ws = match sinkron.max_message_size {
+        Some(size) => ws.max_message_size(size).max_frame_size(size),
+        None => ws,
+    };
     ws.on_upgrade(move |ws| handle_connect(sinkron, ws, query))
 }
 
@@ -438,6 +788,22 @@ async fn check_auth_token(
     req: Request,
     next: Next,
 ) -> Response {
+    // With "clientCa" the api can only be used by clients with certificates.
+    // Requests that were not received by the TlsServer come from the service
+    // that embeds the router, it is responsible for checking them.
+    let require_cert = state
+        .tls
+        .as_ref()
+        .is_some_and(|tls| tls.requires_client_cert());
+    let has_cert = req
+        .extensions()
+        .get::<TlsConnection>()
+        .is_none_or(|conn| conn.has_client_cert);
+    if require_cert && !has_cert {
+        return sinkron_err_response(SinkronError::auth_failed(
+            "Client certificate required",
+        ));
+    }
     let header = get_header_value(&req, "x-sinkron-api-token");
     if Some(state.api_token) == header {
         next.run(req).await
@@ -462,12 +828,44 @@ fn sinkron_err_response(error: SinkronError) -> Response {
         ErrorCode::NotFound => StatusCode::NOT_FOUND,
         ErrorCode::Forbidden => StatusCode::FORBIDDEN,
         ErrorCode::UnprocessableContent => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        ErrorCode::QuotaExceeded => StatusCode::FORBIDDEN,
+        ErrorCode::ResyncRequired => StatusCode::GONE,
         ErrorCode::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
     };
     let body = Json(SinkronErrorBody { error });
     (status, body).into_response()
 }
 
+// Json extractor that responds with SinkronError when body is invalid
+struct Payload<T>(T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Payload<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Response> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(payload)) => Ok(Payload(payload)),
+            Err(rejection) => {
+                let msg = rejection.body_text();
+                let err = if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE
+                {
+                    SinkronError::payload_too_large(&msg)
+                } else {
+                    SinkronError::bad_request(&msg)
+                };
+                Err(sinkron_err_response(err))
+            }
+        }
+    }
+}
+
 fn sinkron_response<T>(result: Result<T, SinkronError>) -> Response
 where
     T: Serialize,
@@ -487,7 +885,7 @@ struct Id {
 
 async fn create_collection(
     State(state): State<Sinkron>,
-    Json(payload): Json<CreateCollection>,
+    Payload(payload): Payload<CreateCollection>,
 ) -> Response {
     let res = state.create_collection(payload).await;
     sinkron_response(res)
@@ -495,12 +893,59 @@ async fn create_collection(
 
 async fn get_collection(
     State(state): State<Sinkron>,
-    Json(id): Json<Id>,
+    Payload(id): Payload<Id>,
 ) -> Response {
     let res = state.get_collection(id.id).await;
     sinkron_response(res)
 }
 
+async fn list_collections(
+    State(state): State<Sinkron>,
+    Payload(payload): Payload<ListCollections>,
+) -> Response {
+    let res = state.list_collections(payload).await;
+    sinkron_response(res)
+}
+
+async fn list_user_collections(
+    State(state): State<Sinkron>,
+    Payload(payload): Payload<ListUserCollections>,
+) -> Response {
+    let res = state.list_user_collections(payload).await;
+    sinkron_response(res)
+}
+
+async fn update_collection_quota(
+    State(state): State<Sinkron>,
+    Payload(payload): Payload<UpdateCollectionQuota>,
+) -> Response {
+    let res = state.update_collection_quota(payload).await;
+    sinkron_response(res)
+}
+
+// Responds with the gzipped archive of the collection
+async fn export_collection(
+    State(state): State<Sinkron>,
+    Payload(id): Payload<Id>,
+) -> Response {
+    match state.export_collection(id.id).await {
+        Ok(data) => {
+            ([(header::CONTENT_TYPE, "application/gzip")], data).into_response()
+        }
+        Err(err) => sinkron_err_response(err),
+    }
+}
+
+// Body is the archive, options are passed in the query string
+async fn import_collection(
+    State(state): State<Sinkron>,
+    Query(options): Query<ImportOptions>,
+    body: Bytes,
+) -> Response {
+    let res = state.import_collection(&body, options).await;
+    sinkron_response(res)
+}
+
 // Document handlers
 
 #[derive(Deserialize)]
@@ -516,7 +961,7 @@ struct CreateDocument {
     id: Uuid,
     col: String,
     data: String,
-    permissions: Option<String>,
+    permissions: Option<Permissions>,
 }
 
 #[derive(Deserialize)]
@@ -528,7 +973,7 @@ struct UpdateDocument {
 
 async fn get_document(
     State(state): State<Sinkron>,
-    Json(payload): Json<GetDocument>,
+    Payload(payload): Payload<GetDocument>,
 ) -> Response {
     let res = state.get_document(payload.id, payload.col).await;
     sinkron_response(res)
@@ -536,7 +981,7 @@ async fn get_document(
 
 async fn create_document(
     State(state): State<Sinkron>,
-    Json(payload): Json<CreateDocument>,
+    Payload(payload): Payload<CreateDocument>,
 ) -> Response {
     let res = state.create_document(payload).await;
     sinkron_response(res)
@@ -544,7 +989,7 @@ async fn create_document(
 
 async fn update_document(
     State(state): State<Sinkron>,
-    Json(payload): Json<UpdateDocument>,
+    Payload(payload): Payload<UpdateDocument>,
 ) -> Response {
     let res = state.update_document(payload).await;
     sinkron_response(res)
@@ -552,7 +997,7 @@ async fn update_document(
 
 async fn delete_document(
     State(state): State<Sinkron>,
-    Json(payload): Json<DeleteDocument>,
+    Payload(payload): Payload<DeleteDocument>,
 ) -> Response {
     let res = state.delete_document(payload.id, payload.col).await;
     sinkron_response(res)
@@ -562,23 +1007,47 @@ async fn delete_document(
 
 async fn create_group(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<Id>,
+    Payload(payload): Payload<CreateGroup>,
 ) -> Response {
-    let res = sinkron.groups_api.create_group(payload.id).await;
+    let res = sinkron.groups_api.create_group(payload).await;
+    sinkron_response(res)
+}
+
+async fn update_group_metadata(
+    State(sinkron): State<Sinkron>,
+    Payload(payload): Payload<UpdateGroupMetadata>,
+) -> Response {
+    let res = sinkron.groups_api.update_group_metadata(payload).await;
     sinkron_response(res)
 }
 
 async fn get_group(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<Id>,
+    Payload(payload): Payload<Id>,
 ) -> Response {
     let res = sinkron.groups_api.get_group(payload.id).await;
     sinkron_response(res)
 }
 
+async fn list_groups(
+    State(sinkron): State<Sinkron>,
+    Payload(payload): Payload<ListGroups>,
+) -> Response {
+    let res = sinkron.groups_api.list_groups(payload).await;
+    sinkron_response(res)
+}
+
+async fn list_group_members(
+    State(sinkron): State<Sinkron>,
+    Payload(payload): Payload<ListGroupMembers>,
+) -> Response {
+    let res = sinkron.groups_api.list_group_members(payload).await;
+    sinkron_response(res)
+}
+
 async fn get_user(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<Id>,
+    Payload(payload): Payload<Id>,
 ) -> Response {
     let res = sinkron.groups_api.get_user(payload.id).await;
     sinkron_response(res)
@@ -586,36 +1055,65 @@ async fn get_user(
 
 async fn delete_group(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<Id>,
+    Payload(payload): Payload<Id>,
 ) -> Response {
-    let res = sinkron.groups_api.delete_group(payload.id).await;
+    let res = sinkron.delete_group(payload.id).await;
     sinkron_response(res)
 }
 
 async fn add_user_to_group(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<AddRemoveUserToGroup>,
+    Payload(payload): Payload<AddRemoveUserToGroup>,
 ) -> Response {
-    let res = sinkron.groups_api.add_user_to_group(payload).await;
+    let res = sinkron.add_user_to_group(payload).await;
     sinkron_response(res)
 }
 
 async fn remove_user_from_group(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<AddRemoveUserToGroup>,
+    Payload(payload): Payload<AddRemoveUserToGroup>,
+) -> Response {
+    let res = sinkron.remove_user_from_group(payload).await;
+    sinkron_response(res)
+}
+
+async fn add_users_to_group(
+    State(sinkron): State<Sinkron>,
+    Payload(payload): Payload<AddRemoveUsersToGroup>,
+) -> Response {
+    let res = sinkron.add_users_to_group(payload).await;
+    sinkron_response(res)
+}
+
+async fn remove_users_from_group(
+    State(sinkron): State<Sinkron>,
+    Payload(payload): Payload<AddRemoveUsersToGroup>,
 ) -> Response {
-    let res = sinkron.groups_api.remove_user_from_group(payload).await;
+    let res = sinkron.remove_users_from_group(payload).await;
     sinkron_response(res)
 }
 
 async fn remove_user_from_all_groups(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<Id>,
+    Payload(payload): Payload<Id>,
+) -> Response {
+    let res = sinkron.remove_user_from_all_groups(payload.id).await;
+    sinkron_response(res)
+}
+
+async fn add_group_to_group(
+    State(sinkron): State<Sinkron>,
+    Payload(payload): Payload<AddRemoveGroupToGroup>,
+) -> Response {
+    let res = sinkron.add_group_to_group(payload).await;
+    sinkron_response(res)
+}
+
+async fn remove_group_from_group(
+    State(sinkron): State<Sinkron>,
+    Payload(payload): Payload<AddRemoveGroupToGroup>,
 ) -> Response {
-    let res = sinkron
-        .groups_api
-        .remove_user_from_all_groups(payload.id)
-        .await;
+    let res = sinkron.remove_group_from_group(payload).await;
     sinkron_response(res)
 }
 
@@ -623,7 +1121,7 @@ async fn remove_user_from_all_groups(
 
 async fn update_collection_permissions(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<UpdateCollectionPermissions>,
+    Payload(payload): Payload<UpdateCollectionPermissions>,
 ) -> Response {
     let res = sinkron.update_collection_permissions(payload).await;
     sinkron_response(res)
@@ -631,7 +1129,7 @@ async fn update_collection_permissions(
 
 async fn update_document_permissions(
     State(sinkron): State<Sinkron>,
-    Json(payload): Json<UpdateDocumentPermissions>,
+    Payload(payload): Payload<UpdateDocumentPermissions>,
 ) -> Response {
     let res = sinkron.update_document_permissions(payload).await;
     sinkron_response(res)
@@ -0,0 +1,532 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::error::SinkronError;
+use crate::models;
+use crate::storage::{CollectionUsage, MigrationStatus, Storage};
+
+#[derive(Default)]
+struct MemoryState {
+    collections: BTreeMap<String, models::Collection>,
+    documents: BTreeMap<Uuid, models::Document>,
+    // Group id -> metadata
+    groups: BTreeMap<String, Option<String>>,
+    // (group, user)
+    members: BTreeSet<(String, String)>,
+    // (parent, child)
+    subgroups: BTreeSet<(String, String)>,
+}
+
+// Keeps all data in memory, data is lost when the process exits
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn migrate(&self) -> Result<(), SinkronError> {
+        Ok(())
+    }
+
+    // Memory storage has no schema
+
+    async fn migration_status(
+        &self,
+    ) -> Result<Vec<MigrationStatus>, SinkronError> {
+        Ok(Vec::new())
+    }
+
+    async fn revert_migration(&self) -> Result<Option<String>, SinkronError> {
+        Ok(None)
+    }
+
+    // Collections
+
+    async fn create_collection(
+        &self,
+        col: models::NewCollection,
+    ) -> Result<models::Collection, SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        if state.collections.contains_key(&col.id) {
+            return Err(SinkronError::unprocessable("Duplicate collection id"));
+        }
+        let col = models::Collection {
+            id: col.id,
+            is_ref: col.is_ref,
+            colrev: 0,
+            permissions: col.permissions,
+            documents_count: 0,
+            size: 0,
+            quota: col.quota,
+            purged_colrev: 0,
+        };
+        state.collections.insert(col.id.clone(), col.clone());
+        Ok(col)
+    }
+
+    async fn get_collection(
+        &self,
+        id: &str,
+    ) -> Result<models::Collection, SinkronError> {
+        let state = self.state.lock().unwrap();
+        state
+            .collections
+            .get(id)
+            .cloned()
+            .ok_or_else(|| SinkronError::not_found("Collection not found"))
+    }
+
+    async fn list_collections(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<models::Collection>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let cols = state
+            .collections
+            .values()
+            .filter(|col| cursor.is_none_or(|cursor| col.id.as_str() > cursor))
+            .filter(|col| {
+                prefix.is_none_or(|prefix| col.id.starts_with(prefix))
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect();
+        Ok(cols)
+    }
+
+    async fn update_collection_permissions(
+        &self,
+        id: &str,
+        permissions: &str,
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(col) = state.collections.get_mut(id) {
+            col.permissions = permissions.to_string();
+        }
+        Ok(())
+    }
+
+    async fn update_collection_quota(
+        &self,
+        id: &str,
+        quota: Option<i64>,
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(col) = state.collections.get_mut(id) {
+            col.quota = quota;
+        }
+        Ok(())
+    }
+
+    async fn increment_colrev(
+        &self,
+        id: &str,
+        documents_delta: i64,
+        size_delta: i64,
+    ) -> Result<CollectionUsage, SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(col) = state.collections.get_mut(id) else {
+            return Err(SinkronError::not_found("Collection not found"));
+        };
+        col.colrev += 1;
+        col.documents_count += documents_delta;
+        col.size += size_delta;
+        Ok(CollectionUsage {
+            colrev: col.colrev,
+            documents_count: col.documents_count,
+            size: col.size,
+        })
+    }
+
+    async fn import_collection(
+        &self,
+        col: models::Collection,
+        docs: Vec<models::Document>,
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        if state.collections.contains_key(&col.id) {
+            return Err(SinkronError::unprocessable("Duplicate collection id"));
+        }
+        if docs.iter().any(|doc| state.documents.contains_key(&doc.id)) {
+            return Err(SinkronError::unprocessable("Duplicate document id"));
+        }
+        state.collections.insert(col.id.clone(), col);
+        for doc in docs {
+            state.documents.insert(doc.id, doc);
+        }
+        Ok(())
+    }
+
+    // Documents
+
+    async fn get_document(
+        &self,
+        col: &str,
+        id: Uuid,
+    ) -> Result<models::Document, SinkronError> {
+        let state = self.state.lock().unwrap();
+        state
+            .documents
+            .get(&id)
+            .filter(|doc| doc.col_id == col)
+            .cloned()
+            .ok_or_else(|| SinkronError::not_found("Document not found"))
+    }
+
+    async fn document_exists(&self, id: Uuid) -> Result<bool, SinkronError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.documents.contains_key(&id))
+    }
+
+    async fn create_document(
+        &self,
+        doc: models::NewDocument<'_>,
+    ) -> Result<chrono::DateTime<chrono::Utc>, SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        let now = chrono::Utc::now();
+        let doc = models::Document {
+            id: doc.id,
+            created_at: now,
+            updated_at: now,
+            col_id: doc.col_id,
+            colrev: doc.colrev,
+            data: Some(doc.data),
+            is_deleted: false,
+            permissions: doc.permissions.to_string(),
+            created_by: doc.created_by.map(|user| user.to_string()),
+        };
+        state.documents.insert(doc.id, doc);
+        Ok(now)
+    }
+
+    async fn update_document(
+        &self,
+        id: Uuid,
+        update: models::DocumentUpdate<'_>,
+    ) -> Result<chrono::DateTime<chrono::Utc>, SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(doc) = state.documents.get_mut(&id) else {
+            return Err(SinkronError::not_found("Document not found"));
+        };
+        doc.colrev = update.colrev;
+        doc.is_deleted = update.is_deleted;
+        doc.data = update.data.cloned();
+        doc.updated_at = chrono::Utc::now();
+        Ok(doc.updated_at)
+    }
+
+    async fn update_document_permissions(
+        &self,
+        col: &str,
+        id: Uuid,
+        permissions: &str,
+        colrev: i64,
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        match state.documents.get_mut(&id) {
+            Some(doc) if doc.col_id == col => {
+                doc.permissions = permissions.to_string();
+                doc.colrev = colrev;
+                Ok(())
+            }
+            _ => Err(SinkronError::not_found("Document not found")),
+        }
+    }
+
+    async fn get_documents_page(
+        &self,
+        col: &str,
+        since: i64,
+        until: i64,
+        skip_deleted: bool,
+        limit: i64,
+    ) -> Result<Vec<models::Document>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let mut docs: Vec<models::Document> = state
+            .documents
+            .values()
+            .filter(|doc| doc.col_id == col)
+            .filter(|doc| doc.colrev > since && doc.colrev <= until)
+            .filter(|doc| !(skip_deleted && doc.is_deleted))
+            .cloned()
+            .collect();
+        docs.sort_by_key(|doc| doc.colrev);
+        docs.truncate(limit as usize);
+        Ok(docs)
+    }
+
+    async fn purge_deleted_documents(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize, SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let len = state.documents.len();
+        state.documents.retain(|_, doc| {
+            let is_purged = doc.is_deleted && doc.updated_at < before;
+            if is_purged {
+                if let Some(col) = state.collections.get_mut(&doc.col_id) {
+                    col.purged_colrev = col.purged_colrev.max(doc.colrev);
+                }
+            }
+            !is_purged
+        });
+        Ok(len - state.documents.len())
+    }
+
+    async fn list_documents_permissions(
+        &self,
+        cursor: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<(Uuid, String, String)>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let docs = state
+            .documents
+            .values()
+            .filter(|doc| cursor.is_none_or(|cursor| doc.id > cursor))
+            .take(limit as usize)
+            .map(|doc| (doc.id, doc.col_id.clone(), doc.permissions.clone()))
+            .collect();
+        Ok(docs)
+    }
+
+    // Groups
+
+    async fn get_group(&self, id: &str) -> Result<models::Group, SinkronError> {
+        let state = self.state.lock().unwrap();
+        match state.groups.get(id) {
+            Some(metadata) => Ok(models::Group {
+                id: id.to_string(),
+                metadata: metadata.clone(),
+            }),
+            None => Err(SinkronError::not_found("Group not found")),
+        }
+    }
+
+    async fn create_group(
+        &self,
+        group: models::Group,
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        if state.groups.contains_key(&group.id) {
+            return Err(SinkronError::internal("Duplicate group id"));
+        }
+        state.groups.insert(group.id, group.metadata);
+        Ok(())
+    }
+
+    async fn update_group_metadata(
+        &self,
+        id: &str,
+        metadata: Option<String>,
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        match state.groups.get_mut(id) {
+            Some(value) => {
+                *value = metadata;
+                Ok(())
+            }
+            None => Err(SinkronError::not_found("Group not found")),
+        }
+    }
+
+    async fn delete_group(
+        &self,
+        id: &str,
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        if state.groups.remove(id).is_none() {
+            return Err(SinkronError::not_found("Group not found"));
+        }
+        let mut groups = BTreeSet::from([id.to_string()]);
+        let mut next = vec![id.to_string()];
+        while let Some(group) = next.pop() {
+            for (parent, child) in state.subgroups.iter() {
+                if *parent == group && groups.insert(child.clone()) {
+                    next.push(child.clone());
+                }
+            }
+        }
+        let users: BTreeSet<String> = state
+            .members
+            .iter()
+            .filter(|(group, _)| groups.contains(group))
+            .map(|(_, user)| user.clone())
+            .collect();
+        state.members.retain(|(group, _)| group != id);
+        state
+            .subgroups
+            .retain(|(parent, child)| parent != id && child != id);
+        Ok(users.into_iter().collect())
+    }
+
+    async fn list_groups(
+        &self,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let groups = state
+            .groups
+            .keys()
+            .filter(|id| cursor.is_none_or(|cursor| id.as_str() > cursor))
+            .take(limit as usize)
+            .cloned()
+            .collect();
+        Ok(groups)
+    }
+
+    // Members
+
+    async fn get_user_groups(
+        &self,
+        user: &str,
+    ) -> Result<Vec<String>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let groups = state
+            .members
+            .iter()
+            .filter(|(_, member)| member == user)
+            .map(|(group, _)| group.clone())
+            .collect();
+        Ok(groups)
+    }
+
+    async fn get_members(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let users: BTreeSet<String> = state
+            .members
+            .iter()
+            .filter(|(group, _)| groups.contains(group))
+            .map(|(_, user)| user.clone())
+            .collect();
+        Ok(users.into_iter().collect())
+    }
+
+    async fn list_group_members(
+        &self,
+        group: &str,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let users = state
+            .members
+            .iter()
+            .filter(|(member_group, _)| member_group == group)
+            .map(|(_, user)| user)
+            .filter(|user| cursor.is_none_or(|cursor| user.as_str() > cursor))
+            .take(limit as usize)
+            .cloned()
+            .collect();
+        Ok(users)
+    }
+
+    async fn add_members(
+        &self,
+        group: &str,
+        users: &[String],
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.groups.contains_key(group) {
+            return Err(SinkronError::not_found("Group not found"));
+        }
+        for user in users {
+            state.members.insert((group.to_string(), user.clone()));
+        }
+        Ok(())
+    }
+
+    async fn remove_members(
+        &self,
+        group: &str,
+        users: &[String],
+    ) -> Result<usize, SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        let mut num = 0;
+        for user in users {
+            if state.members.remove(&(group.to_string(), user.clone())) {
+                num += 1;
+            }
+        }
+        Ok(num)
+    }
+
+    async fn remove_user_from_all_groups(
+        &self,
+        user: &str,
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        state.members.retain(|(_, member)| member != user);
+        Ok(())
+    }
+
+    // Subgroups
+
+    async fn get_parent_groups(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let parents = state
+            .subgroups
+            .iter()
+            .filter(|(_, child)| groups.contains(child))
+            .map(|(parent, _)| parent.clone())
+            .collect();
+        Ok(parents)
+    }
+
+    async fn get_child_groups(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        let state = self.state.lock().unwrap();
+        let children = state
+            .subgroups
+            .iter()
+            .filter(|(parent, _)| groups.contains(parent))
+            .map(|(_, child)| child.clone())
+            .collect();
+        Ok(children)
+    }
+
+    async fn add_subgroup(
+        &self,
+        parent: &str,
+        child: &str,
+    ) -> Result<(), SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .subgroups
+            .insert((parent.to_string(), child.to_string()));
+        Ok(())
+    }
+
+    async fn remove_subgroup(
+        &self,
+        parent: &str,
+        child: &str,
+    ) -> Result<usize, SinkronError> {
+        let mut state = self.state.lock().unwrap();
+        let removed = state
+            .subgroups
+            .remove(&(parent.to_string(), child.to_string()));
+        Ok(removed as usize)
+    }
+}
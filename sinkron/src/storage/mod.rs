@@ -0,0 +1,301 @@
+mod memory;
+mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(test)]
+mod tests;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::db;
+use crate::error::SinkronError;
+use crate::models;
+
+pub use memory::MemoryStorage;
+pub use postgres::PostgresStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+// Storage backend, Postgres is used by default
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum StorageConfig {
+    // Connection options are taken from the `db` config
+    #[default]
+    Postgres,
+    // Embedded database, for single-node installs
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        path: String,
+    },
+    // Data is lost on restart, useful for testing
+    Memory,
+}
+
+pub async fn create_storage(
+    config: StorageConfig,
+    db: Option<db::DbConfig>,
+) -> Result<Arc<dyn Storage>, String> {
+    match config {
+        StorageConfig::Postgres => {
+            let Some(db) = db else {
+                return Err("Postgres storage requires \"db\" config".into());
+            };
+            let schema = db.schema.clone();
+            let pool = db::create_pool(db).await?;
+            Ok(Arc::new(PostgresStorage::new(pool).schema(schema)))
+        }
+        #[cfg(feature = "sqlite")]
+        StorageConfig::Sqlite { path } => {
+            let storage = SqliteStorage::open(&path)
+                .map_err(|err| format!("Couldn't open database: {}", err))?;
+            Ok(Arc::new(storage))
+        }
+        StorageConfig::Memory => Ok(Arc::new(MemoryStorage::new())),
+    }
+}
+
+// Migration of the storage schema, in the order of application
+pub struct MigrationStatus {
+    pub name: String,
+    pub is_applied: bool,
+}
+
+// New colrev and usage of the collection after the change
+pub struct CollectionUsage {
+    pub colrev: i64,
+    pub documents_count: i64,
+    pub size: i64,
+}
+
+// Persistence of collections, documents, groups and their members.
+//
+// Storage only stores and loads the data, all checks (permissions, size
+// limits, etc.) are performed by the callers. Methods that operate on a single
+// entity return `NotFound` error when it doesn't exist.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // Prepares the storage for use, e.g. runs migrations
+    async fn migrate(&self) -> Result<(), SinkronError>;
+
+    async fn migration_status(
+        &self,
+    ) -> Result<Vec<MigrationStatus>, SinkronError>;
+
+    // Reverts the last applied migration and returns its name, returns
+    // `None` when there are no applied migrations
+    async fn revert_migration(&self) -> Result<Option<String>, SinkronError>;
+
+    // Collections
+
+    // Returns `UnprocessableContent` error when id is already taken
+    async fn create_collection(
+        &self,
+        col: models::NewCollection,
+    ) -> Result<models::Collection, SinkronError>;
+
+    async fn get_collection(
+        &self,
+        id: &str,
+    ) -> Result<models::Collection, SinkronError>;
+
+    // Returns collections ordered by id, starting after the `cursor`
+    async fn list_collections(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<models::Collection>, SinkronError>;
+
+    async fn update_collection_permissions(
+        &self,
+        id: &str,
+        permissions: &str,
+    ) -> Result<(), SinkronError>;
+
+    async fn update_collection_quota(
+        &self,
+        id: &str,
+        quota: Option<i64>,
+    ) -> Result<(), SinkronError>;
+
+    // Increments colrev of the collection and updates its usage
+    async fn increment_colrev(
+        &self,
+        id: &str,
+        documents_delta: i64,
+        size_delta: i64,
+    ) -> Result<CollectionUsage, SinkronError>;
+
+    // Creates collection together with its documents in a single transaction,
+    // all fields are stored as is. Returns `UnprocessableContent` error when
+    // the collection id or any of the document ids is already taken
+    async fn import_collection(
+        &self,
+        col: models::Collection,
+        docs: Vec<models::Document>,
+    ) -> Result<(), SinkronError>;
+
+    // Documents
+
+    async fn get_document(
+        &self,
+        col: &str,
+        id: Uuid,
+    ) -> Result<models::Document, SinkronError>;
+
+    // Checks if the document exists in any collection
+    async fn document_exists(&self, id: Uuid) -> Result<bool, SinkronError>;
+
+    // Returns time of creation
+    async fn create_document(
+        &self,
+        doc: models::NewDocument<'_>,
+    ) -> Result<chrono::DateTime<chrono::Utc>, SinkronError>;
+
+    // Returns time of the update
+    async fn update_document(
+        &self,
+        id: Uuid,
+        update: models::DocumentUpdate<'_>,
+    ) -> Result<chrono::DateTime<chrono::Utc>, SinkronError>;
+
+    // Colrev of the document is changed as well, so clients that sync later
+    // get the document with new permissions
+    async fn update_document_permissions(
+        &self,
+        col: &str,
+        id: Uuid,
+        permissions: &str,
+        colrev: i64,
+    ) -> Result<(), SinkronError>;
+
+    // Returns documents of the collection with colrev in the range
+    // (since, until], ordered by colrev
+    async fn get_documents_page(
+        &self,
+        col: &str,
+        since: i64,
+        until: i64,
+        skip_deleted: bool,
+        limit: i64,
+    ) -> Result<Vec<models::Document>, SinkronError>;
+
+    // Removes deleted documents that were last updated before the given time
+    // and refs to them, moves `purged_colrev` of the collections past the
+    // removed colrevs, returns number of removed documents
+    async fn purge_deleted_documents(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize, SinkronError>;
+
+    // Returns id, collection and permissions of the documents in all
+    // collections, ordered by id, starting after the `cursor`
+    async fn list_documents_permissions(
+        &self,
+        cursor: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<(Uuid, String, String)>, SinkronError>;
+
+    // Groups
+
+    async fn get_group(&self, id: &str) -> Result<models::Group, SinkronError>;
+
+    async fn create_group(
+        &self,
+        group: models::Group,
+    ) -> Result<(), SinkronError>;
+
+    async fn update_group_metadata(
+        &self,
+        id: &str,
+        metadata: Option<String>,
+    ) -> Result<(), SinkronError>;
+
+    // Deletes group together with its members and subgroups in a single
+    // transaction. Returns users that were members of the group or of the
+    // groups included into it, they are selected in the same transaction.
+    async fn delete_group(&self, id: &str)
+        -> Result<Vec<String>, SinkronError>;
+
+    // Returns ids of the groups ordered by id, starting after the `cursor`
+    async fn list_groups(
+        &self,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, SinkronError>;
+
+    // Members
+
+    // Returns groups that the user is a direct member of
+    async fn get_user_groups(
+        &self,
+        user: &str,
+    ) -> Result<Vec<String>, SinkronError>;
+
+    // Returns users that are members of any of the groups
+    async fn get_members(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError>;
+
+    // Returns members of the group ordered by id, starting after the `cursor`
+    async fn list_group_members(
+        &self,
+        group: &str,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, SinkronError>;
+
+    // Adds users to the group in a single transaction, users that are
+    // already members are skipped
+    async fn add_members(
+        &self,
+        group: &str,
+        users: &[String],
+    ) -> Result<(), SinkronError>;
+
+    // Returns number of removed members
+    async fn remove_members(
+        &self,
+        group: &str,
+        users: &[String],
+    ) -> Result<usize, SinkronError>;
+
+    async fn remove_user_from_all_groups(
+        &self,
+        user: &str,
+    ) -> Result<(), SinkronError>;
+
+    // Subgroups
+
+    // Returns groups that directly include any of the `groups`
+    async fn get_parent_groups(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError>;
+
+    // Returns groups that are directly included in any of the `groups`
+    async fn get_child_groups(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError>;
+
+    // Does nothing if the group is already included
+    async fn add_subgroup(
+        &self,
+        parent: &str,
+        child: &str,
+    ) -> Result<(), SinkronError>;
+
+    // Returns number of removed subgroups
+    async fn remove_subgroup(
+        &self,
+        parent: &str,
+        child: &str,
+    ) -> Result<usize, SinkronError>;
+}
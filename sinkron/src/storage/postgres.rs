@@ -0,0 +1,694 @@
+use async_trait::async_trait;
+use diesel::prelude::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::db;
+use crate::error::{internal_error, SinkronError};
+use crate::models;
+use crate::schema;
+use crate::storage::{CollectionUsage, MigrationStatus, Storage};
+
+// Members of the group and of all groups included into it, UNION skips
+// visited groups, so this terminates even if the hierarchy has a cycle
+const AFFECTED_USERS_QUERY: &str = "WITH RECURSIVE descendants(id) AS ( \
+    SELECT $1::text \
+    UNION SELECT subgroups.child FROM subgroups \
+    JOIN descendants ON subgroups.parent = descendants.id) \
+    SELECT DISTINCT members.\"user\" FROM members \
+    WHERE members.\"group\" IN (SELECT id FROM descendants)";
+
+// Moves purge horizon of the collections past the colrevs of the purged
+// documents and of the refs to them
+const PURGED_COLREV_QUERY: &str = "UPDATE collections \
+    SET purged_colrev = GREATEST(collections.purged_colrev, purged.colrev) \
+    FROM (SELECT col_id, max(colrev) AS colrev FROM ( \
+        SELECT col_id, colrev FROM documents \
+        WHERE is_deleted AND updated_at < $1 \
+        UNION ALL SELECT refs.col_id, refs.colrev FROM refs \
+        JOIN documents ON refs.doc_id = documents.id \
+        WHERE documents.is_deleted AND documents.updated_at < $1 \
+    ) AS changes GROUP BY col_id) AS purged \
+    WHERE collections.id = purged.col_id";
+
+#[derive(QueryableByName)]
+struct AffectedUser {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    user: String,
+}
+
+pub struct PostgresStorage {
+    pool: db::DbConnectionPool,
+    // Schema that is created before running migrations
+    schema: Option<String>,
+}
+
+impl PostgresStorage {
+    pub fn new(pool: db::DbConnectionPool) -> Self {
+        Self { pool, schema: None }
+    }
+
+    // Connections of the pool should have the schema in the "search_path"
+    pub fn schema(mut self, schema: Option<String>) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    async fn connect(&self) -> Result<db::DbConnection, SinkronError> {
+        self.pool.get().await.map_err(internal_error)
+    }
+}
+
+fn not_found(msg: &str) -> impl Fn(diesel::result::Error) -> SinkronError + '_ {
+    move |err| match err {
+        diesel::NotFound => SinkronError::not_found(msg),
+        err => SinkronError::internal(&err.to_string()),
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        if let Some(schema) = &self.schema {
+            let query = format!(
+                "CREATE SCHEMA IF NOT EXISTS {}",
+                db::quote_ident(schema)
+            );
+            diesel::sql_query(query)
+                .execute(&mut conn)
+                .await
+                .map_err(internal_error)?;
+        }
+        db::run_migrations(deadpool::managed::Object::take(conn))
+            .await
+            .map_err(|err| SinkronError::internal(&err))
+    }
+
+    async fn migration_status(
+        &self,
+    ) -> Result<Vec<MigrationStatus>, SinkronError> {
+        let conn = self.connect().await?;
+        let status =
+            db::migration_status(deadpool::managed::Object::take(conn))
+                .await
+                .map_err(|err| SinkronError::internal(&err))?;
+        let status = status
+            .into_iter()
+            .map(|(name, is_applied)| MigrationStatus { name, is_applied })
+            .collect();
+        Ok(status)
+    }
+
+    async fn revert_migration(&self) -> Result<Option<String>, SinkronError> {
+        let conn = self.connect().await?;
+        db::revert_migration(deadpool::managed::Object::take(conn))
+            .await
+            .map_err(|err| SinkronError::internal(&err))
+    }
+
+    // Collections
+
+    async fn create_collection(
+        &self,
+        col: models::NewCollection,
+    ) -> Result<models::Collection, SinkronError> {
+        let mut conn = self.connect().await?;
+        let cnt: i64 = schema::collections::table
+            .filter(schema::collections::id.eq(&col.id))
+            .count()
+            .get_result(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        if cnt != 0 {
+            return Err(SinkronError::unprocessable("Duplicate collection id"));
+        }
+        diesel::insert_into(schema::collections::table)
+            .values(&col)
+            .returning(models::Collection::as_returning())
+            .get_result(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn get_collection(
+        &self,
+        id: &str,
+    ) -> Result<models::Collection, SinkronError> {
+        let mut conn = self.connect().await?;
+        schema::collections::table
+            .find(id)
+            .first(&mut conn)
+            .await
+            .map_err(not_found("Collection not found"))
+    }
+
+    async fn list_collections(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<models::Collection>, SinkronError> {
+        let mut conn = self.connect().await?;
+        let mut req = schema::collections::table
+            .order(schema::collections::id.asc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(prefix) = prefix {
+            let escaped = prefix
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            req = req
+                .filter(schema::collections::id.like(format!("{}%", escaped)));
+        }
+        if let Some(cursor) = cursor {
+            req = req.filter(schema::collections::id.gt(cursor));
+        }
+        req.get_results(&mut conn).await.map_err(internal_error)
+    }
+
+    async fn update_collection_permissions(
+        &self,
+        id: &str,
+        permissions: &str,
+    ) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        diesel::update(schema::collections::table)
+            .filter(schema::collections::id.eq(id))
+            .set(schema::collections::permissions.eq(permissions))
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn update_collection_quota(
+        &self,
+        id: &str,
+        quota: Option<i64>,
+    ) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        diesel::update(schema::collections::table)
+            .filter(schema::collections::id.eq(id))
+            .set(schema::collections::quota.eq(quota))
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn increment_colrev(
+        &self,
+        id: &str,
+        documents_delta: i64,
+        size_delta: i64,
+    ) -> Result<CollectionUsage, SinkronError> {
+        use schema::collections;
+        let mut conn = self.connect().await?;
+        let (colrev, documents_count, size): (i64, i64, i64) =
+            diesel::update(collections::table)
+                .filter(collections::id.eq(id))
+                .set((
+                    collections::colrev.eq(collections::colrev + 1),
+                    collections::documents_count
+                        .eq(collections::documents_count + documents_delta),
+                    collections::size.eq(collections::size + size_delta),
+                ))
+                .returning((
+                    collections::colrev,
+                    collections::documents_count,
+                    collections::size,
+                ))
+                .get_result(&mut conn)
+                .await
+                .map_err(internal_error)?;
+        Ok(CollectionUsage {
+            colrev,
+            documents_count,
+            size,
+        })
+    }
+
+    async fn import_collection(
+        &self,
+        col: models::Collection,
+        docs: Vec<models::Document>,
+    ) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        conn.transaction::<_, SinkronError, _>(|conn| {
+            async move {
+                let num = diesel::insert_into(schema::collections::table)
+                    .values(&col)
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+                if num == 0 {
+                    return Err(SinkronError::unprocessable(
+                        "Duplicate collection id",
+                    ));
+                }
+                // Number of bind parameters in the query is limited
+                for chunk in docs.chunks(1000) {
+                    let num = diesel::insert_into(schema::documents::table)
+                        .values(chunk)
+                        .on_conflict_do_nothing()
+                        .execute(conn)
+                        .await?;
+                    if num != chunk.len() {
+                        return Err(SinkronError::unprocessable(
+                            "Duplicate document id",
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    // Documents
+
+    async fn get_document(
+        &self,
+        col: &str,
+        id: Uuid,
+    ) -> Result<models::Document, SinkronError> {
+        let mut conn = self.connect().await?;
+        schema::documents::table
+            .find(id)
+            .filter(schema::documents::col_id.eq(col))
+            .first(&mut conn)
+            .await
+            .map_err(not_found("Document not found"))
+    }
+
+    async fn document_exists(&self, id: Uuid) -> Result<bool, SinkronError> {
+        let mut conn = self.connect().await?;
+        let cnt: i64 = schema::documents::table
+            .filter(schema::documents::id.eq(&id))
+            .count()
+            .get_result(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        Ok(cnt != 0)
+    }
+
+    async fn create_document(
+        &self,
+        doc: models::NewDocument<'_>,
+    ) -> Result<chrono::DateTime<chrono::Utc>, SinkronError> {
+        let mut conn = self.connect().await?;
+        diesel::insert_into(schema::documents::table)
+            .values(&doc)
+            .returning(schema::documents::created_at)
+            .get_result(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn update_document(
+        &self,
+        id: Uuid,
+        update: models::DocumentUpdate<'_>,
+    ) -> Result<chrono::DateTime<chrono::Utc>, SinkronError> {
+        let mut conn = self.connect().await?;
+        diesel::update(schema::documents::table)
+            .filter(schema::documents::id.eq(&id))
+            .set(update)
+            .returning(schema::documents::updated_at)
+            .get_result(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn update_document_permissions(
+        &self,
+        col: &str,
+        id: Uuid,
+        permissions: &str,
+        colrev: i64,
+    ) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        let num = diesel::update(schema::documents::table)
+            .filter(schema::documents::id.eq(id))
+            .filter(schema::documents::col_id.eq(col))
+            .set((
+                schema::documents::permissions.eq(permissions),
+                schema::documents::colrev.eq(colrev),
+            ))
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        if num == 0 {
+            Err(SinkronError::not_found("Document not found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_documents_page(
+        &self,
+        col: &str,
+        since: i64,
+        until: i64,
+        skip_deleted: bool,
+        limit: i64,
+    ) -> Result<Vec<models::Document>, SinkronError> {
+        let mut conn = self.connect().await?;
+        let mut req = schema::documents::table
+            .filter(schema::documents::col_id.eq(col))
+            .filter(schema::documents::colrev.gt(since))
+            .filter(schema::documents::colrev.le(until))
+            .order(schema::documents::colrev.asc())
+            .limit(limit)
+            .into_boxed();
+        if skip_deleted {
+            req = req.filter(schema::documents::is_deleted.eq(false));
+        }
+        req.get_results(&mut conn).await.map_err(internal_error)
+    }
+
+    async fn purge_deleted_documents(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize, SinkronError> {
+        let mut conn = self.connect().await?;
+        conn.transaction::<_, SinkronError, _>(|conn| {
+            async move {
+                diesel::sql_query(PURGED_COLREV_QUERY)
+                    .bind::<diesel::sql_types::Timestamptz, _>(before)
+                    .execute(conn)
+                    .await?;
+                let purged = schema::documents::table
+                    .select(schema::documents::id)
+                    .filter(schema::documents::is_deleted.eq(true))
+                    .filter(schema::documents::updated_at.lt(before));
+                diesel::delete(schema::refs::table)
+                    .filter(schema::refs::doc_id.eq_any(purged))
+                    .execute(conn)
+                    .await?;
+                let num = diesel::delete(schema::documents::table)
+                    .filter(schema::documents::is_deleted.eq(true))
+                    .filter(schema::documents::updated_at.lt(before))
+                    .execute(conn)
+                    .await?;
+                Ok(num)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    async fn list_documents_permissions(
+        &self,
+        cursor: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<(Uuid, String, String)>, SinkronError> {
+        let mut conn = self.connect().await?;
+        let mut req = schema::documents::table
+            .select((
+                schema::documents::id,
+                schema::documents::col_id,
+                schema::documents::permissions,
+            ))
+            .order(schema::documents::id.asc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(cursor) = cursor {
+            req = req.filter(schema::documents::id.gt(cursor));
+        }
+        req.get_results(&mut conn).await.map_err(internal_error)
+    }
+
+    // Groups
+
+    async fn get_group(&self, id: &str) -> Result<models::Group, SinkronError> {
+        let mut conn = self.connect().await?;
+        schema::groups::table
+            .find(id)
+            .first(&mut conn)
+            .await
+            .map_err(not_found("Group not found"))
+    }
+
+    async fn create_group(
+        &self,
+        group: models::Group,
+    ) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        diesel::insert_into(schema::groups::table)
+            .values(&group)
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn update_group_metadata(
+        &self,
+        id: &str,
+        metadata: Option<String>,
+    ) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        let num = diesel::update(schema::groups::table)
+            .filter(schema::groups::id.eq(id))
+            .set(schema::groups::metadata.eq(metadata))
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        if num == 0 {
+            Err(SinkronError::not_found("Group not found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn delete_group(
+        &self,
+        id: &str,
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut conn = self.connect().await?;
+        conn.transaction::<_, SinkronError, _>(|conn| {
+            async move {
+                let users: Vec<AffectedUser> =
+                    diesel::sql_query(AFFECTED_USERS_QUERY)
+                        .bind::<diesel::sql_types::Text, _>(id)
+                        .load(conn)
+                        .await?;
+                diesel::delete(schema::subgroups::table)
+                    .filter(
+                        schema::subgroups::parent
+                            .eq(id)
+                            .or(schema::subgroups::child.eq(id)),
+                    )
+                    .execute(conn)
+                    .await?;
+                diesel::delete(schema::members::table)
+                    .filter(schema::members::group.eq(id))
+                    .execute(conn)
+                    .await?;
+                let num = diesel::delete(schema::groups::table)
+                    .filter(schema::groups::id.eq(id))
+                    .execute(conn)
+                    .await?;
+                if num == 0 {
+                    return Err(SinkronError::not_found("Group not found"));
+                }
+                Ok(users.into_iter().map(|row| row.user).collect())
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    async fn list_groups(
+        &self,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut conn = self.connect().await?;
+        let mut req = schema::groups::table
+            .select(schema::groups::id)
+            .order(schema::groups::id.asc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(cursor) = cursor {
+            req = req.filter(schema::groups::id.gt(cursor));
+        }
+        req.get_results(&mut conn).await.map_err(internal_error)
+    }
+
+    // Members
+
+    async fn get_user_groups(
+        &self,
+        user: &str,
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut conn = self.connect().await?;
+        schema::members::table
+            .filter(schema::members::user.eq(user))
+            .select(schema::members::group)
+            .get_results(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn get_members(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut conn = self.connect().await?;
+        schema::members::table
+            .filter(schema::members::group.eq_any(groups))
+            .select(schema::members::user)
+            .distinct()
+            .get_results(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn list_group_members(
+        &self,
+        group: &str,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut conn = self.connect().await?;
+        let mut req = schema::members::table
+            .filter(schema::members::group.eq(group))
+            .select(schema::members::user)
+            .order(schema::members::user.asc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(cursor) = cursor {
+            req = req.filter(schema::members::user.gt(cursor));
+        }
+        req.get_results(&mut conn).await.map_err(internal_error)
+    }
+
+    async fn add_members(
+        &self,
+        group: &str,
+        users: &[String],
+    ) -> Result<(), SinkronError> {
+        let new_members: Vec<models::Member> = users
+            .iter()
+            .map(|user| models::Member {
+                user: user.clone(),
+                group: group.to_string(),
+            })
+            .collect();
+        let mut conn = self.connect().await?;
+        conn.transaction::<_, SinkronError, _>(|conn| {
+            async move {
+                let exists: i64 = schema::groups::table
+                    .filter(schema::groups::id.eq(group))
+                    .count()
+                    .get_result(conn)
+                    .await?;
+                if exists == 0 {
+                    return Err(SinkronError::not_found("Group not found"));
+                }
+                diesel::insert_into(schema::members::table)
+                    .values(&new_members)
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    async fn remove_members(
+        &self,
+        group: &str,
+        users: &[String],
+    ) -> Result<usize, SinkronError> {
+        let mut conn = self.connect().await?;
+        diesel::delete(schema::members::table)
+            .filter(schema::members::group.eq(group))
+            .filter(schema::members::user.eq_any(users))
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn remove_user_from_all_groups(
+        &self,
+        user: &str,
+    ) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        diesel::delete(schema::members::table)
+            .filter(schema::members::user.eq(user))
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    // Subgroups
+
+    async fn get_parent_groups(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut conn = self.connect().await?;
+        schema::subgroups::table
+            .filter(schema::subgroups::child.eq_any(groups))
+            .select(schema::subgroups::parent)
+            .get_results(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn get_child_groups(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        let mut conn = self.connect().await?;
+        schema::subgroups::table
+            .filter(schema::subgroups::parent.eq_any(groups))
+            .select(schema::subgroups::child)
+            .get_results(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn add_subgroup(
+        &self,
+        parent: &str,
+        child: &str,
+    ) -> Result<(), SinkronError> {
+        let mut conn = self.connect().await?;
+        let new_subgroup = models::Subgroup {
+            parent: parent.to_string(),
+            child: child.to_string(),
+        };
+        diesel::insert_into(schema::subgroups::table)
+            .values(&new_subgroup)
+            .on_conflict_do_nothing()
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn remove_subgroup(
+        &self,
+        parent: &str,
+        child: &str,
+    ) -> Result<usize, SinkronError> {
+        let mut conn = self.connect().await?;
+        diesel::delete(schema::subgroups::table)
+            .filter(schema::subgroups::parent.eq(parent))
+            .filter(schema::subgroups::child.eq(child))
+            .execute(&mut conn)
+            .await
+            .map_err(internal_error)
+    }
+}
@@ -0,0 +1,846 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::types::Type;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use uuid::Uuid;
+
+use crate::error::{internal_error, SinkronError};
+use crate::models;
+use crate::storage::{CollectionUsage, MigrationStatus, Storage};
+
+// Each migration is applied once, the number of applied migrations is
+// stored in the `user_version` of the database. Migration "{name}.sql" is
+// reverted with "{name}.down.sql".
+const MIGRATIONS: &[(&str, &str, &str)] = &[
+    (
+        "0001_initial",
+        include_str!("../../migrations_sqlite/0001_initial.sql"),
+        include_str!("../../migrations_sqlite/0001_initial.down.sql"),
+    ),
+    (
+        "0002_purged_colrev",
+        include_str!("../../migrations_sqlite/0002_purged_colrev.sql"),
+        include_str!("../../migrations_sqlite/0002_purged_colrev.down.sql"),
+    ),
+];
+
+// Members of the group and of all groups included into it, UNION skips
+// visited groups, so this terminates even if the hierarchy has a cycle
+const AFFECTED_USERS_QUERY: &str = "WITH RECURSIVE descendants(id) AS ( \
+    SELECT ?1 \
+    UNION SELECT subgroups.child FROM subgroups \
+    JOIN descendants ON subgroups.parent = descendants.id) \
+    SELECT DISTINCT user FROM members \
+    WHERE \"group\" IN (SELECT id FROM descendants)";
+
+// Moves purge horizon of the collections past the colrevs of the purged
+// documents and of the refs to them
+const PURGED_COLREV_QUERY: &str = "UPDATE collections \
+    SET purged_colrev = max(collections.purged_colrev, purged.colrev) \
+    FROM (SELECT col_id, max(colrev) AS colrev FROM ( \
+        SELECT col_id, colrev FROM documents \
+        WHERE is_deleted AND updated_at < ?1 \
+        UNION ALL SELECT refs.col_id, refs.colrev FROM refs \
+        JOIN documents ON refs.doc_id = documents.id \
+        WHERE documents.is_deleted AND documents.updated_at < ?1 \
+    ) GROUP BY col_id) AS purged \
+    WHERE collections.id = purged.col_id";
+
+fn get_user_version(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+const COLLECTION_COLUMNS: &str = "id, is_ref, colrev, permissions, \
+    documents_count, size, quota, purged_colrev";
+
+const DOCUMENT_COLUMNS: &str = "id, created_at, updated_at, col_id, colrev, \
+    data, is_deleted, permissions, created_by";
+
+fn get_uuid(row: &Row, idx: usize) -> rusqlite::Result<Uuid> {
+    let value: String = row.get(idx)?;
+    Uuid::parse_str(&value).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(
+            idx,
+            Type::Text,
+            Box::new(err),
+        )
+    })
+}
+
+fn collection_from_row(row: &Row) -> rusqlite::Result<models::Collection> {
+    Ok(models::Collection {
+        id: row.get(0)?,
+        is_ref: row.get(1)?,
+        colrev: row.get(2)?,
+        permissions: row.get(3)?,
+        documents_count: row.get(4)?,
+        size: row.get(5)?,
+        quota: row.get(6)?,
+        purged_colrev: row.get(7)?,
+    })
+}
+
+fn document_from_row(row: &Row) -> rusqlite::Result<models::Document> {
+    Ok(models::Document {
+        id: get_uuid(row, 0)?,
+        created_at: row.get(1)?,
+        updated_at: row.get(2)?,
+        col_id: row.get(3)?,
+        colrev: row.get(4)?,
+        data: row.get(5)?,
+        is_deleted: row.get(6)?,
+        permissions: row.get(7)?,
+        created_by: row.get(8)?,
+    })
+}
+
+// List of placeholders for the `IN` clause
+fn placeholders(num: usize) -> String {
+    vec!["?"; num].join(", ")
+}
+
+// Embedded storage for single-node installs. Sqlite doesn't support
+// concurrent writes, so all queries use single connection.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Runs query on the blocking thread pool
+    async fn call<T, F>(&self, f: F) -> Result<T, SinkronError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await
+        .map_err(internal_error)?
+        .map_err(internal_error)
+    }
+
+    async fn list_strings(
+        &self,
+        query: String,
+        values: Vec<String>,
+    ) -> Result<Vec<String>, SinkronError> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let rows =
+                stmt.query_map(params_from_iter(values), |row| row.get(0))?;
+            rows.collect()
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> Result<(), SinkronError> {
+        self.call(|conn| {
+            let version = get_user_version(conn)?;
+            for (i, (_, up, _)) in MIGRATIONS.iter().enumerate().skip(version) {
+                let tx = conn.transaction()?;
+                tx.execute_batch(up)?;
+                tx.pragma_update(None, "user_version", i + 1)?;
+                tx.commit()?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn migration_status(
+        &self,
+    ) -> Result<Vec<MigrationStatus>, SinkronError> {
+        let version = self.call(|conn| get_user_version(conn)).await?;
+        let status = MIGRATIONS
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _, _))| MigrationStatus {
+                name: name.to_string(),
+                is_applied: i < version,
+            })
+            .collect();
+        Ok(status)
+    }
+
+    async fn revert_migration(&self) -> Result<Option<String>, SinkronError> {
+        self.call(|conn| {
+            let version = get_user_version(conn)?;
+            let Some((name, _, down)) =
+                version.checked_sub(1).and_then(|i| MIGRATIONS.get(i))
+            else {
+                return Ok(None);
+            };
+            let tx = conn.transaction()?;
+            tx.execute_batch(down)?;
+            tx.pragma_update(None, "user_version", version - 1)?;
+            tx.commit()?;
+            Ok(Some(name.to_string()))
+        })
+        .await
+    }
+
+    // Collections
+
+    async fn create_collection(
+        &self,
+        col: models::NewCollection,
+    ) -> Result<models::Collection, SinkronError> {
+        let created = self
+            .call(move |conn| {
+                let query = format!(
+                    "INSERT INTO collections (id, is_ref, permissions, quota) \
+                    VALUES (?1, ?2, ?3, ?4) ON CONFLICT DO NOTHING \
+                    RETURNING {}",
+                    COLLECTION_COLUMNS
+                );
+                conn.query_row(
+                    &query,
+                    params![col.id, col.is_ref, col.permissions, col.quota],
+                    collection_from_row,
+                )
+                .optional()
+            })
+            .await?;
+        created.ok_or_else(|| {
+            SinkronError::unprocessable("Duplicate collection id")
+        })
+    }
+
+    async fn get_collection(
+        &self,
+        id: &str,
+    ) -> Result<models::Collection, SinkronError> {
+        let id = id.to_string();
+        let col = self
+            .call(move |conn| {
+                let query = format!(
+                    "SELECT {} FROM collections WHERE id = ?1",
+                    COLLECTION_COLUMNS
+                );
+                conn.query_row(&query, params![id], collection_from_row)
+                    .optional()
+            })
+            .await?;
+        col.ok_or_else(|| SinkronError::not_found("Collection not found"))
+    }
+
+    async fn list_collections(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<models::Collection>, SinkronError> {
+        let prefix = prefix.map(|prefix| prefix.to_string());
+        let cursor = cursor.map(|cursor| cursor.to_string());
+        self.call(move |conn| {
+            // LIKE is case-insensitive in sqlite, so prefix is compared
+            // with substr
+            let query = format!(
+                "SELECT {} FROM collections \
+                WHERE (?1 IS NULL OR substr(id, 1, length(?1)) = ?1) \
+                AND (?2 IS NULL OR id > ?2) \
+                ORDER BY id ASC LIMIT ?3",
+                COLLECTION_COLUMNS
+            );
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map(
+                params![prefix, cursor, limit],
+                collection_from_row,
+            )?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn update_collection_permissions(
+        &self,
+        id: &str,
+        permissions: &str,
+    ) -> Result<(), SinkronError> {
+        let id = id.to_string();
+        let permissions = permissions.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE collections SET permissions = ?1 WHERE id = ?2",
+                params![permissions, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn update_collection_quota(
+        &self,
+        id: &str,
+        quota: Option<i64>,
+    ) -> Result<(), SinkronError> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE collections SET quota = ?1 WHERE id = ?2",
+                params![quota, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn increment_colrev(
+        &self,
+        id: &str,
+        documents_delta: i64,
+        size_delta: i64,
+    ) -> Result<CollectionUsage, SinkronError> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            conn.query_row(
+                "UPDATE collections SET colrev = colrev + 1, \
+                documents_count = documents_count + ?1, size = size + ?2 \
+                WHERE id = ?3 RETURNING colrev, documents_count, size",
+                params![documents_delta, size_delta, id],
+                |row| {
+                    Ok(CollectionUsage {
+                        colrev: row.get(0)?,
+                        documents_count: row.get(1)?,
+                        size: row.get(2)?,
+                    })
+                },
+            )
+        })
+        .await
+    }
+
+    async fn import_collection(
+        &self,
+        col: models::Collection,
+        docs: Vec<models::Document>,
+    ) -> Result<(), SinkronError> {
+        let res = self
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let query = format!(
+                    "INSERT INTO collections ({}) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) \
+                    ON CONFLICT DO NOTHING",
+                    COLLECTION_COLUMNS
+                );
+                let num = tx.execute(
+                    &query,
+                    params![
+                        col.id,
+                        col.is_ref,
+                        col.colrev,
+                        col.permissions,
+                        col.documents_count,
+                        col.size,
+                        col.quota,
+                        col.purged_colrev
+                    ],
+                )?;
+                if num == 0 {
+                    return Ok(Err("Duplicate collection id"));
+                }
+                let query = format!(
+                    "INSERT INTO documents ({}) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) \
+                    ON CONFLICT DO NOTHING",
+                    DOCUMENT_COLUMNS
+                );
+                for doc in docs {
+                    let num = tx.execute(
+                        &query,
+                        params![
+                            doc.id.to_string(),
+                            doc.created_at,
+                            doc.updated_at,
+                            doc.col_id,
+                            doc.colrev,
+                            doc.data,
+                            doc.is_deleted,
+                            doc.permissions,
+                            doc.created_by
+                        ],
+                    )?;
+                    if num == 0 {
+                        return Ok(Err("Duplicate document id"));
+                    }
+                }
+                tx.commit()?;
+                Ok(Ok(()))
+            })
+            .await?;
+        res.map_err(SinkronError::unprocessable)
+    }
+
+    // Documents
+
+    async fn get_document(
+        &self,
+        col: &str,
+        id: Uuid,
+    ) -> Result<models::Document, SinkronError> {
+        let col = col.to_string();
+        let doc = self
+            .call(move |conn| {
+                let query = format!(
+                    "SELECT {} FROM documents WHERE id = ?1 AND col_id = ?2",
+                    DOCUMENT_COLUMNS
+                );
+                conn.query_row(
+                    &query,
+                    params![id.to_string(), col],
+                    document_from_row,
+                )
+                .optional()
+            })
+            .await?;
+        doc.ok_or_else(|| SinkronError::not_found("Document not found"))
+    }
+
+    async fn document_exists(&self, id: Uuid) -> Result<bool, SinkronError> {
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM documents WHERE id = ?1)",
+                params![id.to_string()],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    async fn create_document(
+        &self,
+        doc: models::NewDocument<'_>,
+    ) -> Result<chrono::DateTime<chrono::Utc>, SinkronError> {
+        let id = doc.id.to_string();
+        let col_id = doc.col_id;
+        let colrev = doc.colrev;
+        let data = doc.data;
+        let permissions = doc.permissions.to_string();
+        let created_by = doc.created_by.map(|user| user.to_string());
+        let now = chrono::Utc::now();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO documents (id, created_at, updated_at, col_id, \
+                colrev, data, permissions, created_by) \
+                VALUES (?1, ?2, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![id, now, col_id, colrev, data, permissions, created_by],
+            )
+        })
+        .await?;
+        Ok(now)
+    }
+
+    async fn update_document(
+        &self,
+        id: Uuid,
+        update: models::DocumentUpdate<'_>,
+    ) -> Result<chrono::DateTime<chrono::Utc>, SinkronError> {
+        let colrev = update.colrev;
+        let is_deleted = update.is_deleted;
+        let data = update.data.cloned();
+        let now = chrono::Utc::now();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE documents SET colrev = ?1, is_deleted = ?2, \
+                data = ?3, updated_at = ?4 WHERE id = ?5",
+                params![colrev, is_deleted, data, now, id.to_string()],
+            )
+        })
+        .await?;
+        Ok(now)
+    }
+
+    async fn update_document_permissions(
+        &self,
+        col: &str,
+        id: Uuid,
+        permissions: &str,
+        colrev: i64,
+    ) -> Result<(), SinkronError> {
+        let col = col.to_string();
+        let permissions = permissions.to_string();
+        let num = self
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE documents SET permissions = ?1, colrev = ?2 \
+                    WHERE id = ?3 AND col_id = ?4",
+                    params![permissions, colrev, id.to_string(), col],
+                )
+            })
+            .await?;
+        if num == 0 {
+            Err(SinkronError::not_found("Document not found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_documents_page(
+        &self,
+        col: &str,
+        since: i64,
+        until: i64,
+        skip_deleted: bool,
+        limit: i64,
+    ) -> Result<Vec<models::Document>, SinkronError> {
+        let col = col.to_string();
+        self.call(move |conn| {
+            let query = format!(
+                "SELECT {} FROM documents \
+                WHERE col_id = ?1 AND colrev > ?2 AND colrev <= ?3 \
+                AND (NOT ?4 OR NOT is_deleted) \
+                ORDER BY colrev ASC LIMIT ?5",
+                DOCUMENT_COLUMNS
+            );
+            let mut stmt = conn.prepare(&query)?;
+            let rows = stmt.query_map(
+                params![col, since, until, skip_deleted, limit],
+                document_from_row,
+            )?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn purge_deleted_documents(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize, SinkronError> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(PURGED_COLREV_QUERY, [before])?;
+            tx.execute(
+                "DELETE FROM refs WHERE doc_id IN (SELECT id FROM documents \
+                WHERE is_deleted AND updated_at < ?1)",
+                [before],
+            )?;
+            let num = tx.execute(
+                "DELETE FROM documents WHERE is_deleted AND updated_at < ?1",
+                [before],
+            )?;
+            tx.commit()?;
+            Ok(num)
+        })
+        .await
+    }
+
+    async fn list_documents_permissions(
+        &self,
+        cursor: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<(Uuid, String, String)>, SinkronError> {
+        let cursor = cursor.map(|cursor| cursor.to_string());
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, col_id, permissions FROM documents \
+                WHERE (?1 IS NULL OR id > ?1) ORDER BY id ASC LIMIT ?2",
+            )?;
+            let rows = stmt.query_map(params![cursor, limit], |row| {
+                Ok((get_uuid(row, 0)?, row.get(1)?, row.get(2)?))
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    // Groups
+
+    async fn get_group(&self, id: &str) -> Result<models::Group, SinkronError> {
+        let id = id.to_string();
+        let group = self
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT id, metadata FROM groups WHERE id = ?1",
+                    params![id],
+                    |row| {
+                        Ok(models::Group {
+                            id: row.get(0)?,
+                            metadata: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()
+            })
+            .await?;
+        group.ok_or_else(|| SinkronError::not_found("Group not found"))
+    }
+
+    async fn create_group(
+        &self,
+        group: models::Group,
+    ) -> Result<(), SinkronError> {
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO groups (id, metadata) VALUES (?1, ?2)",
+                params![group.id, group.metadata],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn update_group_metadata(
+        &self,
+        id: &str,
+        metadata: Option<String>,
+    ) -> Result<(), SinkronError> {
+        let id = id.to_string();
+        let num = self
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE groups SET metadata = ?1 WHERE id = ?2",
+                    params![metadata, id],
+                )
+            })
+            .await?;
+        if num == 0 {
+            Err(SinkronError::not_found("Group not found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn delete_group(
+        &self,
+        id: &str,
+    ) -> Result<Vec<String>, SinkronError> {
+        let id = id.to_string();
+        let (num, users) = self
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let users = {
+                    let mut stmt = tx.prepare(AFFECTED_USERS_QUERY)?;
+                    let rows = stmt.query_map(params![id], |row| row.get(0))?;
+                    rows.collect::<rusqlite::Result<Vec<String>>>()?
+                };
+                tx.execute(
+                    "DELETE FROM subgroups WHERE parent = ?1 OR child = ?1",
+                    params![id],
+                )?;
+                tx.execute(
+                    "DELETE FROM members WHERE \"group\" = ?1",
+                    params![id],
+                )?;
+                let num = tx
+                    .execute("DELETE FROM groups WHERE id = ?1", params![id])?;
+                tx.commit()?;
+                Ok((num, users))
+            })
+            .await?;
+        if num == 0 {
+            Err(SinkronError::not_found("Group not found"))
+        } else {
+            Ok(users)
+        }
+    }
+
+    async fn list_groups(
+        &self,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, SinkronError> {
+        let cursor = cursor.map(|cursor| cursor.to_string());
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id FROM groups WHERE (?1 IS NULL OR id > ?1) \
+                ORDER BY id ASC LIMIT ?2",
+            )?;
+            let rows =
+                stmt.query_map(params![cursor, limit], |row| row.get(0))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    // Members
+
+    async fn get_user_groups(
+        &self,
+        user: &str,
+    ) -> Result<Vec<String>, SinkronError> {
+        let query = "SELECT \"group\" FROM members WHERE user = ?".to_string();
+        self.list_strings(query, vec![user.to_string()]).await
+    }
+
+    async fn get_members(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        if groups.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query = format!(
+            "SELECT DISTINCT user FROM members WHERE \"group\" IN ({})",
+            placeholders(groups.len())
+        );
+        self.list_strings(query, groups.to_vec()).await
+    }
+
+    async fn list_group_members(
+        &self,
+        group: &str,
+        cursor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, SinkronError> {
+        let group = group.to_string();
+        let cursor = cursor.map(|cursor| cursor.to_string());
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT user FROM members \
+                WHERE \"group\" = ?1 AND (?2 IS NULL OR user > ?2) \
+                ORDER BY user ASC LIMIT ?3",
+            )?;
+            let rows = stmt
+                .query_map(params![group, cursor, limit], |row| row.get(0))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn add_members(
+        &self,
+        group: &str,
+        users: &[String],
+    ) -> Result<(), SinkronError> {
+        let group = group.to_string();
+        let users = users.to_vec();
+        let exists = self
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let exists: bool = tx.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM groups WHERE id = ?1)",
+                    params![group],
+                    |row| row.get(0),
+                )?;
+                if !exists {
+                    return Ok(false);
+                }
+                for user in users {
+                    tx.execute(
+                        "INSERT INTO members (\"group\", user) VALUES (?1, ?2) \
+                        ON CONFLICT DO NOTHING",
+                        params![group, user],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(true)
+            })
+            .await?;
+        if exists {
+            Ok(())
+        } else {
+            Err(SinkronError::not_found("Group not found"))
+        }
+    }
+
+    async fn remove_members(
+        &self,
+        group: &str,
+        users: &[String],
+    ) -> Result<usize, SinkronError> {
+        if users.is_empty() {
+            return Ok(0);
+        }
+        let query = format!(
+            "DELETE FROM members WHERE \"group\" = ? AND user IN ({})",
+            placeholders(users.len())
+        );
+        let mut values = vec![group.to_string()];
+        values.extend_from_slice(users);
+        self.call(move |conn| conn.execute(&query, params_from_iter(values)))
+            .await
+    }
+
+    async fn remove_user_from_all_groups(
+        &self,
+        user: &str,
+    ) -> Result<(), SinkronError> {
+        let user = user.to_string();
+        self.call(move |conn| {
+            conn.execute("DELETE FROM members WHERE user = ?1", params![user])
+        })
+        .await?;
+        Ok(())
+    }
+
+    // Subgroups
+
+    async fn get_parent_groups(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        if groups.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query = format!(
+            "SELECT parent FROM subgroups WHERE child IN ({})",
+            placeholders(groups.len())
+        );
+        self.list_strings(query, groups.to_vec()).await
+    }
+
+    async fn get_child_groups(
+        &self,
+        groups: &[String],
+    ) -> Result<Vec<String>, SinkronError> {
+        if groups.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query = format!(
+            "SELECT child FROM subgroups WHERE parent IN ({})",
+            placeholders(groups.len())
+        );
+        self.list_strings(query, groups.to_vec()).await
+    }
+
+    async fn add_subgroup(
+        &self,
+        parent: &str,
+        child: &str,
+    ) -> Result<(), SinkronError> {
+        let parent = parent.to_string();
+        let child = child.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO subgroups (parent, child) VALUES (?1, ?2) \
+                ON CONFLICT DO NOTHING",
+                params![parent, child],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_subgroup(
+        &self,
+        parent: &str,
+        child: &str,
+    ) -> Result<usize, SinkronError> {
+        let parent = parent.to_string();
+        let child = child.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM subgroups WHERE parent = ?1 AND child = ?2",
+                params![parent, child],
+            )
+        })
+        .await
+    }
+}
@@ -0,0 +1,255 @@
+// Tests that are run against every storage backend, except Postgres, which
+// requires running database
+
+use uuid::Uuid;
+
+use crate::models;
+use crate::storage::{MemoryStorage, Storage};
+
+async fn create_collection(storage: &dyn Storage, id: &str) {
+    let col = models::NewCollection {
+        id: id.to_string(),
+        is_ref: false,
+        permissions: "{}".to_string(),
+        quota: None,
+    };
+    storage.create_collection(col).await.unwrap();
+}
+
+async fn create_document(storage: &dyn Storage, col: &str) -> (Uuid, i64) {
+    let data = vec![1, 2, 3];
+    let usage = storage
+        .increment_colrev(col, 1, data.len() as i64)
+        .await
+        .unwrap();
+    let id = Uuid::new_v4();
+    let doc = models::NewDocument {
+        id,
+        col_id: col.to_string(),
+        colrev: usage.colrev,
+        data,
+        permissions: "{}",
+        created_by: None,
+    };
+    storage.create_document(doc).await.unwrap();
+    (id, usage.colrev)
+}
+
+async fn page_colrevs(
+    storage: &dyn Storage,
+    since: i64,
+    until: i64,
+    skip_deleted: bool,
+    limit: i64,
+) -> Vec<i64> {
+    storage
+        .get_documents_page("col", since, until, skip_deleted, limit)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|doc| doc.colrev)
+        .collect()
+}
+
+async fn documents_page_range(storage: &dyn Storage) {
+    create_collection(storage, "col").await;
+    create_collection(storage, "other").await;
+    let mut docs = Vec::new();
+    for _ in 0..5 {
+        docs.push(create_document(storage, "col").await);
+    }
+    create_document(storage, "other").await;
+
+    // Range doesn't include `since` and includes `until`
+    assert_eq!(page_colrevs(storage, 1, 4, false, 10).await, vec![2, 3, 4]);
+    assert_eq!(page_colrevs(storage, 0, 5, false, 2).await, vec![1, 2]);
+    assert!(page_colrevs(storage, 5, 5, false, 10).await.is_empty());
+
+    let (id, _) = docs[2];
+    let usage = storage.increment_colrev("col", -1, -3).await.unwrap();
+    let update = models::DocumentUpdate {
+        colrev: usage.colrev,
+        is_deleted: true,
+        data: None,
+    };
+    storage.update_document(id, update).await.unwrap();
+
+    assert_eq!(
+        page_colrevs(storage, 0, 10, false, 10).await,
+        vec![1, 2, 4, 5, 6]
+    );
+    assert_eq!(
+        page_colrevs(storage, 0, 10, true, 10).await,
+        vec![1, 2, 4, 5]
+    );
+}
+
+async fn increment_colrev_usage(storage: &dyn Storage) {
+    create_collection(storage, "col").await;
+
+    let usage = storage.increment_colrev("col", 1, 100).await.unwrap();
+    assert_eq!(
+        (usage.colrev, usage.documents_count, usage.size),
+        (1, 1, 100)
+    );
+    let usage = storage.increment_colrev("col", 1, 50).await.unwrap();
+    assert_eq!(
+        (usage.colrev, usage.documents_count, usage.size),
+        (2, 2, 150)
+    );
+    let usage = storage.increment_colrev("col", -1, -100).await.unwrap();
+    assert_eq!(
+        (usage.colrev, usage.documents_count, usage.size),
+        (3, 1, 50)
+    );
+
+    let col = storage.get_collection("col").await.unwrap();
+    assert_eq!((col.colrev, col.documents_count, col.size), (3, 1, 50));
+    assert!(storage.increment_colrev("missing", 0, 0).await.is_err());
+}
+
+async fn list_collections_prefix(storage: &dyn Storage) {
+    for id in ["a_b", "axb", "a%c", "abc", "A_B"] {
+        create_collection(storage, id).await;
+    }
+    let list = |prefix: &'static str| async move {
+        storage
+            .list_collections(Some(prefix), None, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|col| col.id)
+            .collect::<Vec<_>>()
+    };
+    // Wildcard characters are matched literally and case-sensitively
+    assert_eq!(list("a_").await, vec!["a_b"]);
+    assert_eq!(list("a%").await, vec!["a%c"]);
+    assert_eq!(list("A").await, vec!["A_B"]);
+    assert_eq!(list("a").await.len(), 4);
+}
+
+async fn delete_group_returns_members(storage: &dyn Storage) {
+    for id in ["parent", "child", "grandchild"] {
+        let group = models::Group {
+            id: id.to_string(),
+            metadata: None,
+        };
+        storage.create_group(group).await.unwrap();
+    }
+    storage.add_subgroup("parent", "child").await.unwrap();
+    storage.add_subgroup("child", "grandchild").await.unwrap();
+    storage
+        .add_members("parent", &["a".to_string()])
+        .await
+        .unwrap();
+    storage
+        .add_members("grandchild", &["b".to_string(), "c".to_string()])
+        .await
+        .unwrap();
+
+    let mut users = storage.delete_group("parent").await.unwrap();
+    users.sort();
+    assert_eq!(users, vec!["a", "b", "c"]);
+    assert!(storage.delete_group("parent").await.is_err());
+}
+
+async fn add_members_skips_existing(storage: &dyn Storage) {
+    let group = models::Group {
+        id: "group".to_string(),
+        metadata: None,
+    };
+    storage.create_group(group).await.unwrap();
+    let users = |ids: &[&str]| -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    };
+    storage
+        .add_members("group", &users(&["a", "b"]))
+        .await
+        .unwrap();
+    storage
+        .add_members("group", &users(&["b", "c", "c"]))
+        .await
+        .unwrap();
+
+    let members = storage.list_group_members("group", None, 10).await.unwrap();
+    assert_eq!(members, vec!["a", "b", "c"]);
+    let groups = storage.get_user_groups("b").await.unwrap();
+    assert_eq!(groups, vec!["group"]);
+}
+
+async fn purge_deleted_documents(storage: &dyn Storage) {
+    create_collection(storage, "col").await;
+    let (deleted, _) = create_document(storage, "col").await;
+    let (kept, _) = create_document(storage, "col").await;
+
+    let usage = storage.increment_colrev("col", -1, -3).await.unwrap();
+    let update = models::DocumentUpdate {
+        colrev: usage.colrev,
+        is_deleted: true,
+        data: None,
+    };
+    storage.update_document(deleted, update).await.unwrap();
+
+    let before = chrono::Utc::now() + chrono::Duration::seconds(1);
+    assert_eq!(storage.purge_deleted_documents(before).await.unwrap(), 1);
+    let col = storage.get_collection("col").await.unwrap();
+    assert_eq!(col.purged_colrev, usage.colrev);
+    let docs = storage
+        .get_documents_page("col", 0, usage.colrev, false, 10)
+        .await
+        .unwrap();
+    let ids: Vec<Uuid> = docs.into_iter().map(|doc| doc.id).collect();
+    assert_eq!(ids, vec![kept]);
+}
+
+// Each test gets its own storage, created by the `$storage` expression
+// (it can use `.await`)
+macro_rules! storage_tests {
+    ($backend:ident, $storage:expr) => {
+        mod $backend {
+            use super::*;
+
+            #[tokio::test]
+            async fn documents_page_range() {
+                super::documents_page_range(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn increment_colrev_usage() {
+                super::increment_colrev_usage(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn list_collections_prefix() {
+                super::list_collections_prefix(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn delete_group_returns_members() {
+                super::delete_group_returns_members(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn add_members_skips_existing() {
+                super::add_members_skips_existing(&$storage).await;
+            }
+
+            #[tokio::test]
+            async fn purge_deleted_documents() {
+                super::purge_deleted_documents(&$storage).await;
+            }
+        }
+    };
+}
+
+storage_tests!(memory, MemoryStorage::new());
+
+#[cfg(feature = "sqlite")]
+async fn sqlite_storage() -> crate::storage::SqliteStorage {
+    let storage = crate::storage::SqliteStorage::open(":memory:").unwrap();
+    storage.migrate().await.unwrap();
+    storage
+}
+
+#[cfg(feature = "sqlite")]
+storage_tests!(sqlite, sqlite_storage().await);
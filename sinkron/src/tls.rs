@@ -0,0 +1,259 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::Request;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use log::{debug, error, info};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+// Connections that didn't complete handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_reload_interval() -> u64 {
+    10_000
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsConfig {
+    // Path to the certificate chain (PEM)
+    pub cert: String,
+    // Path to the private key (PEM)
+    pub key: String,
+    // Path to the CA certificates (PEM) that are used to verify client
+    // certificates. When set, api routes can only be called by clients with
+    // a valid certificate, sync endpoint doesn't require it. Only applies
+    // when sinkron serves tls itself, service that embeds the router and
+    // terminates tls has to verify client certificates on its own.
+    pub client_ca: Option<String>,
+    // How often to check the files for changes (in milliseconds), files are
+    // also reloaded on SIGHUP
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+// Request extension that is set on the requests received by the TlsServer
+#[derive(Clone, Copy)]
+pub struct TlsConnection {
+    // Client has presented a certificate that was verified with "clientCa"
+    pub has_client_cert: bool,
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path)
+        .map_err(|err| format!("Couldn't read {}: {}", path, err))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let pem = read_pem(path)?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("Couldn't parse {}: {}", path, err))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let pem = read_pem(path)?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .map_err(|err| format!("Couldn't parse {}: {}", path, err))?
+        .ok_or_else(|| format!("No private key found in {}", path))
+}
+
+fn load_server_config(config: &TlsConfig) -> Result<ServerConfig, String> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|err| format!("Invalid {}: {}", path, err))?;
+            }
+            // Clients without certificate are allowed to connect, access
+            // to the api routes is checked by the auth middleware
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                provider,
+            )
+            .allow_unauthenticated()
+            .build()
+            .map_err(|err| format!("Invalid {}: {}", path, err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("Invalid certificate or key: {}", err))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+fn modified_at(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    let paths = [
+        Some(&config.cert),
+        Some(&config.key),
+        config.client_ca.as_ref(),
+    ];
+    paths
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            std::fs::metadata(Path::new(path))
+                .and_then(|meta| meta.modified())
+                .ok()
+        })
+        .collect()
+}
+
+// Terminates TLS connections and serves the router, certificates can be
+// replaced without restarting the server
+#[derive(Clone)]
+pub struct TlsServer {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl TlsServer {
+    // Loads the certificates, fails when they are invalid
+    pub fn new(config: TlsConfig) -> Result<Self, String> {
+        let server_config = load_server_config(&config)?;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        Ok(Self {
+            config,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    pub fn requires_client_cert(&self) -> bool {
+        self.config.client_ca.is_some()
+    }
+
+    // When new certificates are invalid, previous ones are kept
+    fn reload(&self) {
+        match load_server_config(&self.config) {
+            Ok(server_config) => {
+                let acceptor = TlsAcceptor::from(Arc::new(server_config));
+                *self.acceptor.write().unwrap() = acceptor;
+                info!("sinkron: reloaded tls certificates");
+            }
+            Err(err) => {
+                error!("sinkron: couldn't reload tls certificates: {}", err);
+            }
+        }
+    }
+
+    // Reloads certificates when the files are modified
+    async fn watch_files(self) {
+        let period = Duration::from_millis(self.config.reload_interval.max(1));
+        let mut interval = tokio::time::interval(period);
+        let mut modified = modified_at(&self.config);
+        loop {
+            interval.tick().await;
+            let current = modified_at(&self.config);
+            if current != modified {
+                modified = current;
+                self.reload();
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    async fn watch_signal(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                error!("sinkron: couldn't listen to SIGHUP: {}", err);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            self.reload();
+        }
+    }
+
+    pub async fn serve(self, listener: TcpListener, app: Router) {
+        tokio::spawn(self.clone().watch_files());
+        #[cfg(unix)]
+        tokio::spawn(self.clone().watch_signal());
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(res) => res,
+                Err(err) => {
+                    // Same as axum::serve, errors like too many open files
+                    // are not recovered immediately
+                    error!("sinkron: couldn't accept connection: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let acceptor = self.acceptor.read().unwrap().clone();
+            let app = app.clone();
+            tokio::spawn(async move {
+                let accept = acceptor.accept(stream);
+                let stream =
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, accept).await
+                    {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(err)) => {
+                            debug!(
+                                "sinkron: tls handshake with {} failed: {}",
+                                addr, err
+                            );
+                            return;
+                        }
+                        Err(_) => {
+                            debug!(
+                                "sinkron: tls handshake with {} timed out",
+                                addr
+                            );
+                            return;
+                        }
+                    };
+                // Verifier only accepts valid certificates, so any presented
+                // certificate is trusted
+                let has_cert = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .is_some_and(|certs| !certs.is_empty());
+                let service = hyper::service::service_fn(
+                    move |mut req: Request<hyper::body::Incoming>| {
+                        req.extensions_mut().insert(TlsConnection {
+                            has_client_cert: has_cert,
+                        });
+                        app.clone().oneshot(req.map(axum::body::Body::new))
+                    },
+                );
+                let res = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(
+                        TokioIo::new(stream),
+                        service,
+                    )
+                    .await;
+                if let Err(err) = res {
+                    debug!("sinkron: connection with {} failed: {}", addr, err);
+                }
+            });
+        }
+    }
+}
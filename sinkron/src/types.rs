@@ -10,6 +10,7 @@ pub struct Document {
     pub col: String,
     pub colrev: i64,
     pub permissions: String,
+    pub created_by: Option<String>,
 }
 
 pub type Collection = models::Collection;
@@ -17,11 +18,43 @@ pub type Collection = models::Collection;
 #[derive(serde::Serialize)]
 pub struct Group {
     pub id: String,
-    pub members: Vec<String>
+    pub members: Vec<String>,
+    // Groups that are directly included in this group
+    pub subgroups: Vec<String>,
+    // Arbitrary data attached to the group, like display name
+    pub metadata: Option<serde_json::Value>,
 }
 
 #[derive(serde::Serialize, Clone)]
 pub struct User {
     pub id: String,
+    // Groups of the user, including groups inherited from parent groups
     pub groups: Vec<String>
 }
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+// Page of the list, items are ordered by id
+#[derive(serde::Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // Cursor of the next page, not set when this page is the last one
+    pub cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    // Creates page from the items that were requested with the `limit`
+    pub fn new(items: Vec<T>, limit: i64, id: impl Fn(&T) -> String) -> Self {
+        let cursor = if items.len() as i64 == limit {
+            items.last().map(id)
+        } else {
+            None
+        };
+        Page { items, cursor }
+    }
+}
+
+pub fn page_limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}